@@ -0,0 +1,477 @@
+use crate::error::{AppError, Result};
+use crate::history;
+use crate::safety;
+use crate::shell_integration::ShellKind;
+use crate::types::{AnsweredBy, AppConfig, GenerationOutput};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// `[cache]` section of `config.toml`.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct CacheSettings {
+    pub enabled: bool,
+    /// Entries older than this are ignored (except in offline mode).
+    pub ttl_secs: u64,
+    /// Least recently used entries are evicted past this count.
+    pub max_entries: usize,
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            ttl_secs: 7 * 24 * 60 * 60,
+            max_entries: 500,
+        }
+    }
+}
+
+/// Where a single-turn result came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Provider,
+    Cache,
+    History,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+struct CacheFile {
+    hits: u64,
+    misses: u64,
+    entries: Vec<CacheEntry>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct CacheEntry {
+    key: String,
+    created: u64,
+    last_used: u64,
+    hits: u64,
+    output: GenerationOutput,
+    /// Kept beside `output`, which doesn't serialize it, so a fallback's
+    /// answer isn't later shown as the configured provider's.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    answered_by: Option<AnsweredBy>,
+}
+
+impl CacheEntry {
+    fn output(&self) -> GenerationOutput {
+        GenerationOutput {
+            answered_by: self.answered_by.clone(),
+            ..self.output.clone()
+        }
+    }
+}
+
+pub struct CacheStats {
+    pub entries: usize,
+    pub expired: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub bytes: u64,
+}
+
+/// `cache.json` lives next to `config.toml`.
+pub fn cache_file_path(config_path: &Path) -> PathBuf {
+    config_path.with_file_name("cache.json")
+}
+
+/// Looks for a stored answer before any request is made. In offline mode
+/// expired entries and history count too, and a miss is an error.
+pub fn lookup(config: &AppConfig, prompt: &str) -> Result<Option<(GenerationOutput, Source)>> {
+    if config.offline {
+        return match stale_lookup(config, prompt)? {
+            Some(found) => Ok(Some(found)),
+            None => Err(AppError::from(
+                "offline: no cached or history result for this prompt (drop --offline to ask the provider)",
+            )),
+        };
+    }
+    if config.no_cache || !config.cache.enabled {
+        return Ok(None);
+    }
+    let Some(path) = config.cache_path.as_deref() else {
+        return Ok(None);
+    };
+    let key = cache_key(config, prompt);
+    let mut file = load(path)?;
+    let now = history::now();
+    let hit = file
+        .entries
+        .iter_mut()
+        .find(|e| e.key == key && now.saturating_sub(e.created) <= config.cache.ttl_secs);
+    let found = hit.map(|entry| {
+        entry.hits += 1;
+        entry.last_used = now;
+        entry.output()
+    });
+    match found {
+        Some(_) => file.hits += 1,
+        None => file.misses += 1,
+    }
+    save(path, &file)?;
+    Ok(found.map(|output| (recheck(output, config.shell), Source::Cache)))
+}
+
+/// Stores a fresh provider answer, evicting the least recently used entries.
+pub fn store(config: &AppConfig, prompt: &str, output: &GenerationOutput) -> Result<()> {
+    let Some(path) = config.cache_path.as_deref() else {
+        return Ok(());
+    };
+    if !config.cache.enabled || config.cache.max_entries == 0 {
+        return Ok(());
+    }
+    let key = cache_key(config, prompt);
+    let now = history::now();
+    let mut file = load(path)?;
+    file.entries.retain(|e| e.key != key);
+    file.entries.push(CacheEntry {
+        key,
+        created: now,
+        last_used: now,
+        hits: 0,
+        output: output.clone(),
+        answered_by: output.answered_by.clone(),
+    });
+    if file.entries.len() > config.cache.max_entries {
+        // Stable, so within the same second the entry just stored stays.
+        file.entries.sort_by_key(|e| e.last_used);
+        let excess = file.entries.len() - config.cache.max_entries;
+        file.entries.drain(..excess);
+    }
+    save(path, &file)
+}
+
+/// Single-turn generation behind the cache: a stored answer when there is
+/// one, otherwise `generate`, whose answer is then remembered.
+pub fn generate_cached(
+    config: &AppConfig,
+    prompt: &str,
+    generate: impl FnOnce() -> Result<GenerationOutput>,
+) -> Result<(GenerationOutput, Source)> {
+    if let Some(found) = lookup(config, prompt)? {
+        return Ok(found);
+    }
+    match generate() {
+        Ok(output) => {
+            remember(config, prompt, &output);
+            Ok((output, Source::Provider))
+        }
+        Err(err) => {
+            let found = fallback(config, prompt, err)?;
+            eprintln!("warning: provider unreachable; using a stored answer");
+            Ok(found)
+        }
+    }
+}
+
+/// `store`, but a failed cache write only warns.
+pub fn remember(config: &AppConfig, prompt: &str, output: &GenerationOutput) {
+    if let Err(err) = store(config, prompt, output) {
+        eprintln!("warning: could not update cache: {err}");
+    }
+}
+
+/// When the provider can't be reached at all, an old answer beats an error.
+pub fn fallback(
+    config: &AppConfig,
+    prompt: &str,
+    err: AppError,
+) -> Result<(GenerationOutput, Source)> {
+    if !err.is_network() {
+        return Err(err);
+    }
+    match stale_lookup(config, prompt)? {
+        Some(found) => Ok(found),
+        None => Err(err),
+    }
+}
+
+pub fn clear(path: &Path) -> Result<usize> {
+    let count = load(path)?.entries.len();
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    Ok(count)
+}
+
+pub fn stats(path: &Path, settings: &CacheSettings) -> Result<CacheStats> {
+    let file = load(path)?;
+    let now = history::now();
+    Ok(CacheStats {
+        entries: file.entries.len(),
+        expired: file
+            .entries
+            .iter()
+            .filter(|e| now.saturating_sub(e.created) > settings.ttl_secs)
+            .count(),
+        hits: file.hits,
+        misses: file.misses,
+        bytes: fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+    })
+}
+
+/// Cache entries regardless of age, then the newest history entry for the
+/// same prompt that the user accepted, in this shell's syntax.
+fn stale_lookup(
+    config: &AppConfig,
+    prompt: &str,
+) -> Result<Option<(GenerationOutput, Source)>> {
+    if let Some(path) = config.cache_path.as_deref() {
+        let key = cache_key(config, prompt);
+        if let Some(entry) = load(path)?.entries.into_iter().find(|e| e.key == key) {
+            return Ok(Some((recheck(entry.output(), config.shell), Source::Cache)));
+        }
+    }
+    let Some(path) = config.history_path.as_deref() else {
+        return Ok(None);
+    };
+    let wanted = normalize_prompt(prompt);
+    let same_syntax = |shell: ShellKind| {
+        shell == config.shell || !(shell.has_own_dialect() || config.shell.has_own_dialect())
+    };
+    let found = history::load(path)?
+        .into_iter()
+        .rev()
+        .find(|e| e.accepted() && same_syntax(e.shell) && normalize_prompt(&e.prompt) == wanted);
+    Ok(found.map(|entry| {
+        let output = GenerationOutput {
+            command: entry.command,
+            explanation: format!("From history ({}).", entry.outcome.as_str()),
+            safety: entry.safety,
+            safety_reasons: Vec::new(),
+            answered_by: None,
+            usage: None,
+        };
+        (recheck(output, config.shell), Source::History)
+    }))
+}
+
+/// Safety rules may have changed since the answer was stored.
+fn recheck(mut output: GenerationOutput, shell: ShellKind) -> GenerationOutput {
+    let analysis = safety::reconcile(&output.safety, &output.command, shell);
+    output.safety = analysis.level.as_str().to_string();
+    output.safety_reasons = analysis.reasons;
+    output
+}
+
+fn cache_key(config: &AppConfig, prompt: &str) -> String {
+    let context = config.context.as_ref().map(|c| c.render()).unwrap_or_default();
+    let mut material = [
+        config.provider.as_str(),
+        config.model.as_str(),
+        context.as_str(),
+        normalize_prompt(prompt).as_str(),
+    ]
+    .join("\u{1f}");
+    // nu and pwsh answers are in another syntax; bash-like keys stay as they were.
+    if config.shell.has_own_dialect() {
+        material.push('\u{1f}');
+        material.push_str(config.shell.as_str());
+    }
+    format!("{:016x}", fnv1a(material.as_bytes()))
+}
+
+/// Case, spacing and trailing punctuation don't change what is being asked.
+fn normalize_prompt(prompt: &str) -> String {
+    prompt
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .trim_end_matches(['.', '?', '!'])
+        .to_lowercase()
+}
+
+/// FNV-1a: stable across Rust releases, unlike `DefaultHasher`.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn load(path: &Path) -> Result<CacheFile> {
+    if !path.exists() {
+        return Ok(CacheFile::default());
+    }
+    // A corrupt cache is not worth failing over; start again.
+    Ok(serde_json::from_str(&fs::read_to_string(path)?).unwrap_or_default())
+}
+
+fn save(path: &Path, file: &CacheFile) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_string(file)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_prompt_ignores_case_spacing_and_punctuation() {
+        assert_eq!(
+            normalize_prompt("  Show disk usage   sorted by size?"),
+            normalize_prompt("show disk usage sorted by size")
+        );
+        assert_ne!(normalize_prompt("show disk usage"), normalize_prompt("show memory usage"));
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    /// A config whose cache and history live in a fresh directory, returned
+    /// alongside it for cleanup.
+    fn temp_config(name: &str) -> (PathBuf, AppConfig) {
+        let dir = std::env::temp_dir().join(format!("b-cache-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut config = crate::config::parse_cli(Vec::new(), None).expect("parse failed");
+        config.cache_path = Some(dir.join("cache.json"));
+        config.history_path = Some(dir.join("history.jsonl"));
+        config.shell = ShellKind::Bash;
+        (dir, config)
+    }
+
+    fn answer(command: &str) -> GenerationOutput {
+        GenerationOutput {
+            command: command.to_string(),
+            explanation: "x".to_string(),
+            safety: "safe".to_string(),
+            safety_reasons: Vec::new(),
+            answered_by: None,
+            usage: None,
+        }
+    }
+
+    /// Moves every entry's timestamps `secs` into the past.
+    fn age_entries(config: &AppConfig, secs: u64) {
+        let path = config.cache_path.as_deref().expect("cache path");
+        let mut file = load(path).expect("load");
+        for entry in &mut file.entries {
+            entry.created -= secs;
+            entry.last_used -= secs;
+        }
+        save(path, &file).expect("save");
+    }
+
+    #[test]
+    fn cached_fallback_answers_keep_who_answered() {
+        use crate::types::{Provider, Via};
+
+        let (dir, config) = temp_config("answered-by");
+        let by = AnsweredBy {
+            provider: Provider::Ollama,
+            model: "llama3".to_string(),
+            via: Via::Fallback,
+        };
+        let output = GenerationOutput { answered_by: Some(by.clone()), ..answer("ls") };
+        store(&config, "list files", &output).expect("store");
+        let (hit, source) = lookup(&config, "list files").expect("lookup").expect("hit");
+        assert_eq!((hit.answered_by, source), (Some(by), Source::Cache));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn expired_entries_miss_unless_offline() {
+        let (dir, mut config) = temp_config("ttl");
+        config.cache.ttl_secs = 60;
+        store(&config, "list files", &answer("ls")).expect("store");
+        assert!(lookup(&config, "List files.").expect("lookup").is_some());
+
+        age_entries(&config, 120);
+        assert!(lookup(&config, "list files").expect("lookup").is_none());
+        config.offline = true;
+        let (hit, source) = lookup(&config, "list files").expect("lookup").expect("stale hit");
+        assert_eq!((hit.command.as_str(), source), ("ls", Source::Cache));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn least_recently_used_entries_are_evicted() {
+        let (dir, mut config) = temp_config("lru");
+        config.cache.max_entries = 2;
+        store(&config, "first", &answer("a")).expect("store");
+        store(&config, "second", &answer("b")).expect("store");
+        age_entries(&config, 10);
+        // Touching "first" makes "second" the least recently used.
+        assert!(lookup(&config, "first").expect("lookup").is_some());
+        store(&config, "third", &answer("c")).expect("store");
+
+        assert!(lookup(&config, "second").expect("lookup").is_none());
+        assert!(lookup(&config, "first").expect("lookup").is_some());
+        assert!(lookup(&config, "third").expect("lookup").is_some());
+
+        // Within one second the entry just stored is never the one dropped.
+        store(&config, "fourth", &answer("d")).expect("store");
+        assert!(lookup(&config, "fourth").expect("lookup").is_some());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn no_cache_skips_the_lookup_but_refreshes_the_entry() {
+        let (dir, mut config) = temp_config("no-cache");
+        store(&config, "list files", &answer("ls")).expect("store");
+        config.no_cache = true;
+        assert!(lookup(&config, "list files").expect("lookup").is_none());
+        let (output, source) =
+            generate_cached(&config, "list files", || Ok(answer("ls -la"))).expect("generate");
+        assert_eq!((output.command.as_str(), source), ("ls -la", Source::Provider));
+
+        config.no_cache = false;
+        let (hit, _) = lookup(&config, "list files").expect("lookup").expect("hit");
+        assert_eq!(hit.command, "ls -la");
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn offline_falls_back_to_history_then_errors() {
+        let (dir, mut config) = temp_config("offline");
+        config.offline = true;
+        assert!(lookup(&config, "show disk usage").is_err());
+
+        let entry = history::HistoryEntry {
+            timestamp: history::now(),
+            prompt: "Show disk usage".to_string(),
+            provider: "openai".to_string(),
+            model: "gpt-4o-mini".to_string(),
+            command: "df -h".to_string(),
+            shell: ShellKind::Bash,
+            safety: "safe".to_string(),
+            outcome: history::Outcome::Executed,
+            exit_status: Some(0),
+        };
+        let history_path = config.history_path.as_deref().expect("history path");
+        history::append(history_path, &entry).expect("append");
+        // Newer entries the user quit on, that failed, or in another shell's
+        // syntax are passed over.
+        use history::Outcome::{Executed, Quit};
+        for (command, shell, outcome, exit_status) in [
+            ("du -a / | sort", ShellKind::Bash, Quit, None),
+            ("df --bogus", ShellKind::Zsh, Executed, Some(1)),
+            ("sys disks", ShellKind::Nu, Executed, Some(0)),
+        ] {
+            let newer = history::HistoryEntry {
+                command: command.to_string(),
+                shell,
+                outcome,
+                exit_status,
+                ..entry.clone()
+            };
+            history::append(history_path, &newer).expect("append");
+        }
+        let (hit, source) = lookup(&config, "show disk usage").expect("lookup").expect("hit");
+        assert_eq!((hit.command.as_str(), source), ("df -h", Source::History));
+        config.shell = ShellKind::Nu;
+        let (hit, _) = lookup(&config, "show disk usage").expect("lookup").expect("hit");
+        assert_eq!(hit.command, "sys disks");
+        config.shell = ShellKind::Bash;
+
+        // A cache entry, however old, wins over history.
+        store(&config, "show disk usage", &answer("du -sh .")).expect("store");
+        age_entries(&config, 365 * 86_400);
+        let (hit, source) = lookup(&config, "show disk usage").expect("lookup").expect("hit");
+        assert_eq!((hit.command.as_str(), source), ("du -sh .", Source::Cache));
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -1,8 +1,16 @@
 use crate::error::{AppError, Result};
-use crate::types::{AppConfig, FileConfig, Provider};
+use crate::fix::FailedCommand;
+use crate::providers::cassette::Cassette;
+use crate::safety::Policy;
+use crate::shell_integration::{detect_shell_from_env, parse_shell_name, ShellKind};
+use crate::types::{AppConfig, FallbackEntry, FileConfig, Provider, ProviderTarget};
 use std::env;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Upper bound for `--candidates`; each one costs a completion.
+const MAX_CANDIDATES: usize = 8;
 
 pub fn config_file_path() -> Result<PathBuf> {
     let mut dir =
@@ -20,6 +28,28 @@ pub fn load_file_config(path: &PathBuf) -> Result<Option<FileConfig>> {
     Ok(Some(cfg))
 }
 
+/// `policy.toml` lives next to `config.toml` so it can be managed separately.
+pub fn policy_file_path(config_path: &Path) -> PathBuf {
+    config_path.with_file_name("policy.toml")
+}
+
+pub fn load_policy(config_path: &Path, file_config: Option<&FileConfig>) -> Result<Policy> {
+    let path = policy_file_path(config_path);
+    if path.exists() {
+        let content = fs::read_to_string(&path)?;
+        return toml::from_str(&content)
+            .map_err(|e| AppError::from(format!("invalid policy file {}: {e}", path.display())));
+    }
+    Ok(file_config.and_then(|c| c.policy.clone()).unwrap_or_default())
+}
+
+/// `--shell`, then `BAISHIFY_SHELL`, then the login shell, then bash.
+fn resolve_shell(flag: Option<ShellKind>) -> ShellKind {
+    flag.or_else(|| env::var("BAISHIFY_SHELL").ok().and_then(|s| parse_shell_name(&s)))
+        .or_else(detect_shell_from_env)
+        .unwrap_or(ShellKind::Bash)
+}
+
 pub fn save_file_config(path: &PathBuf, cfg: &FileConfig) -> Result<()> {
     if let Some(parent) = path.parent() {
         fs::create_dir_all(parent)?;
@@ -34,15 +64,41 @@ pub fn parse_cli(args: Vec<String>, file_config: Option<FileConfig>) -> Result<A
     let mut json = false;
     let mut plain = false;
     let mut no_fun = false;
+    let mut no_context = false;
+    let mut no_cache = false;
+    let mut offline = false;
+    let mut race = false;
     let mut setup = false;
     let mut provider_override: Option<Provider> = None;
     let mut model_override: Option<String> = None;
     let mut base_url_override: Option<String> = None;
     let mut api_key_override: Option<String> = None;
     let mut output_file: Option<String> = None;
+    let mut candidates = 1usize;
+    let mut fix_mode = false;
+    let mut explain_existing = false;
+    let mut last_command: Option<String> = None;
+    let mut last_status: Option<i32> = None;
+    let mut stderr_file: Option<String> = None;
+    let mut record: Option<String> = None;
+    let mut replay: Option<String> = None;
+    let mut shell_override = None;
     let mut prompt_parts: Vec<String> = Vec::new();
 
-    let mut iter = args.into_iter();
+    let mut iter = args.into_iter().peekable();
+    // `fix` and `explain` are only subcommands in first position so prompts
+    // like "fix permissions on ~/.ssh" still work.
+    match iter.peek().map(String::as_str) {
+        Some("fix") => {
+            iter.next();
+            fix_mode = true;
+        }
+        Some("explain") => {
+            iter.next();
+            explain_existing = true;
+        }
+        _ => {}
+    }
     while let Some(arg) = iter.next() {
         match arg.as_str() {
             "-h" | "--help" => {
@@ -54,6 +110,10 @@ pub fn parse_cli(args: Vec<String>, file_config: Option<FileConfig>) -> Result<A
             "--json" => json = true,
             "--plain" => plain = true,
             "--no-fun" => no_fun = true,
+            "--no-context" => no_context = true,
+            "--no-cache" => no_cache = true,
+            "--offline" => offline = true,
+            "--race" => race = true,
             "--provider" => {
                 let value = iter
                     .next()
@@ -61,7 +121,7 @@ pub fn parse_cli(args: Vec<String>, file_config: Option<FileConfig>) -> Result<A
                 provider_override = Provider::parse(&value);
                 if provider_override.is_none() {
                     return Err(AppError::from(format!(
-                        "unsupported provider `{value}` (use: openai, anthropic, openrouter, vercel)"
+                        "unsupported provider `{value}` (use: openai, anthropic, openrouter, vercel, ollama, llamacpp)"
                     )));
                 }
             }
@@ -89,6 +149,59 @@ pub fn parse_cli(args: Vec<String>, file_config: Option<FileConfig>) -> Result<A
                     .ok_or_else(|| AppError::from("--output-file requires a value"))?;
                 output_file = Some(value);
             }
+            "--candidates" => {
+                let value = iter
+                    .next()
+                    .ok_or_else(|| AppError::from("--candidates requires a value"))?;
+                candidates = value
+                    .trim()
+                    .parse()
+                    .ok()
+                    .filter(|n| (1..=MAX_CANDIDATES).contains(n))
+                    .ok_or_else(|| {
+                        AppError::from(format!("--candidates must be between 1 and {MAX_CANDIDATES}"))
+                    })?;
+            }
+            "--last-command" => {
+                let value = iter
+                    .next()
+                    .ok_or_else(|| AppError::from("--last-command requires a value"))?;
+                last_command = Some(value).filter(|v| !v.trim().is_empty());
+            }
+            "--last-status" => {
+                let value = iter
+                    .next()
+                    .ok_or_else(|| AppError::from("--last-status requires a value"))?;
+                last_status = value.trim().parse().ok();
+            }
+            "--stderr-file" => {
+                let value = iter
+                    .next()
+                    .ok_or_else(|| AppError::from("--stderr-file requires a value"))?;
+                stderr_file = Some(value).filter(|v| !v.trim().is_empty());
+            }
+            "--shell" => {
+                let value = iter
+                    .next()
+                    .ok_or_else(|| AppError::from("--shell requires a value"))?;
+                shell_override = Some(parse_shell_name(&value).ok_or_else(|| {
+                    AppError::from(format!(
+                        "unsupported shell `{value}` (use: bash, zsh, fish, nu, pwsh)"
+                    ))
+                })?);
+            }
+            "--record" => {
+                let value = iter
+                    .next()
+                    .ok_or_else(|| AppError::from("--record requires a value"))?;
+                record = Some(value);
+            }
+            "--replay" => {
+                let value = iter
+                    .next()
+                    .ok_or_else(|| AppError::from("--replay requires a value"))?;
+                replay = Some(value);
+            }
             _ => prompt_parts.push(arg),
         }
     }
@@ -113,6 +226,33 @@ pub fn parse_cli(args: Vec<String>, file_config: Option<FileConfig>) -> Result<A
         || env::var("B_FUN").ok().as_deref() == Some("0")
         || file_config.as_ref().and_then(|c| c.no_fun).unwrap_or(false);
 
+    let no_context =
+        no_context || file_config.as_ref().and_then(|c| c.no_context).unwrap_or(false);
+
+    let fallback: Vec<ProviderTarget> = file_config
+        .as_ref()
+        .map(|c| c.fallback.iter().map(resolve_fallback).collect())
+        .unwrap_or_default();
+    let race = race || file_config.as_ref().and_then(|c| c.race).unwrap_or(false);
+    if race && fallback.is_empty() {
+        return Err(AppError::from(
+            "--race needs at least two providers; add [[fallback]] entries to config.toml",
+        ));
+    }
+
+    let record = record.or_else(|| env::var("BAISHIFY_RECORD").ok());
+    let replay = replay.or_else(|| env::var("BAISHIFY_REPLAY").ok());
+    let cassette = match (record, replay) {
+        (Some(_), Some(_)) => {
+            return Err(AppError::from("--record and --replay can't be used together"));
+        }
+        (Some(path), None) => Some(Arc::new(Cassette::record(PathBuf::from(path)))),
+        (None, Some(path)) => Some(Arc::new(Cassette::replay(PathBuf::from(path))?)),
+        (None, None) => None,
+    };
+
+    let shell = resolve_shell(shell_override);
+
     let api_key = api_key_override
         .or_else(|| env_api_key_for(provider))
         .or_else(|| file_config.as_ref().and_then(|c| c.api_key.clone()))
@@ -124,6 +264,17 @@ pub fn parse_cli(args: Vec<String>, file_config: Option<FileConfig>) -> Result<A
         Some(prompt_parts.join(" "))
     };
 
+    let fix = if fix_mode {
+        let command = last_command.ok_or_else(|| {
+            AppError::from(
+                "no failed command recorded. Run `b init` and restart your shell, or pass --last-command",
+            )
+        })?;
+        Some(FailedCommand::load(command, last_status, stderr_file.as_deref())?)
+    } else {
+        None
+    };
+
     Ok(AppConfig {
         provider,
         model,
@@ -136,14 +287,66 @@ pub fn parse_cli(args: Vec<String>, file_config: Option<FileConfig>) -> Result<A
         setup,
         prompt,
         output_file,
+        candidates,
+        no_context,
+        context: None,
+        fix,
+        explain_existing,
+        policy: Policy::default(),
+        history_path: None,
+        cache: file_config
+            .as_ref()
+            .and_then(|c| c.cache.clone())
+            .unwrap_or_default(),
+        cache_path: None,
+        no_cache,
+        offline,
+        retry: file_config
+            .as_ref()
+            .and_then(|c| c.retry.clone())
+            .unwrap_or_default(),
+        network: file_config
+            .as_ref()
+            .and_then(|c| c.network.clone())
+            .unwrap_or_default(),
+        fallback,
+        race,
+        usage_path: None,
+        cassette,
+        shell,
     })
 }
 
+/// `BAISHIFY_*` and CLI overrides belong to the main provider, so fallbacks
+/// only take their own fields, the provider defaults, and its key variable.
+fn resolve_fallback(entry: &FallbackEntry) -> ProviderTarget {
+    let provider = entry.provider;
+    ProviderTarget {
+        provider,
+        model: entry
+            .model
+            .clone()
+            .unwrap_or_else(|| provider.default_model().to_string()),
+        base_url: entry
+            .base_url
+            .clone()
+            .unwrap_or_else(|| provider.default_base_url().to_string()),
+        api_key: entry
+            .api_key
+            .clone()
+            .or_else(|| env_api_key_for(provider))
+            .unwrap_or_default(),
+    }
+}
+
 pub fn merge_cli_with_setup(mut config: AppConfig, setup: FileConfig) -> Result<AppConfig> {
     if config.api_key.is_empty() {
-        config.api_key = setup
-            .api_key
-            .ok_or_else(|| AppError::from("setup did not return api key"))?;
+        let requires_key = setup.provider.unwrap_or(config.provider).requires_api_key();
+        match setup.api_key {
+            Some(key) => config.api_key = key,
+            None if requires_key => return Err(AppError::from("setup did not return api key")),
+            None => {}
+        }
     }
     if config.model == config.provider.default_model() {
         if let Some(model) = setup.model {
@@ -175,6 +378,8 @@ pub fn env_model_for(provider: Provider) -> Option<String> {
             Provider::Anthropic => env::var("ANTHROPIC_MODEL").ok(),
             Provider::Openrouter => env::var("OPENROUTER_MODEL").ok(),
             Provider::Vercel => env::var("VERCEL_AI_GATEWAY_MODEL").ok(),
+            Provider::Ollama => env::var("OLLAMA_MODEL").ok(),
+            Provider::Llamacpp => env::var("LLAMACPP_MODEL").ok(),
         })
 }
 
@@ -186,6 +391,8 @@ pub fn env_api_key_for(provider: Provider) -> Option<String> {
         Provider::Vercel => env::var("VERCEL_AI_GATEWAY_API_KEY")
             .ok()
             .or_else(|| env::var("AI_GATEWAY_API_KEY").ok()),
+        Provider::Ollama => env::var("OLLAMA_API_KEY").ok(),
+        Provider::Llamacpp => env::var("LLAMACPP_API_KEY").ok(),
     }
 }
 
@@ -197,9 +404,21 @@ pub fn env_base_url_for(provider: Provider) -> Option<String> {
         Provider::Vercel => env::var("VERCEL_AI_GATEWAY_BASE_URL")
             .ok()
             .or_else(|| env::var("AI_GATEWAY_BASE_URL").ok()),
+        Provider::Ollama => env::var("OLLAMA_HOST").ok().map(|h| normalize_host_url(&h)),
+        Provider::Llamacpp => env::var("LLAMACPP_BASE_URL").ok(),
     })
 }
 
+/// `OLLAMA_HOST` is commonly set without a scheme (e.g. `127.0.0.1:11434`).
+fn normalize_host_url(host: &str) -> String {
+    let host = host.trim();
+    if host.contains("://") {
+        host.to_string()
+    } else {
+        format!("http://{host}")
+    }
+}
+
 pub fn detected_provider_keys() -> Vec<(Provider, String)> {
     let mut out = Vec::new();
     if let Ok(v) = env::var("OPENAI_API_KEY") {
@@ -237,10 +456,15 @@ pub fn print_usage() {
            b [options] <prompt>\n\
            echo \"<prompt>\" | b [options]\n\
            b setup\n\
-           b init [zsh|bash]\n\
+           b init [--uninstall|--status] [--rc-file <path>] [zsh|bash|fish|nu|pwsh]\n\
+           b fix [hint]         Repair the last failed command (needs `b init`)\n\
+           b explain <command>  Break down an existing command part by part\n\
+           b history [query]    Search past results and run or copy one again\n\
+           b cache clear|stats  Manage the response cache\n\
+           b usage [--days n]   Tokens and estimated cost per provider per day\n\
          \n\
          Options:\n\
-           --provider <name>    openai | anthropic | openrouter | vercel\n\
+           --provider <name>    openai | anthropic | openrouter | vercel | ollama | llamacpp\n\
            --model <name>       Override model\n\
            --base-url <url>     Override API base URL\n\
            --api-key <key>      Override API key\n\
@@ -248,6 +472,14 @@ pub fn print_usage() {
            --json               JSON output mode\n\
            --plain              Disable interactive rendering\n\
            --no-fun             Disable playful copy\n\
+           --no-context         Don't send OS/shell/cwd details to the provider\n\
+           --candidates <n>     Ask for n alternative commands and pick one\n\
+           --no-cache           Ask the provider even if a cached answer exists\n\
+           --offline            Answer only from the cache or history\n\
+           --race               Ask the provider and its fallbacks at once; fastest wins\n\
+           --shell <name>       Write the command for bash | zsh | fish | nu | pwsh\n\
+           --record <file>      Save provider requests and responses to a cassette\n\
+           --replay <file>      Answer from a recorded cassette instead of the network\n\
            -h, --help           Show help\n\
          \n\
          Interactive mode is default on TTY. Non-TTY prints command only."
@@ -315,4 +547,108 @@ mod tests {
         assert_eq!(cfg.output_file.as_deref(), Some("/tmp/cmd.out"));
         assert_eq!(cfg.prompt.as_deref(), Some("list files"));
     }
+
+    #[test]
+    fn parse_cli_local_provider_needs_no_key() {
+        let _guard = env_lock();
+        clear_env(&[
+            "BAISHIFY_PROVIDER",
+            "BAISHIFY_BASE_URL",
+            "OLLAMA_HOST",
+            "OLLAMA_API_KEY",
+        ]);
+        std::env::set_var("OLLAMA_HOST", "127.0.0.1:11434");
+
+        let cfg = parse_cli(
+            vec!["--provider".to_string(), "ollama".to_string(), "hi".to_string()],
+            None,
+        )
+        .expect("parse failed");
+        assert_eq!(cfg.provider, Provider::Ollama);
+        assert_eq!(cfg.base_url, "http://127.0.0.1:11434");
+        assert!(!cfg.provider_api_key_missing());
+    }
+
+    #[test]
+    fn parse_cli_fix_only_in_first_position() {
+        let _guard = env_lock();
+        clear_env(&["BAISHIFY_PROVIDER", "OPENAI_API_KEY"]);
+        std::env::set_var("OPENAI_API_KEY", "k");
+
+        let cfg = parse_cli(
+            vec![
+                "fix".to_string(),
+                "--last-command".to_string(),
+                "git psuh".to_string(),
+                "--last-status".to_string(),
+                "1".to_string(),
+            ],
+            None,
+        )
+        .expect("parse failed");
+        let fix = cfg.fix.expect("fix missing");
+        assert_eq!(fix.command, "git psuh");
+        assert_eq!(fix.status, Some(1));
+        assert!(cfg.prompt.is_none());
+
+        let cfg = parse_cli(vec!["fix".to_string(), "perms".to_string()], None);
+        assert!(cfg.is_err());
+
+        let cfg = parse_cli(vec!["please".to_string(), "fix".to_string()], None)
+            .expect("parse failed");
+        assert!(cfg.fix.is_none());
+        assert_eq!(cfg.prompt.as_deref(), Some("please fix"));
+    }
+
+    #[test]
+    fn parse_cli_validates_candidates() {
+        let _guard = env_lock();
+        clear_env(&["BAISHIFY_PROVIDER", "OPENAI_API_KEY"]);
+        std::env::set_var("OPENAI_API_KEY", "k");
+
+        let args = |n: &str| vec!["--candidates".to_string(), n.to_string(), "list".to_string()];
+        assert_eq!(parse_cli(args("3"), None).expect("parse failed").candidates, 3);
+        assert!(parse_cli(args("0"), None).is_err());
+        assert!(parse_cli(args("many"), None).is_err());
+    }
+
+    #[test]
+    fn parse_cli_race_needs_a_fallback() {
+        let _guard = env_lock();
+        clear_env(&["BAISHIFY_PROVIDER", "OPENAI_API_KEY", "ANTHROPIC_API_KEY"]);
+        std::env::set_var("OPENAI_API_KEY", "k");
+
+        let args = || vec!["--race".to_string(), "list".to_string()];
+        assert!(parse_cli(args(), None).is_err());
+
+        let file: FileConfig =
+            toml::from_str("[[fallback]]\nprovider = \"anthropic\"\n").expect("toml failed");
+        let cfg = parse_cli(args(), Some(file)).expect("parse failed");
+        assert!(cfg.race);
+        assert_eq!(cfg.fallback[0].model, Provider::Anthropic.default_model());
+        assert_eq!(cfg.fallback[0].api_key, "");
+    }
+
+    #[test]
+    fn parse_cli_shell_flag_beats_env_and_login_shell() {
+        let _guard = env_lock();
+        clear_env(&["BAISHIFY_SHELL"]);
+        let login_shell = std::env::var_os("SHELL");
+        std::env::set_var("SHELL", "/usr/bin/pwsh");
+        let args = |extra: &[&str]| extra.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+
+        let cfg = parse_cli(args(&["list"]), None).expect("parse failed");
+        assert_eq!(cfg.shell, ShellKind::Pwsh);
+        std::env::set_var("BAISHIFY_SHELL", "nushell");
+        let cfg = parse_cli(args(&["list"]), None).expect("parse failed");
+        assert_eq!(cfg.shell, ShellKind::Nu);
+        let cfg = parse_cli(args(&["--shell", "zsh", "list"]), None).expect("parse failed");
+        assert_eq!(cfg.shell, ShellKind::Zsh);
+        assert!(parse_cli(args(&["--shell", "tcsh", "list"]), None).is_err());
+        clear_env(&["BAISHIFY_SHELL"]);
+        match login_shell {
+            Some(shell) => std::env::set_var("SHELL", shell),
+            None => std::env::remove_var("SHELL"),
+        }
+    }
 }
@@ -0,0 +1,153 @@
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::process::Command;
+
+/// Tools whose presence changes which command is the practical answer.
+const PROBED_TOOLS: [&str; 5] = ["rg", "fd", "jq", "docker", "kubectl"];
+
+/// Snapshot of the local environment sent alongside the prompt so the model
+/// can pick flags that actually work here (GNU vs BSD, installed tools).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EnvContext {
+    pub os: String,
+    pub distro: Option<String>,
+    pub shell: Option<String>,
+    pub shell_version: Option<String>,
+    pub cwd: Option<String>,
+    pub coreutils: Option<String>,
+    pub sed: Option<String>,
+    pub tools_present: Vec<String>,
+    pub tools_missing: Vec<String>,
+}
+
+/// `shell` names the shell `b` was called from when it isn't `$SHELL`, e.g.
+/// nu started from a bash login.
+pub fn collect_context(shell: Option<&str>) -> EnvContext {
+    let shell_path = shell
+        .map(str::to_string)
+        .or_else(|| std::env::var("SHELL").ok());
+    let shell = shell_path.as_deref().and_then(|p| {
+        Path::new(p)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+    });
+    let shell_version = shell_path
+        .as_deref()
+        .and_then(|p| first_output_line(p, &["--version"]));
+
+    let (tools_present, tools_missing): (Vec<&str>, Vec<&str>) =
+        PROBED_TOOLS.iter().partition(|tool| on_path(tool));
+
+    EnvContext {
+        os: std::env::consts::OS.to_string(),
+        distro: detect_distro(),
+        shell,
+        shell_version,
+        cwd: std::env::current_dir()
+            .ok()
+            .map(|p| p.display().to_string()),
+        coreutils: Some(flavor_of("ls")),
+        sed: Some(flavor_of("sed")),
+        tools_present: tools_present.into_iter().map(str::to_string).collect(),
+        tools_missing: tools_missing.into_iter().map(str::to_string).collect(),
+    }
+}
+
+impl EnvContext {
+    /// Renders the context as a compact block for the user message.
+    pub fn render(&self) -> String {
+        let mut out = String::from("Environment:\n");
+        match &self.distro {
+            Some(distro) => out.push_str(&format!("- os: {} ({distro})\n", self.os)),
+            None => out.push_str(&format!("- os: {}\n", self.os)),
+        }
+        if let Some(shell) = &self.shell {
+            match &self.shell_version {
+                Some(version) => out.push_str(&format!("- shell: {shell} ({version})\n")),
+                None => out.push_str(&format!("- shell: {shell}\n")),
+            }
+        }
+        if let Some(cwd) = &self.cwd {
+            out.push_str(&format!("- cwd: {cwd}\n"));
+        }
+        if let Some(coreutils) = &self.coreutils {
+            out.push_str(&format!("- coreutils: {coreutils}\n"));
+        }
+        if let Some(sed) = &self.sed {
+            out.push_str(&format!("- sed: {sed}\n"));
+        }
+        if !self.tools_present.is_empty() {
+            out.push_str(&format!("- installed: {}\n", self.tools_present.join(", ")));
+        }
+        if !self.tools_missing.is_empty() {
+            out.push_str(&format!("- not installed: {}\n", self.tools_missing.join(", ")));
+        }
+        out
+    }
+}
+
+fn detect_distro() -> Option<String> {
+    if cfg!(target_os = "macos") {
+        return first_output_line("sw_vers", &["-productVersion"]).map(|v| format!("macOS {v}"));
+    }
+    let raw = std::fs::read_to_string("/etc/os-release").ok()?;
+    raw.lines()
+        .find_map(|line| line.strip_prefix("PRETTY_NAME="))
+        .map(|v| v.trim_matches('"').to_string())
+}
+
+/// GNU tools answer `--version`; BSD ones reject the flag.
+fn flavor_of(tool: &str) -> String {
+    match first_output_line(tool, &["--version"]) {
+        Some(line) if line.contains("GNU") => "GNU".to_string(),
+        Some(_) | None if on_path(tool) => "BSD".to_string(),
+        _ => "unknown".to_string(),
+    }
+}
+
+fn first_output_line(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program)
+        .args(args)
+        .stdin(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let text = String::from_utf8_lossy(&output.stdout);
+    let line = text.lines().next()?.trim();
+    (!line.is_empty()).then(|| line.chars().take(80).collect())
+}
+
+fn on_path(tool: &str) -> bool {
+    let Some(paths) = std::env::var_os("PATH") else {
+        return false;
+    };
+    std::env::split_paths(&paths).any(|dir| dir.join(tool).is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_lists_only_known_fields() {
+        let ctx = EnvContext {
+            os: "linux".to_string(),
+            distro: Some("Ubuntu 24.04 LTS".to_string()),
+            shell: Some("zsh".to_string()),
+            sed: Some("GNU".to_string()),
+            tools_present: vec!["rg".to_string(), "jq".to_string()],
+            tools_missing: vec!["fd".to_string()],
+            ..EnvContext::default()
+        };
+        let block = ctx.render();
+        assert!(block.contains("- os: linux (Ubuntu 24.04 LTS)\n"));
+        assert!(block.contains("- shell: zsh\n"));
+        assert!(block.contains("- sed: GNU\n"));
+        assert!(block.contains("- installed: rg, jq\n"));
+        assert!(block.contains("- not installed: fd\n"));
+        assert!(!block.contains("cwd"));
+    }
+}
@@ -0,0 +1,193 @@
+use crate::error::Result;
+use dialoguer::console::{Key, Term};
+
+/// Text and cursor of the line being edited; cursor is a char index.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct LineBuffer {
+    chars: Vec<char>,
+    cursor: usize,
+}
+
+impl LineBuffer {
+    fn new(text: &str) -> Self {
+        let chars: Vec<char> = text.chars().collect();
+        let cursor = chars.len();
+        Self { chars, cursor }
+    }
+
+    fn text(&self) -> String {
+        self.chars.iter().collect()
+    }
+
+    fn insert(&mut self, c: char) {
+        self.chars.insert(self.cursor, c);
+        self.cursor += 1;
+    }
+
+    fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.chars.remove(self.cursor);
+        }
+    }
+
+    fn delete(&mut self) {
+        if self.cursor < self.chars.len() {
+            self.chars.remove(self.cursor);
+        }
+    }
+
+    fn left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    fn right(&mut self) {
+        self.cursor = (self.cursor + 1).min(self.chars.len());
+    }
+
+    /// Start of the word before the cursor, skipping whitespace first (readline `M-b`).
+    fn word_start(&self) -> usize {
+        let mut i = self.cursor;
+        while i > 0 && self.chars[i - 1].is_whitespace() {
+            i -= 1;
+        }
+        while i > 0 && !self.chars[i - 1].is_whitespace() {
+            i -= 1;
+        }
+        i
+    }
+
+    fn word_end(&self) -> usize {
+        let mut i = self.cursor;
+        while i < self.chars.len() && self.chars[i].is_whitespace() {
+            i += 1;
+        }
+        while i < self.chars.len() && !self.chars[i].is_whitespace() {
+            i += 1;
+        }
+        i
+    }
+
+    fn delete_word_back(&mut self) {
+        let start = self.word_start();
+        self.chars.drain(start..self.cursor);
+        self.cursor = start;
+    }
+
+    fn kill_to_end(&mut self) {
+        self.chars.truncate(self.cursor);
+    }
+
+    fn kill_to_start(&mut self) {
+        self.chars.drain(..self.cursor);
+        self.cursor = 0;
+    }
+}
+
+/// Single-line editor with readline-style bindings. Keeps the lines accepted
+/// during this session so Up/Down can step back through earlier edits.
+#[derive(Debug, Default)]
+pub struct LineEditor {
+    history: Vec<String>,
+}
+
+impl LineEditor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Edits `initial` on one terminal row. Returns `None` if the user cancels
+    /// with Esc or Ctrl-C.
+    pub fn edit(&mut self, term: &Term, prompt: &str, initial: &str) -> Result<Option<String>> {
+        let mut line = LineBuffer::new(initial);
+        // Up/Down index into history; `history.len()` is the line being edited.
+        let mut pos = self.history.len();
+        let mut draft = initial.to_string();
+
+        loop {
+            redraw(term, prompt, &line)?;
+            match term.read_key()? {
+                Key::Enter => break,
+                Key::Escape | Key::CtrlC => {
+                    term.write_line("")?;
+                    return Ok(None);
+                }
+                Key::Char('\u{17}') => line.delete_word_back(), // Ctrl-W
+                Key::Char('\u{15}') => line.kill_to_start(),    // Ctrl-U
+                Key::Char('\u{0b}') => line.kill_to_end(),      // Ctrl-K
+                Key::Char('\u{02}') => line.left(),             // Ctrl-B
+                Key::Char('\u{06}') => line.right(),            // Ctrl-F
+                Key::Char('\u{04}') => line.delete(),           // Ctrl-D
+                Key::Char(c) if !c.is_control() => line.insert(c),
+                Key::Backspace => line.backspace(),
+                Key::Del => line.delete(),
+                Key::ArrowLeft => line.left(),
+                Key::ArrowRight => line.right(),
+                Key::Home => line.cursor = 0,
+                Key::End => line.cursor = line.chars.len(),
+                // Alt-b / Alt-f arrive as Esc followed by the letter.
+                Key::UnknownEscSeq(seq) if seq == ['b'] => line.cursor = line.word_start(),
+                Key::UnknownEscSeq(seq) if seq == ['f'] => line.cursor = line.word_end(),
+                Key::ArrowUp if pos > 0 => {
+                    if pos == self.history.len() {
+                        draft = line.text();
+                    }
+                    pos -= 1;
+                    line = LineBuffer::new(&self.history[pos]);
+                }
+                Key::ArrowDown if pos < self.history.len() => {
+                    pos += 1;
+                    line = LineBuffer::new(self.history.get(pos).unwrap_or(&draft));
+                }
+                _ => {}
+            }
+        }
+
+        term.write_line("")?;
+        let text = line.text().trim().to_string();
+        if !text.is_empty() && self.history.last() != Some(&text) {
+            self.history.push(text.clone());
+        }
+        Ok(Some(text))
+    }
+}
+
+fn redraw(term: &Term, prompt: &str, line: &LineBuffer) -> Result<()> {
+    term.clear_line()?;
+    term.write_str(prompt)?;
+    term.write_str(&line.text())?;
+    let back = line.chars.len() - line.cursor;
+    if back > 0 {
+        term.move_cursor_left(back)?;
+    }
+    term.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn word_motions_and_deletion() {
+        let mut line = LineBuffer::new("find . -name '*.log'  ");
+        line.delete_word_back();
+        assert_eq!(line.text(), "find . -name ");
+
+        line.cursor = line.word_start();
+        assert_eq!(line.cursor, 7);
+        line.cursor = line.word_end();
+        assert_eq!(line.cursor, 12);
+
+        line.cursor = 4;
+        line.kill_to_end();
+        assert_eq!(line.text(), "find");
+        line.insert('x');
+        line.left();
+        line.left();
+        line.backspace();
+        assert_eq!(line.text(), "fidx");
+        line.kill_to_start();
+        assert_eq!((line.text().as_str(), line.cursor), ("dx", 0));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::types::Provider;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -13,11 +14,110 @@ pub enum AppError {
     #[error("toml encode error: {0}")]
     TomlSer(#[from] toml::ser::Error),
     #[error("request failed: {0}")]
-    Request(#[from] ureq::Error),
+    Request(Box<ureq::Error>),
+    /// The provider answered with an error status; `message` is taken from
+    /// its error body when there is one.
+    #[error("{} returned {status}: {message}", .provider.as_str())]
+    Provider {
+        provider: Provider,
+        status: u16,
+        kind: ProviderErrorKind,
+        message: String,
+    },
+    #[error("request timed out after {0}s")]
+    Timeout(u64),
     #[error("prompt failed: {0}")]
     Dialoguer(#[from] dialoguer::Error),
 }
 
+/// Common provider failures that have an obvious next step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProviderErrorKind {
+    InvalidKey,
+    /// The key is valid but may not use this model or endpoint.
+    NoAccess,
+    UnknownModel,
+    QuotaExceeded,
+    ContextTooLong,
+    RateLimited,
+    Overloaded,
+    Other,
+}
+
+impl AppError {
+    /// True when the provider could not be reached at all (DNS, refused
+    /// connection, TLS, timeout) as opposed to answering with an error.
+    pub fn is_network(&self) -> bool {
+        match self {
+            AppError::Request(e) => matches!(**e, ureq::Error::Transport(_)),
+            AppError::Timeout(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Worth trying another provider: unreachable, rate limited, or a
+    /// server-side failure.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            AppError::Provider { status, .. } => *status == 429 || *status >= 500,
+            _ => self.is_network(),
+        }
+    }
+
+    /// What to try next, for errors where that is clear.
+    pub fn hint(&self) -> Option<String> {
+        let AppError::Provider { provider, kind, .. } = self else {
+            return None;
+        };
+        let hint = match kind {
+            ProviderErrorKind::InvalidKey => match provider.api_key_env() {
+                Some(var) => format!("check {var} or run `b setup` to enter a new key"),
+                None => "check the server's API key or run `b setup`".to_string(),
+            },
+            ProviderErrorKind::NoAccess => {
+                "this key can't use that model; pick another with --model or run `b setup`"
+                    .to_string()
+            }
+            ProviderErrorKind::UnknownModel if *provider == Provider::Ollama => {
+                "pull the model with `ollama pull <model>` or pick another with --model"
+                    .to_string()
+            }
+            ProviderErrorKind::UnknownModel => {
+                "check the model name passed with --model, or run `b setup` to pick one"
+                    .to_string()
+            }
+            ProviderErrorKind::QuotaExceeded => {
+                format!("the {} account is out of credit or quota; check billing", provider.as_str())
+            }
+            ProviderErrorKind::ContextTooLong => {
+                "shorten the prompt or drop environment details with --no-context".to_string()
+            }
+            ProviderErrorKind::RateLimited => {
+                "still rate limited after retrying; wait a minute or raise [retry] max_total_secs"
+                    .to_string()
+            }
+            ProviderErrorKind::Overloaded => {
+                "the provider is overloaded; try again shortly or switch with --provider"
+                    .to_string()
+            }
+            ProviderErrorKind::Other => return None,
+        };
+        Some(hint)
+    }
+}
+
+impl From<ureq::Error> for AppError {
+    fn from(value: ureq::Error) -> Self {
+        Self::Request(Box::new(value))
+    }
+}
+
+impl From<Box<ureq::Error>> for AppError {
+    fn from(value: Box<ureq::Error>) -> Self {
+        Self::Request(value)
+    }
+}
+
 impl From<&str> for AppError {
     fn from(value: &str) -> Self {
         Self::Message(value.to_string())
@@ -0,0 +1,126 @@
+use crate::error::Result;
+use std::fs;
+
+/// Keep only the tail of stderr; the actual error is almost always at the end.
+const MAX_STDERR_CHARS: usize = 4000;
+
+/// A command that failed in the user's shell, as recorded by the shell hook.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FailedCommand {
+    pub command: String,
+    pub status: Option<i32>,
+    pub stderr: Option<String>,
+}
+
+impl FailedCommand {
+    pub fn load(command: String, status: Option<i32>, stderr_file: Option<&str>) -> Result<Self> {
+        let stderr = match stderr_file {
+            Some(path) => {
+                let raw = fs::read(path)?;
+                let text = String::from_utf8_lossy(&raw);
+                let text = text.trim();
+                let skip = text.chars().count().saturating_sub(MAX_STDERR_CHARS);
+                Some(text.chars().skip(skip).collect::<String>()).filter(|s| !s.is_empty())
+            }
+            None => None,
+        };
+        Ok(Self {
+            command: command.trim().to_string(),
+            status,
+            stderr,
+        })
+    }
+
+    /// Builds the request sent to the model; `hint` is any extra text the user
+    /// typed after `b fix`.
+    pub fn prompt(&self, hint: Option<&str>) -> String {
+        let mut out = String::from(
+            "This command failed in my shell. Return a corrected command with the same intent.\n",
+        );
+        out.push_str(&format!("Command: {}\n", self.command));
+        if let Some(status) = self.status {
+            out.push_str(&format!("Exit status: {status}\n"));
+        }
+        if let Some(stderr) = &self.stderr {
+            out.push_str(&format!("Stderr:\n{stderr}\n"));
+        }
+        if let Some(hint) = hint.map(str::trim).filter(|h| !h.is_empty()) {
+            out.push_str(&format!("Hint: {hint}\n"));
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffPart {
+    Same(String),
+    Removed(String),
+    Added(String),
+}
+
+/// Word-level diff between two command lines (LCS over whitespace tokens).
+pub fn word_diff(before: &str, after: &str) -> Vec<DiffPart> {
+    let a: Vec<&str> = before.split_whitespace().collect();
+    let b: Vec<&str> = after.split_whitespace().collect();
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut out = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            out.push(DiffPart::Same(a[i].to_string()));
+            i += 1;
+            j += 1;
+        } else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            out.push(DiffPart::Removed(a[i].to_string()));
+            i += 1;
+        } else {
+            out.push(DiffPart::Added(b[j].to_string()));
+            j += 1;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn word_diff_marks_changed_tokens() {
+        let diff = word_diff("git psuh origin main", "git push origin main");
+        assert_eq!(
+            diff,
+            vec![
+                DiffPart::Same("git".to_string()),
+                DiffPart::Removed("psuh".to_string()),
+                DiffPart::Added("push".to_string()),
+                DiffPart::Same("origin".to_string()),
+                DiffPart::Same("main".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn prompt_includes_status_and_hint() {
+        let failed = FailedCommand {
+            command: "tar -xf a.tgz".to_string(),
+            status: Some(2),
+            stderr: Some("tar: a.tgz: Cannot open".to_string()),
+        };
+        let prompt = failed.prompt(Some("file is in ~/Downloads"));
+        assert!(prompt.contains("Command: tar -xf a.tgz\n"));
+        assert!(prompt.contains("Exit status: 2\n"));
+        assert!(prompt.contains("Stderr:\ntar: a.tgz: Cannot open\n"));
+        assert!(prompt.contains("Hint: file is in ~/Downloads\n"));
+    }
+}
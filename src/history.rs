@@ -0,0 +1,172 @@
+use crate::error::Result;
+use crate::shell_integration::ShellKind;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Oldest entries are dropped once the file grows past this.
+const MAX_ENTRIES: usize = 5000;
+
+/// What the user did with a generated command.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    /// Run by `b` itself.
+    Executed,
+    /// Handed to the shell wrapper via `--output-file`, which runs it. The
+    /// wrapper doesn't report back, so these entries have no exit status.
+    Written,
+    Copied,
+    Quit,
+    /// Printed by `--plain`, `--json` or a piped run, for the caller to use.
+    Printed,
+}
+
+impl Outcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Executed => "executed",
+            Outcome::Written => "written",
+            Outcome::Copied => "copied",
+            Outcome::Quit => "quit",
+            Outcome::Printed => "printed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct HistoryEntry {
+    /// Unix seconds.
+    pub timestamp: u64,
+    pub prompt: String,
+    pub provider: String,
+    pub model: String,
+    pub command: String,
+    /// The shell whose syntax `command` is in; entries from before it was
+    /// recorded were all bash.
+    #[serde(default)]
+    pub shell: ShellKind,
+    pub safety: String,
+    pub outcome: Outcome,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_status: Option<i32>,
+}
+
+impl HistoryEntry {
+    /// Whether the user took the command: handed to the wrapper, copied, or
+    /// run by `b` and exited 0. Quit, printed and failed entries don't count.
+    pub fn accepted(&self) -> bool {
+        match self.outcome {
+            Outcome::Written | Outcome::Copied => true,
+            Outcome::Executed => self.exit_status == Some(0),
+            Outcome::Quit | Outcome::Printed => false,
+        }
+    }
+}
+
+/// `history.jsonl` lives next to `config.toml`, one entry per line.
+pub fn history_file_path(config_path: &Path) -> PathBuf {
+    config_path.with_file_name("history.jsonl")
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Entries oldest first. Lines that fail to parse (e.g. a write cut short)
+/// are skipped rather than failing the whole load.
+pub fn load(path: &Path) -> Result<Vec<HistoryEntry>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let content = fs::read_to_string(path)?;
+    Ok(content
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+pub fn append(path: &Path, entry: &HistoryEntry) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", serde_json::to_string(entry)?)?;
+    drop(file);
+
+    let entries = load(path)?;
+    if entries.len() > MAX_ENTRIES {
+        let keep = &entries[entries.len() - MAX_ENTRIES..];
+        let mut out = String::new();
+        for entry in keep {
+            out.push_str(&serde_json::to_string(entry)?);
+            out.push('\n');
+        }
+        fs::write(path, out)?;
+    }
+    Ok(())
+}
+
+/// Short relative age for list views, e.g. "5m ago".
+pub fn age(timestamp: u64, now: u64) -> String {
+    let secs = now.saturating_sub(timestamp);
+    match secs {
+        0..=59 => "just now".to_string(),
+        60..=3599 => format!("{}m ago", secs / 60),
+        3600..=86_399 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86_400),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn append_and_load_round_trip_skipping_bad_lines() {
+        let dir = std::env::temp_dir().join(format!("b-history-test-{}", std::process::id()));
+        let path = dir.join("history.jsonl");
+        let _ = fs::remove_file(&path);
+
+        let entry = HistoryEntry {
+            timestamp: 1_700_000_000,
+            prompt: "list files".to_string(),
+            provider: "openai".to_string(),
+            model: "gpt-4o-mini".to_string(),
+            command: "ls -la".to_string(),
+            shell: ShellKind::Nu,
+            safety: "safe".to_string(),
+            outcome: Outcome::Executed,
+            exit_status: Some(0),
+        };
+        append(&path, &entry).expect("append failed");
+        fs::write(&path, format!("{}{{\"truncated\n", fs::read_to_string(&path).unwrap()))
+            .expect("write failed");
+        append(&path, &HistoryEntry { outcome: Outcome::Quit, exit_status: None, ..entry.clone() })
+            .expect("append failed");
+
+        let loaded = load(&path).expect("load failed");
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[0], entry);
+        assert_eq!(loaded[1].outcome, Outcome::Quit);
+
+        // Entries written before the shell was recorded read as bash.
+        let old = r#"{"timestamp":1,"prompt":"p","provider":"openai","model":"m",
+            "command":"ls","safety":"safe","outcome":"quit"}"#;
+        let old: HistoryEntry = serde_json::from_str(old).expect("old entry should load");
+        assert_eq!(old.shell, ShellKind::Bash);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn age_uses_coarse_units() {
+        assert_eq!(age(100, 130), "just now");
+        assert_eq!(age(0, 7200), "2h ago");
+        assert_eq!(age(0, 3 * 86_400), "3d ago");
+    }
+}
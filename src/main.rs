@@ -1,54 +1,223 @@
+mod cache;
 mod config;
+mod context;
+mod editor;
 mod error;
+mod fix;
+mod history;
+mod net;
 mod onboarding;
 mod prompt;
 mod providers;
+mod safety;
 mod shell_integration;
 mod types;
 mod ui;
+mod usage;
 
-use crate::config::{config_file_path, load_file_config, merge_cli_with_setup, parse_cli};
+use crate::cache::{cache_file_path, generate_cached};
+use crate::config::{
+    config_file_path, load_file_config, load_policy, merge_cli_with_setup, parse_cli,
+};
+use crate::context::collect_context;
 use crate::error::{AppError, Result};
+use crate::history::history_file_path;
+use crate::net::build_agent;
 use crate::onboarding::run_onboarding;
 use crate::prompt::resolve_prompt;
-use crate::providers::generate_once;
-use crate::shell_integration::{detect_shell_from_env, install as install_shell, parse_shell_name};
-use crate::ui::{emit_non_interactive, run_interactive};
+use crate::providers::{generate_candidates, generate_once, initial_messages};
+use crate::shell_integration::{
+    detect_shell_from_env, install as install_shell, parse_shell_name, uninstall as uninstall_shell,
+    BlockStatus, ShellKind,
+};
+use crate::ui::{
+    emit_candidates, emit_non_interactive, run_explain, run_history, run_interactive, run_usage,
+};
+use crate::usage::usage_file_path;
 use std::io::IsTerminal;
+use std::path::PathBuf;
 
 fn main() {
     let args: Vec<String> = std::env::args().skip(1).collect();
     if let Err(err) = run(args) {
         eprintln!("error: {err}");
+        if let Some(hint) = err.hint() {
+            eprintln!("hint: {hint}");
+        }
         std::process::exit(1);
     }
 }
 
+/// `b init [--uninstall|--status] [--rc-file <path>] [shell]`.
+fn run_init(args: &[String]) -> Result<()> {
+    let mut uninstall = false;
+    let mut status = false;
+    let mut shell = None;
+    let mut rc_file = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--uninstall" => uninstall = true,
+            "--status" => status = true,
+            "--rc-file" => {
+                let path = args
+                    .next()
+                    .ok_or_else(|| AppError::from("--rc-file requires a path"))?;
+                rc_file = Some(PathBuf::from(path));
+            }
+            name => {
+                shell = Some(parse_shell_name(name).ok_or_else(|| {
+                    AppError::from(format!("unknown shell `{name}` (use zsh|bash|fish|nu|pwsh)"))
+                })?);
+            }
+        }
+    }
+    if uninstall && status {
+        return Err(AppError::from("use either --uninstall or --status"));
+    }
+
+    // A custom rc file belongs to one shell, so that shell is needed.
+    if rc_file.is_some() {
+        shell = shell.or_else(detect_shell_from_env);
+        if shell.is_none() {
+            return Err(AppError::from(
+                "--rc-file needs a shell: run `b init zsh|bash|fish|nu|pwsh --rc-file <path>`",
+            ));
+        }
+    }
+    if status {
+        let shells = match shell {
+            Some(shell) => vec![shell],
+            None => ShellKind::ALL.to_vec(),
+        };
+        for shell in shells {
+            let rc_path = match &rc_file {
+                Some(path) => path.clone(),
+                None => shell.rc_path()?,
+            };
+            let entry = shell_integration::status(shell, rc_path)?;
+            let state = match &entry.status {
+                BlockStatus::NotInstalled => "not installed".to_string(),
+                BlockStatus::Current => "up to date".to_string(),
+                BlockStatus::Outdated(Some(version)) => format!("outdated (from {version})"),
+                BlockStatus::Outdated(None) => "outdated (unversioned)".to_string(),
+                BlockStatus::Modified => "modified by hand".to_string(),
+            };
+            println!("{:<5} {state:<24} {}", entry.shell.as_str(), entry.rc_path.display());
+            if matches!(entry.status, BlockStatus::Outdated(_) | BlockStatus::Modified) {
+                println!("      run `b init {}` to update", entry.shell.as_str());
+            }
+        }
+        return Ok(());
+    }
+
+    let shell = shell.or_else(detect_shell_from_env).ok_or_else(|| {
+        AppError::from("could not detect shell. Run `b init zsh|bash|fish|nu|pwsh`.")
+    })?;
+    let rc_path = match rc_file {
+        Some(path) => path,
+        None => shell.rc_path()?,
+    };
+    if uninstall {
+        let result = uninstall_shell(shell, rc_path)?;
+        match result.backup {
+            Some(backup) => println!(
+                "Removed shell integration for {} from {} (backup: {})",
+                result.shell.as_str(),
+                result.rc_path.display(),
+                backup.display()
+            ),
+            None => println!(
+                "No shell integration for {} in {}",
+                result.shell.as_str(),
+                result.rc_path.display()
+            ),
+        }
+        return Ok(());
+    }
+
+    let result = install_shell(shell, rc_path)?;
+    if result.updated {
+        println!(
+            "Installed shell integration for {} at {}",
+            result.shell.as_str(),
+            result.rc_path.display()
+        );
+        if let Some(backup) = &result.backup {
+            println!("Previous version saved as {}", backup.display());
+        }
+    } else {
+        println!(
+            "Shell integration already up to date for {} at {}",
+            result.shell.as_str(),
+            result.rc_path.display()
+        );
+    }
+    let source = if result.shell == ShellKind::Pwsh { "." } else { "source" };
+    println!("Restart shell or run: {source} {}", result.rc_path.display());
+    Ok(())
+}
+
 fn run(args: Vec<String>) -> Result<()> {
     if let Some(first) = args.first() {
         if first == "init" {
-            let shell = args
-                .get(1)
-                .and_then(|s| parse_shell_name(s))
-                .or_else(detect_shell_from_env)
-                .ok_or_else(|| {
-                    AppError::from("could not detect shell. Run `b init zsh` or `b init bash`.")
-                })?;
-            let result = install_shell(shell)?;
-            if result.updated {
-                println!(
-                    "Installed shell integration for {} at {}",
-                    result.shell.as_str(),
-                    result.rc_path.display()
-                );
-            } else {
-                println!(
-                    "Shell integration already up to date for {} at {}",
-                    result.shell.as_str(),
-                    result.rc_path.display()
-                );
+            return run_init(&args[1..]);
+        }
+        if first == "history" {
+            let config_path = config_file_path()?;
+            let file_config = load_file_config(&config_path)?;
+            let policy = load_policy(&config_path, file_config.as_ref())?;
+            let query = args[1..].join(" ");
+            let query = Some(query.as_str()).filter(|q| !q.is_empty());
+            return run_history(&history_file_path(&config_path), &policy, query);
+        }
+        if first == "cache" {
+            let config_path = config_file_path()?;
+            let file_config = load_file_config(&config_path)?;
+            let settings = file_config.and_then(|c| c.cache).unwrap_or_default();
+            let path = cache_file_path(&config_path);
+            match args.get(1).map(String::as_str) {
+                Some("clear") => {
+                    let removed = cache::clear(&path)?;
+                    println!("Removed {removed} cached answers.");
+                }
+                Some("stats") | None => {
+                    let stats = cache::stats(&path, &settings)?;
+                    let lookups = stats.hits + stats.misses;
+                    let rate = (stats.hits * 100).checked_div(lookups).unwrap_or(0);
+                    println!("path:    {}", path.display());
+                    println!("entries: {} ({} expired)", stats.entries, stats.expired);
+                    println!("hits:    {} of {lookups} lookups ({rate}%)", stats.hits);
+                    println!("size:    {} bytes", stats.bytes);
+                }
+                Some(other) => {
+                    return Err(AppError::from(format!(
+                        "unknown cache command `{other}` (use: b cache clear | b cache stats)"
+                    )));
+                }
             }
-            println!("Restart shell or run: source {}", result.rc_path.display());
+            return Ok(());
+        }
+        if first == "usage" {
+            let days = match args.get(1).map(String::as_str) {
+                None => 30,
+                Some("--days") => args
+                    .get(2)
+                    .and_then(|d| d.parse::<u64>().ok())
+                    .filter(|d| *d > 0)
+                    .ok_or_else(|| AppError::from("--days needs a positive number"))?,
+                Some(other) => {
+                    return Err(AppError::from(format!(
+                        "unknown usage option `{other}` (use: b usage [--days n])"
+                    )));
+                }
+            };
+            let config_path = config_file_path()?;
+            let file_config = load_file_config(&config_path)?;
+            let prices = usage::price_table(&file_config.map(|c| c.prices).unwrap_or_default());
+            let records = usage::load(&usage_file_path(&config_path))?;
+            let since = history::now().saturating_sub((days - 1) * 86_400) / 86_400 * 86_400;
+            run_usage(&usage::summarize(&records, since, &prices), days);
             return Ok(());
         }
     }
@@ -56,15 +225,23 @@ fn run(args: Vec<String>) -> Result<()> {
     let config_path = config_file_path()?;
     let file_config = load_file_config(&config_path)?;
     let mut config = parse_cli(args, file_config.clone())?;
+    config.policy = load_policy(&config_path, file_config.as_ref())?;
+    config.history_path = Some(history_file_path(&config_path));
+    config.cache_path = Some(cache_file_path(&config_path));
+    config.usage_path = Some(usage_file_path(&config_path));
+    if config.cassette.is_some() {
+        // A cached answer would skip the request being recorded or replayed.
+        config.cache_path = None;
+    }
 
-    let agent = ureq::AgentBuilder::new().build();
+    let agent = build_agent(&config)?;
 
     if config.setup {
         let _saved = run_onboarding(&config_path, file_config, &agent)?;
         return Ok(());
     }
 
-    if config.provider_api_key_missing() {
+    if config.provider_api_key_missing() && !config.offline {
         if std::io::stdin().is_terminal() && std::io::stdout().is_terminal() {
             eprintln!("No provider key found. Launching onboarding...");
             let saved = run_onboarding(&config_path, file_config, &agent)?;
@@ -76,14 +253,36 @@ fn run(args: Vec<String>) -> Result<()> {
         }
     }
 
-    let prompt = resolve_prompt(config.prompt.as_deref())?;
+    if config.explain_existing {
+        if config.offline {
+            return Err(AppError::from("`b explain` needs the provider; drop --offline"));
+        }
+        let command = resolve_prompt(config.prompt.as_deref())?;
+        return run_explain(&agent, &config, &command);
+    }
+
+    let prompt = match &config.fix {
+        Some(failed) => failed.prompt(config.prompt.as_deref()),
+        None => resolve_prompt(config.prompt.as_deref())?,
+    };
+    if !config.no_context {
+        let shell = config.shell.has_own_dialect().then(|| config.shell.as_str());
+        config.context = Some(collect_context(shell));
+    }
     let interactive = std::io::stdout().is_terminal() && !config.json && !config.plain;
 
     if interactive {
         run_interactive(&agent, &config, &prompt)?;
+    } else if config.candidates > 1 && !config.offline {
+        let messages = initial_messages(config.context.as_ref(), &prompt);
+        let outputs = generate_candidates(&agent, &config, &messages, config.candidates)?;
+        emit_candidates(&config, &prompt, outputs)?;
     } else {
-        let output = generate_once(&agent, &config, &prompt)?;
-        emit_non_interactive(&config, output)?;
+        let (output, _) = generate_cached(&config, &prompt, || {
+            let messages = initial_messages(config.context.as_ref(), &prompt);
+            generate_once(&agent, &config, &messages)
+        })?;
+        emit_non_interactive(&config, &prompt, output)?;
     }
     Ok(())
 }
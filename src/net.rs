@@ -0,0 +1,198 @@
+use crate::error::{AppError, Result};
+use crate::types::AppConfig;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::CertificateDer;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// `[network]` section of `config.toml`.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct NetworkSettings {
+    /// Seconds; 0 disables a timeout.
+    pub connect_timeout_secs: u64,
+    /// Longest silence while waiting for response bytes.
+    pub read_timeout_secs: u64,
+    /// Cap on a streamed response, counted from the first attempt.
+    pub timeout_secs: u64,
+    /// Used when no proxy environment variable is set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    /// PEM file of extra root certificates, e.g. for a TLS-intercepting proxy.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ca_bundle: Option<PathBuf>,
+}
+
+impl Default for NetworkSettings {
+    fn default() -> Self {
+        Self {
+            connect_timeout_secs: 10,
+            read_timeout_secs: 60,
+            timeout_secs: 180,
+            proxy: None,
+            ca_bundle: None,
+        }
+    }
+}
+
+/// The HTTP agent for requests to `config.base_url`. Proxy selection depends
+/// on the host, so fallback providers get an agent of their own.
+pub fn build_agent(config: &AppConfig) -> Result<ureq::Agent> {
+    let settings = &config.network;
+    let mut builder = ureq::AgentBuilder::new();
+    if let Some(timeout) = secs(settings.connect_timeout_secs) {
+        builder = builder.timeout_connect(timeout);
+    }
+    if let Some(timeout) = secs(settings.read_timeout_secs) {
+        builder = builder.timeout_read(timeout);
+    }
+    if let Some(proxy) = proxy_for(settings, &config.base_url) {
+        let proxy = ureq::Proxy::new(&proxy)
+            .map_err(|e| AppError::from(format!("invalid proxy `{proxy}`: {e}")))?;
+        builder = builder.proxy(proxy);
+    }
+    if let Some(path) = &settings.ca_bundle {
+        builder = builder.tls_config(tls_config(path)?);
+    }
+    Ok(builder.build())
+}
+
+fn secs(value: u64) -> Option<Duration> {
+    (value > 0).then(|| Duration::from_secs(value))
+}
+
+/// The overall request timeout, from the first attempt through retries to
+/// the last byte. ureq's own timeout would take precedence over the read
+/// timeout, so streams check it between chunks instead; replies read in one
+/// go get it through `bound`.
+pub struct Deadline {
+    at: Option<Instant>,
+    secs: u64,
+}
+
+impl Deadline {
+    pub fn start(settings: &NetworkSettings) -> Self {
+        Self {
+            at: secs(settings.timeout_secs).map(|timeout| Instant::now() + timeout),
+            secs: settings.timeout_secs,
+        }
+    }
+
+    pub fn check(&self) -> Result<()> {
+        match self.at {
+            Some(at) if Instant::now() > at => Err(AppError::Timeout(self.secs)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Time left, or `None` without a timeout.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.at.map(|at| at.saturating_duration_since(Instant::now()))
+    }
+
+    /// `request` with ureq's overall timeout set to the time left, so a
+    /// server trickling bytes can't outlast the deadline.
+    pub fn bound(&self, request: ureq::Request) -> ureq::Request {
+        match self.remaining() {
+            Some(left) => request.timeout(left),
+            None => request,
+        }
+    }
+}
+
+/// Scheme-specific variable first, then `ALL_PROXY`, then the config key,
+/// unless the host is listed in `NO_PROXY`.
+fn proxy_for(settings: &NetworkSettings, base_url: &str) -> Option<String> {
+    let (scheme, host) = split_url(base_url)?;
+    let scheme_var = if scheme == "http" { "HTTP_PROXY" } else { "HTTPS_PROXY" };
+    let proxy = env_either(scheme_var)
+        .or_else(|| env_either("ALL_PROXY"))
+        .or_else(|| settings.proxy.clone().filter(|p| !p.trim().is_empty()))?;
+    let no_proxy = env_either("NO_PROXY").unwrap_or_default();
+    (!no_proxy_matches(&no_proxy, host)).then_some(proxy)
+}
+
+/// Upper or lower case, as curl accepts both.
+fn env_either(name: &str) -> Option<String> {
+    env::var(name)
+        .ok()
+        .or_else(|| env::var(name.to_ascii_lowercase()).ok())
+        .filter(|v| !v.trim().is_empty())
+}
+
+/// Scheme and host (without port) of a base URL.
+fn split_url(url: &str) -> Option<(&str, &str)> {
+    let (scheme, rest) = url.split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let authority = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+    let host = match authority.strip_prefix('[') {
+        Some(v6) => v6.split(']').next()?,
+        None => authority.split(':').next()?,
+    };
+    Some((scheme, host))
+}
+
+/// `NO_PROXY` as curl reads it: comma-separated hosts or domain suffixes,
+/// optional leading dot, optional port, `*` for everything.
+fn no_proxy_matches(no_proxy: &str, host: &str) -> bool {
+    let host = host.to_ascii_lowercase();
+    no_proxy.split(',').map(str::trim).any(|entry| {
+        if entry == "*" {
+            return true;
+        }
+        let entry = match entry.rsplit_once(':') {
+            Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name,
+            _ => entry,
+        };
+        let entry = entry.trim_start_matches('.').to_ascii_lowercase();
+        !entry.is_empty() && (host == entry || host.ends_with(&format!(".{entry}")))
+    })
+}
+
+/// The built-in web roots plus every certificate in `path`.
+fn tls_config(path: &Path) -> Result<Arc<rustls::ClientConfig>> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let invalid = |e: &dyn std::fmt::Display| {
+        AppError::from(format!("could not read CA bundle {}: {e}", path.display()))
+    };
+    let mut added = 0;
+    for cert in CertificateDer::pem_file_iter(path).map_err(|e| invalid(&e))? {
+        roots.add(cert.map_err(|e| invalid(&e))?).map_err(|e| invalid(&e))?;
+        added += 1;
+    }
+    if added == 0 {
+        return Err(invalid(&"no certificates found"));
+    }
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| invalid(&e))?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_proxy_matches_hosts_and_suffixes() {
+        let list = "localhost, .corp.example:8443,10.0.0.5";
+        assert!(no_proxy_matches(list, "localhost"));
+        assert!(no_proxy_matches(list, "api.corp.example"));
+        assert!(no_proxy_matches(list, "corp.example"));
+        assert!(no_proxy_matches(list, "10.0.0.5"));
+        assert!(!no_proxy_matches(list, "api.openai.com"));
+        assert!(!no_proxy_matches(list, "notcorp.example"));
+        assert!(no_proxy_matches("*", "anything"));
+        assert!(!no_proxy_matches("", "anything"));
+
+        assert_eq!(split_url("https://u:p@[::1]:8080/v1"), Some(("https", "::1")));
+        assert_eq!(split_url("http://127.0.0.1:11434"), Some(("http", "127.0.0.1")));
+    }
+}
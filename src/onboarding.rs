@@ -1,7 +1,10 @@
+use crate::cache::CacheSettings;
 use crate::config::{detected_provider_keys, save_file_config};
 use crate::error::{AppError, Result};
-use crate::providers::generate_once;
-use crate::shell_integration::{detect_shell_from_env, install as install_shell};
+use crate::providers::retry::RetrySettings;
+use crate::providers::{generate_once, initial_messages};
+use crate::safety::Policy;
+use crate::shell_integration::{detect_shell_from_env, install as install_shell, ShellKind};
 use crate::types::{AppConfig, FileConfig, Provider};
 use dialoguer::{theme::ColorfulTheme, Confirm, FuzzySelect, Input, Password, Select};
 use serde::{Deserialize, Serialize};
@@ -71,11 +74,32 @@ pub fn run_onboarding(
         setup: false,
         prompt: None,
         output_file: None,
+        candidates: 1,
+        no_context: true,
+        context: None,
+        fix: None,
+        explain_existing: false,
+        policy: Policy::default(),
+        history_path: None,
+        cache: CacheSettings::default(),
+        cache_path: None,
+        no_cache: true,
+        offline: false,
+        retry: RetrySettings::default(),
+        network: existing
+            .as_ref()
+            .and_then(|c| c.network.clone())
+            .unwrap_or_default(),
+        fallback: Vec::new(),
+        race: false,
+        usage_path: None,
+        cassette: None,
+        shell: ShellKind::Bash,
     };
 
     print!("{} ", paint("Running a tiny test prompt...", Ansi::Cyan));
     io::stdout().flush()?;
-    let test = generate_once(agent, &staged, "print current directory");
+    let test = generate_once(agent, &staged, &initial_messages(None, "print current directory"));
     match test {
         Ok(_) => println!("{}", paint("nice, connection looks good.", Ansi::Green)),
         Err(e) => {
@@ -88,8 +112,16 @@ pub fn run_onboarding(
         provider: Some(provider),
         model: Some(model),
         base_url: Some(base_url),
-        api_key: Some(key),
+        api_key: Some(key).filter(|k| !k.is_empty()),
         no_fun: existing.as_ref().and_then(|c| c.no_fun).or(Some(false)),
+        no_context: existing.as_ref().and_then(|c| c.no_context),
+        race: existing.as_ref().and_then(|c| c.race),
+        policy: existing.as_ref().and_then(|c| c.policy.clone()),
+        cache: existing.as_ref().and_then(|c| c.cache.clone()),
+        retry: existing.as_ref().and_then(|c| c.retry.clone()),
+        network: existing.as_ref().and_then(|c| c.network.clone()),
+        fallback: existing.as_ref().map(|c| c.fallback.clone()).unwrap_or_default(),
+        prices: existing.as_ref().map(|c| c.prices.clone()).unwrap_or_default(),
     };
     save_file_config(config_path, &saved)?;
     println!();
@@ -109,6 +141,8 @@ fn select_provider(
         "anthropic   Anthropic",
         "openrouter  OpenRouter",
         "vercel      Vercel AI Gateway",
+        "ollama      Ollama (local)",
+        "llamacpp    llama.cpp server (local)",
     ];
 
     let suggested = default
@@ -119,6 +153,8 @@ fn select_provider(
         Provider::Anthropic => 1,
         Provider::Openrouter => 2,
         Provider::Vercel => 3,
+        Provider::Ollama => 4,
+        Provider::Llamacpp => 5,
     };
 
     let idx = Select::with_theme(theme)
@@ -132,6 +168,8 @@ fn select_provider(
         1 => Provider::Anthropic,
         2 => Provider::Openrouter,
         3 => Provider::Vercel,
+        4 => Provider::Ollama,
+        5 => Provider::Llamacpp,
         _ => return Err(AppError::from("invalid provider selection")),
     };
     Ok(provider)
@@ -215,7 +253,10 @@ fn fetch_live_models(
     api_key: &str,
 ) -> Result<Vec<String>> {
     let url = match provider {
-        Provider::Anthropic => format!("{}/v1/models", base_url.trim_end_matches('/')),
+        Provider::Anthropic | Provider::Llamacpp => {
+            format!("{}/v1/models", base_url.trim_end_matches('/'))
+        }
+        Provider::Ollama => format!("{}/api/tags", base_url.trim_end_matches('/')),
         _ => format!("{}/models", base_url.trim_end_matches('/')),
     };
 
@@ -240,6 +281,11 @@ fn fetch_live_models(
                 .set("x-api-key", api_key)
                 .set("anthropic-version", "2023-06-01");
         }
+        Provider::Ollama | Provider::Llamacpp => {
+            if !api_key.trim().is_empty() {
+                req = req.set("Authorization", &format!("Bearer {api_key}"));
+            }
+        }
     }
 
     let value: Value = req.call()?.into_json()?;
@@ -256,6 +302,15 @@ fn extract_model_ids(value: Value) -> Vec<String> {
         }
         return out;
     }
+    // Ollama's `/api/tags` lists installed models under `models[].name`.
+    if let Some(array) = value.get("models").and_then(|v| v.as_array()) {
+        for item in array {
+            if let Some(name) = item.get("name").and_then(|v| v.as_str()) {
+                out.push(name.to_string());
+            }
+        }
+        return out;
+    }
     if let Some(array) = value.as_array() {
         for item in array {
             if let Some(id) = item.get("id").and_then(|v| v.as_str()) {
@@ -324,6 +379,11 @@ fn select_api_key(
     detected: &[(Provider, String)],
     existing: Option<&FileConfig>,
 ) -> Result<String> {
+    if !provider.requires_api_key() {
+        println!("{}", paint("Local provider, no API key needed.", Ansi::Dim));
+        return Ok(String::new());
+    }
+
     if let Some((_, key)) = detected.iter().find(|(p, _)| *p == provider) {
         let use_detected = Confirm::with_theme(theme)
             .with_prompt("Use detected env key?")
@@ -395,6 +455,14 @@ fn model_candidates(provider: Provider) -> Vec<&'static str> {
             "openai/gpt-4o-mini",
             "anthropic/claude-3-5-sonnet-latest",
         ],
+        Provider::Ollama => vec![
+            "llama3.2",
+            "llama3.1",
+            "qwen2.5-coder",
+            "mistral",
+            "gemma2",
+        ],
+        Provider::Llamacpp => vec!["default"],
     }
 }
 
@@ -418,7 +486,7 @@ fn maybe_install_shell_integration(theme: &ColorfulTheme) -> Result<()> {
         println!(
             "{}",
             paint(
-                "Tip: run `b init zsh` or `b init bash` for parent-shell execution + history.",
+                "Tip: run `b init zsh|bash|fish|nu|pwsh` for parent-shell execution + history.",
                 Ansi::Dim
             )
         );
@@ -443,7 +511,7 @@ fn maybe_install_shell_integration(theme: &ColorfulTheme) -> Result<()> {
         return Ok(());
     }
 
-    let installed = install_shell(shell)?;
+    let installed = install_shell(shell, shell.rc_path()?)?;
     if installed.updated {
         println!(
             "{} {}",
@@ -0,0 +1,293 @@
+use crate::error::{AppError, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+#[cfg(test)]
+use std::{cell::RefCell, sync::mpsc};
+
+/// Response headers worth keeping; the rest describe the connection, not
+/// the answer.
+const KEPT_HEADERS: &[&str] = &["content-type", "retry-after"];
+
+/// Provider HTTP traffic saved to, or served from, a JSON file. Only request
+/// bodies are stored, never headers, so API keys stay out of cassettes;
+/// prompts and the environment context do not.
+#[derive(Debug)]
+pub struct Cassette {
+    path: PathBuf,
+    mode: Mode,
+    tape: Mutex<Tape>,
+    #[cfg(test)]
+    gates: Mutex<Gates>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Record,
+    Replay,
+}
+
+#[derive(Debug, Default)]
+struct Tape {
+    interactions: Vec<Interaction>,
+    played: Vec<bool>,
+}
+
+/// Lets racing tests decide which replayed request answers first.
+#[cfg(test)]
+#[derive(Debug, Default)]
+struct Gates {
+    /// Replays that wait until the sender side of their channel is dropped.
+    held: Vec<(String, mpsc::Receiver<()>)>,
+    /// Senders dropped once the thread that replays the endpoint exits.
+    released_by: Vec<(String, mpsc::Sender<()>)>,
+}
+
+#[cfg(test)]
+thread_local! {
+    static RELEASED_ON_EXIT: RefCell<Vec<mpsc::Sender<()>>> = const { RefCell::new(Vec::new()) };
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct CassetteFile {
+    interactions: Vec<Interaction>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(super) struct Interaction {
+    pub(super) request: RecordedRequest,
+    pub(super) response: RecordedResponse,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(super) struct RecordedRequest {
+    pub(super) method: String,
+    pub(super) url: String,
+    pub(super) body: Value,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(super) struct RecordedResponse {
+    pub(super) status: u16,
+    #[serde(default)]
+    pub(super) status_text: String,
+    #[serde(default)]
+    pub(super) headers: BTreeMap<String, String>,
+    /// Streams are stored as the raw server-sent events.
+    pub(super) body: String,
+}
+
+impl Cassette {
+    /// Starts an empty cassette; `path` is rewritten after every response.
+    pub fn record(path: PathBuf) -> Self {
+        Self {
+            path,
+            mode: Mode::Record,
+            tape: Mutex::new(Tape::default()),
+            #[cfg(test)]
+            gates: Mutex::default(),
+        }
+    }
+
+    pub fn replay(path: PathBuf) -> Result<Self> {
+        let content = fs::read_to_string(&path).map_err(|e| {
+            AppError::from(format!("could not read cassette {}: {e}", path.display()))
+        })?;
+        let file: CassetteFile = serde_json::from_str(&content).map_err(|e| {
+            AppError::from(format!("invalid cassette {}: {e}", path.display()))
+        })?;
+        Ok(Self::replaying(path, file.interactions))
+    }
+
+    pub(super) fn replaying(path: PathBuf, interactions: Vec<Interaction>) -> Self {
+        Self {
+            path,
+            mode: Mode::Replay,
+            tape: Mutex::new(Tape {
+                played: vec![false; interactions.len()],
+                interactions,
+            }),
+            #[cfg(test)]
+            gates: Mutex::default(),
+        }
+    }
+
+    /// Replaying needs no network and no API key.
+    pub fn is_replay(&self) -> bool {
+        self.mode == Mode::Replay
+    }
+
+    fn play(&self, request: &ureq::Request) -> Result<ureq::Response> {
+        let mut tape = self.tape.lock().unwrap_or_else(|e| e.into_inner());
+        let wanted = url_path(request.url());
+        let Tape {
+            interactions,
+            played,
+        } = &mut *tape;
+        // Hosts and base paths differ between machines, so only the method
+        // and endpoint must match; the first unplayed one wins.
+        let (index, interaction) = interactions
+            .iter()
+            .enumerate()
+            .find(|(i, it)| {
+                !played[*i]
+                    && it.request.method.eq_ignore_ascii_case(request.method())
+                    && same_endpoint(url_path(&it.request.url), wanted)
+            })
+            .ok_or_else(|| {
+                AppError::from(format!(
+                    "cassette {} has no unplayed response for {} {wanted}",
+                    self.path.display(),
+                    request.method()
+                ))
+            })?;
+        played[index] = true;
+        let response = into_result(&interaction.response);
+        drop(tape);
+        #[cfg(test)]
+        self.pass_gates(wanted);
+        response
+    }
+
+    /// The next replay of `endpoint` waits until the returned sender is
+    /// dropped.
+    #[cfg(test)]
+    pub(super) fn hold(&self, endpoint: &str) -> mpsc::Sender<()> {
+        let (release, held) = mpsc::channel();
+        let mut gates = self.gates.lock().unwrap_or_else(|e| e.into_inner());
+        gates.held.push((endpoint.to_string(), held));
+        release
+    }
+
+    /// The next replay of `endpoint` waits until the thread that replays
+    /// `first` has exited, and with it reported its result.
+    #[cfg(test)]
+    pub(super) fn hold_until_done(&self, endpoint: &str, first: &str) {
+        let release = self.hold(endpoint);
+        let mut gates = self.gates.lock().unwrap_or_else(|e| e.into_inner());
+        gates.released_by.push((first.to_string(), release));
+    }
+
+    #[cfg(test)]
+    fn pass_gates(&self, wanted: &str) {
+        let mut gates = self.gates.lock().unwrap_or_else(|e| e.into_inner());
+        let (done, pending) = std::mem::take(&mut gates.released_by)
+            .into_iter()
+            .partition(|(first, _)| same_endpoint(first, wanted));
+        gates.released_by = pending;
+        RELEASED_ON_EXIT.with(|senders| {
+            senders.borrow_mut().extend(done.into_iter().map(|(_, release)| release));
+        });
+        let held = gates.held.iter().position(|(endpoint, _)| same_endpoint(endpoint, wanted));
+        if let Some((_, held)) = held.map(|i| gates.held.remove(i)) {
+            drop(gates);
+            // Nothing is ever sent; the gate opens when the sender is dropped.
+            let _ = held.recv();
+        }
+    }
+
+    fn save(&self, interaction: Interaction) -> Result<()> {
+        let mut tape = self.tape.lock().unwrap_or_else(|e| e.into_inner());
+        tape.interactions.push(interaction);
+        let file = CassetteFile {
+            interactions: tape.interactions.clone(),
+        };
+        write_file(&self.path, &file)
+    }
+}
+
+/// `request.send_json(body)`, recorded or replayed when a cassette is set.
+/// Failed requests come back as `AppError::Request` like any other.
+pub fn send_json(
+    cassette: Option<&Cassette>,
+    request: &ureq::Request,
+    body: &Value,
+) -> Result<ureq::Response> {
+    let Some(cassette) = cassette else {
+        return Ok(request.clone().send_json(body)?);
+    };
+    if cassette.is_replay() {
+        return cassette.play(request);
+    }
+    let (status, response) = match request.clone().send_json(body) {
+        Ok(response) => (response.status(), response),
+        Err(ureq::Error::Status(status, response)) => (status, response),
+        // Nothing came back, so there is nothing to replay.
+        Err(transport) => return Err(transport.into()),
+    };
+    // Streams are read to the end here, so recording shows no live output.
+    let recorded = read_response(status, response)?;
+    let result = into_result(&recorded);
+    cassette.save(Interaction {
+        request: RecordedRequest {
+            method: request.method().to_string(),
+            url: request.url().to_string(),
+            body: body.clone(),
+        },
+        response: recorded,
+    })?;
+    result
+}
+
+/// Reads an error response's body for inspection and returns it with the
+/// error rebuilt around it, so whoever handles the error can still read it.
+pub fn buffer_error(status: u16, response: ureq::Response) -> Result<(String, AppError)> {
+    let recorded = read_response(status, response)?;
+    let err = match into_result(&recorded) {
+        Ok(response) => ureq::Error::Status(status, response).into(),
+        Err(err) => err,
+    };
+    Ok((recorded.body, err))
+}
+
+fn read_response(status: u16, response: ureq::Response) -> Result<RecordedResponse> {
+    let headers = KEPT_HEADERS
+        .iter()
+        .filter_map(|&name| Some((name.to_string(), response.header(name)?.to_string())))
+        .collect();
+    Ok(RecordedResponse {
+        status,
+        status_text: response.status_text().to_string(),
+        headers,
+        body: response.into_string()?,
+    })
+}
+
+/// Rebuilds the response, with error statuses as `ureq::Error::Status` as
+/// ureq itself reports them.
+fn into_result(recorded: &RecordedResponse) -> Result<ureq::Response> {
+    let mut raw = format!("HTTP/1.1 {} {}\r\n", recorded.status, recorded.status_text);
+    for (name, value) in &recorded.headers {
+        raw.push_str(&format!("{name}: {value}\r\n"));
+    }
+    raw.push_str("\r\n");
+    raw.push_str(&recorded.body);
+    let response: ureq::Response = raw.parse()?;
+    if recorded.status >= 400 {
+        return Err(ureq::Error::Status(recorded.status, response).into());
+    }
+    Ok(response)
+}
+
+fn url_path(url: &str) -> &str {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let path = rest.find('/').map_or("/", |i| &rest[i..]);
+    path.split(['?', '#']).next().unwrap_or(path)
+}
+
+/// `/v1/chat/completions` and `/chat/completions` are the same endpoint
+/// behind different base URLs.
+fn same_endpoint(recorded: &str, wanted: &str) -> bool {
+    recorded.ends_with(wanted) || wanted.ends_with(recorded)
+}
+
+fn write_file(path: &Path, file: &CassetteFile) -> Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(file)?)?;
+    Ok(())
+}
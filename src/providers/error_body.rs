@@ -0,0 +1,154 @@
+use crate::error::{AppError, ProviderErrorKind};
+use crate::types::Provider;
+use serde_json::Value;
+
+/// Longest raw body quoted back when it isn't a JSON error we recognise.
+const MAX_RAW_BODY: usize = 300;
+
+/// Turns a failed request into `AppError::Provider` when the server answered,
+/// reading the error body it sent. Transport errors pass through untouched.
+pub fn provider_error(provider: Provider, err: Box<ureq::Error>) -> AppError {
+    match *err {
+        ureq::Error::Status(status, response) => {
+            let status_text = response.status_text().to_string();
+            let body = response.into_string().unwrap_or_default();
+            parse(provider, status, &status_text, &body)
+        }
+        transport => AppError::from(transport),
+    }
+}
+
+/// The shapes seen in practice:
+/// - OpenAI, OpenRouter, Vercel, llama.cpp: `{"error": {"message", "type", "code"}}`
+/// - Anthropic: `{"type": "error", "error": {"type", "message"}}`
+/// - Ollama: `{"error": "model \"x\" not found, try pulling it first"}`
+fn parse(provider: Provider, status: u16, status_text: &str, body: &str) -> AppError {
+    let (message, tags) = message_and_tags(body);
+    let message = message
+        .filter(|m| !m.trim().is_empty())
+        .unwrap_or_else(|| raw_message(status_text, body));
+    AppError::Provider {
+        provider,
+        status,
+        kind: classify(status, &tags, &message),
+        message,
+    }
+}
+
+/// The error's message, if any, and its `code` and `type` joined by a space.
+fn message_and_tags(body: &str) -> (Option<String>, String) {
+    let value: Value = serde_json::from_str(body).unwrap_or(Value::Null);
+    let error = value.get("error").unwrap_or(&value);
+    match error {
+        Value::String(message) => (Some(message.clone()), String::new()),
+        Value::Object(fields) => {
+            let text = |key: &str| match fields.get(key) {
+                Some(Value::String(s)) => s.clone(),
+                Some(Value::Number(n)) => n.to_string(),
+                _ => String::new(),
+            };
+            let message = fields.get("message").and_then(Value::as_str).map(str::to_string);
+            (message, format!("{} {}", text("code"), text("type")))
+        }
+        _ => (None, String::new()),
+    }
+}
+
+/// Whether a 429 body says the balance or quota is used up rather than the
+/// rate exceeded, so waiting won't help. Narrower than `classify`: Gemini's
+/// per-minute "quota" 429s do clear on retry.
+pub fn is_quota_exhausted(body: &str) -> bool {
+    let (message, tags) = message_and_tags(body);
+    let message = message.unwrap_or_default().to_ascii_lowercase();
+    tags.contains("insufficient_quota")
+        || ["current quota", "billing", "credit", "balance"].iter().any(|n| message.contains(n))
+}
+
+fn classify(status: u16, tags: &str, message: &str) -> ProviderErrorKind {
+    let message = message.to_ascii_lowercase();
+    let mentions = |needles: &[&str]| needles.iter().any(|n| message.contains(n));
+    if status == 401 || tags.contains("invalid_api_key") || tags.contains("authentication_error") {
+        return ProviderErrorKind::InvalidKey;
+    }
+    // OpenAI reports an empty balance as a 429 with this code, so it must be
+    // checked before the plain rate limit.
+    if status == 402 || tags.contains("insufficient_quota") || mentions(&["quota", "credits"]) {
+        return ProviderErrorKind::QuotaExceeded;
+    }
+    if tags.contains("context_length_exceeded")
+        || mentions(&["context length", "context window", "maximum context", "prompt is too long"])
+    {
+        return ProviderErrorKind::ContextTooLong;
+    }
+    if tags.contains("model_not_found")
+        || (message.contains("model")
+            && mentions(&["not found", "does not exist", "not a valid model", "unknown model"]))
+    {
+        return ProviderErrorKind::UnknownModel;
+    }
+    if status == 403 || tags.contains("permission_error") {
+        return ProviderErrorKind::NoAccess;
+    }
+    if status == 429 || tags.contains("rate_limit") {
+        return ProviderErrorKind::RateLimited;
+    }
+    if matches!(status, 503 | 529) || tags.contains("overloaded") {
+        return ProviderErrorKind::Overloaded;
+    }
+    ProviderErrorKind::Other
+}
+
+fn raw_message(status_text: &str, body: &str) -> String {
+    let body = body.trim();
+    if body.is_empty() || body.starts_with('<') {
+        // Nothing, or an HTML error page from a proxy.
+        return status_text.to_string();
+    }
+    match body.char_indices().nth(MAX_RAW_BODY) {
+        Some((cut, _)) => format!("{}...", &body[..cut]),
+        None => body.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kind_of(err: AppError) -> (ProviderErrorKind, String) {
+        match err {
+            AppError::Provider { kind, message, .. } => (kind, message),
+            other => panic!("expected a provider error, got {other}"),
+        }
+    }
+
+    #[test]
+    fn parses_each_provider_error_shape() {
+        let openai = r#"{"error":{"message":"Incorrect API key provided: sk-x.","type":"invalid_request_error","code":"invalid_api_key"}}"#;
+        assert_eq!(
+            kind_of(parse(Provider::Openai, 401, "Unauthorized", openai)),
+            (ProviderErrorKind::InvalidKey, "Incorrect API key provided: sk-x.".to_string())
+        );
+
+        let quota = r#"{"error":{"message":"You exceeded your current quota.","type":"insufficient_quota","code":"insufficient_quota"}}"#;
+        let (kind, _) = kind_of(parse(Provider::Openai, 429, "Too Many Requests", quota));
+        assert_eq!(kind, ProviderErrorKind::QuotaExceeded);
+        assert!(is_quota_exhausted(quota));
+        let per_minute = r#"{"error":{"code":429,"message":"Quota exceeded for quota metric 'Generate Content API requests per minute'","status":"RESOURCE_EXHAUSTED"}}"#;
+        assert!(!is_quota_exhausted(per_minute));
+
+        let anthropic = r#"{"type":"error","error":{"type":"invalid_request_error","message":"prompt is too long: 210000 tokens > 200000 maximum"}}"#;
+        let (kind, _) = kind_of(parse(Provider::Anthropic, 400, "Bad Request", anthropic));
+        assert_eq!(kind, ProviderErrorKind::ContextTooLong);
+
+        let ollama = r#"{"error":"model \"llama9\" not found, try pulling it first"}"#;
+        let (kind, _) = kind_of(parse(Provider::Ollama, 404, "Not Found", ollama));
+        assert_eq!(kind, ProviderErrorKind::UnknownModel);
+
+        let openrouter = r#"{"error":{"code":403,"message":"Key not allowed for this model"}}"#;
+        let (kind, _) = kind_of(parse(Provider::Openrouter, 403, "Forbidden", openrouter));
+        assert_eq!(kind, ProviderErrorKind::NoAccess);
+
+        let (kind, message) = kind_of(parse(Provider::Openai, 502, "Bad Gateway", "<html>"));
+        assert_eq!((kind, message.as_str()), (ProviderErrorKind::Other, "Bad Gateway"));
+    }
+}
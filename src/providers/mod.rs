@@ -1,99 +1,759 @@
+pub mod cassette;
+mod error_body;
+mod race;
+pub mod retry;
+mod stream;
+
+use crate::context::EnvContext;
 use crate::error::{AppError, Result};
-use crate::types::{AppConfig, GenerationOutput, Provider};
+use crate::history;
+use crate::net;
+use crate::safety;
+use crate::shell_integration::ShellKind;
+use crate::types::{
+    AnsweredBy, AppConfig, ChatMessage, ChatRole, CommandExplanation, GenerationOutput, Provider,
+    Via,
+};
+use crate::usage::{self, CallOutcome, CallRecord, TokenUsage};
+use error_body::provider_error;
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
-use serde_json::json;
+use serde_json::{json, Value};
+use std::thread;
+use std::time::{Duration, Instant};
+use stream::{CommandExtractor, SseReader};
+
+/// Sampling temperature for `--candidates`; at 0 every candidate would be identical.
+const CANDIDATE_TEMPERATURE: f32 = 0.8;
+
+/// Added to the system prompt for `--candidates`, since temperature alone
+/// mostly reshuffles flags on the same command.
+const CANDIDATES_NOTE: &str = "Several alternatives are being collected for this request. Where more than one sensible approach exists, use a different tool or technique rather than a variation in flags of the obvious command.";
+
+/// A structured-output request: the reply must be a JSON object matching
+/// `schema`, which providers enforce natively where they can.
+pub struct Completion<'a> {
+    pub system: &'a str,
+    pub messages: &'a [ChatMessage],
+    /// Identifier for the schema; doubles as the forced Anthropic tool name.
+    pub schema_name: &'a str,
+    pub schema: &'a Value,
+    pub temperature: f32,
+}
+
+impl Completion<'_> {
+    fn with_messages<'b>(&'b self, messages: &'b [ChatMessage]) -> Completion<'b> {
+        Completion {
+            system: self.system,
+            messages,
+            schema_name: self.schema_name,
+            schema: self.schema,
+            temperature: self.temperature,
+        }
+    }
+
+    fn with_system<'b>(&'b self, system: &'b str) -> Completion<'b> {
+        Completion {
+            system,
+            messages: self.messages,
+            schema_name: self.schema_name,
+            schema: self.schema,
+            temperature: self.temperature,
+        }
+    }
+}
+
+pub trait ProviderClient: Sync {
+    /// Sends one completion and returns the raw JSON text of the reply.
+    fn complete(&self, agent: &ureq::Agent, config: &AppConfig, request: &Completion) -> Result<String>;
+
+    /// Like `complete`, but calls `on_text` with each raw chunk as it streams
+    /// in. Clients without streaming support answer in one shot and never
+    /// call `on_text`.
+    fn complete_stream(
+        &self,
+        agent: &ureq::Agent,
+        config: &AppConfig,
+        request: &Completion,
+        on_text: &mut dyn FnMut(&str),
+    ) -> Result<String> {
+        let _ = on_text;
+        self.complete(agent, config, request)
+    }
+
+    /// Returns up to `n` independent replies to the same request. The default
+    /// makes `n` parallel calls and keeps whichever succeed.
+    fn complete_many(
+        &self,
+        agent: &ureq::Agent,
+        config: &AppConfig,
+        request: &Completion,
+        n: usize,
+    ) -> Result<Vec<String>> {
+        // Separate calls can't see each other's answers, so each is asked for
+        // a different place in the ranking of approaches.
+        let systems: Vec<String> =
+            (0..n).map(|i| alternative_system(request.system, i, n)).collect();
+        let results: Vec<Result<String>> = thread::scope(|scope| {
+            let workers: Vec<_> = systems
+                .iter()
+                .map(|system| {
+                    let request = request.with_system(system);
+                    scope.spawn(move || usage::metered(|| self.complete(agent, config, &request)))
+                })
+                .collect();
+            workers
+                .into_iter()
+                .map(|w| match w.join() {
+                    Ok((result, tokens)) => {
+                        // Usage is metered per thread; carry it back to this one.
+                        if let Some(tokens) = tokens {
+                            usage::report(tokens);
+                        }
+                        result
+                    }
+                    Err(_) => Err(AppError::from("provider call panicked")),
+                })
+                .collect()
+        });
+        first_successes(results)
+    }
 
-pub trait ProviderClient {
-    fn generate(&self, agent: &ureq::Agent, config: &AppConfig, prompt: &str) -> Result<GenerationOutput>;
+    /// Generates a command from the conversation so far; `messages` starts
+    /// with `initial_messages` and grows with each `push_follow_up`.
+    fn generate(
+        &self,
+        agent: &ureq::Agent,
+        config: &AppConfig,
+        messages: &[ChatMessage],
+    ) -> Result<GenerationOutput> {
+        let schema = output_schema(config.shell);
+        let system = system_prompt(config.shell);
+        let request = generation_request(&system, messages, &schema);
+        let content = self.complete(agent, config, &request)?;
+        let parse = |c: &str| parse_model_output(c, config.shell);
+        finish_with_repair(&request, content, parse, |r| {
+            self.complete(agent, config, r)
+        })
+    }
+
+    /// Asks for `n` alternative commands and drops duplicates. Candidates that
+    /// fail to parse are skipped as long as at least one succeeds.
+    fn generate_candidates(
+        &self,
+        agent: &ureq::Agent,
+        config: &AppConfig,
+        messages: &[ChatMessage],
+        n: usize,
+    ) -> Result<Vec<GenerationOutput>> {
+        let schema = output_schema(config.shell);
+        let system = format!("{} {CANDIDATES_NOTE}", system_prompt(config.shell));
+        let mut request = generation_request(&system, messages, &schema);
+        request.temperature = CANDIDATE_TEMPERATURE;
+        let parse = |c: &str| parse_model_output(c, config.shell);
+        let parsed = self
+            .complete_many(agent, config, &request, n)?
+            .into_iter()
+            .map(|content| {
+                finish_with_repair(&request, content, parse, |r| {
+                    self.complete(agent, config, r)
+                })
+            })
+            .collect();
+        let mut candidates: Vec<GenerationOutput> = Vec::new();
+        for output in first_successes(parsed)? {
+            if !candidates.iter().any(|c| same_command(&c.command, &output.command)) {
+                candidates.push(output);
+            }
+        }
+        Ok(candidates)
+    }
+
+    /// Like `generate`, but calls `on_delta` with each newly streamed piece of
+    /// the command.
+    fn generate_stream(
+        &self,
+        agent: &ureq::Agent,
+        config: &AppConfig,
+        messages: &[ChatMessage],
+        on_delta: &mut dyn FnMut(&str),
+    ) -> Result<GenerationOutput> {
+        let schema = output_schema(config.shell);
+        let system = system_prompt(config.shell);
+        let request = generation_request(&system, messages, &schema);
+        let mut extractor = CommandExtractor::default();
+        let mut on_text = |text: &str| {
+            let delta = extractor.push(text);
+            if !delta.is_empty() {
+                on_delta(&delta);
+            }
+        };
+        let content = self.complete_stream(agent, config, &request, &mut on_text)?;
+        let parse = |c: &str| parse_model_output(c, config.shell);
+        finish_with_repair(&request, content, parse, |r| {
+            self.complete(agent, config, r)
+        })
+    }
 }
 
 struct OpenAIClient;
 struct OpenRouterClient;
 struct VercelClient;
 struct AnthropicClient;
+struct OllamaClient;
+struct LlamaCppClient;
 
-pub fn generate_once(agent: &ureq::Agent, config: &AppConfig, prompt: &str) -> Result<GenerationOutput> {
-    let client: Box<dyn ProviderClient> = match config.provider {
+fn client_for(provider: Provider) -> Box<dyn ProviderClient> {
+    match provider {
         Provider::Openai => Box::new(OpenAIClient),
         Provider::Openrouter => Box::new(OpenRouterClient),
         Provider::Vercel => Box::new(VercelClient),
         Provider::Anthropic => Box::new(AnthropicClient),
+        Provider::Ollama => Box::new(OllamaClient),
+        Provider::Llamacpp => Box::new(LlamaCppClient),
+    }
+}
+
+pub fn generate_once(
+    agent: &ureq::Agent,
+    config: &AppConfig,
+    messages: &[ChatMessage],
+) -> Result<GenerationOutput> {
+    if config.race {
+        return race::race(agent, config, messages);
+    }
+    let (mut output, answered_by, tokens) = with_fallback(agent, config, |agent, cfg| {
+        client_for(cfg.provider).generate(agent, cfg, messages)
+    })?;
+    output.answered_by = answered_by;
+    output.usage = tokens;
+    Ok(output)
+}
+
+pub fn generate_candidates(
+    agent: &ureq::Agent,
+    config: &AppConfig,
+    messages: &[ChatMessage],
+    n: usize,
+) -> Result<Vec<GenerationOutput>> {
+    let (mut outputs, answered_by, tokens) = with_fallback(agent, config, |agent, cfg| {
+        client_for(cfg.provider).generate_candidates(agent, cfg, messages, n)
+    })?;
+    for output in &mut outputs {
+        output.answered_by = answered_by.clone();
+    }
+    // One bill for the whole batch, so it is reported once.
+    if let Some(first) = outputs.first_mut() {
+        first.usage = tokens;
+    }
+    Ok(outputs)
+}
+
+/// What `generate_stream` reports while the command arrives.
+pub enum StreamEvent<'a> {
+    /// The next piece of the command.
+    Delta(&'a str),
+    /// A provider failed partway through and a fallback starts over; the
+    /// text streamed so far is void.
+    Restart,
+}
+
+pub fn generate_stream(
+    agent: &ureq::Agent,
+    config: &AppConfig,
+    messages: &[ChatMessage],
+    on_event: &mut dyn FnMut(StreamEvent),
+) -> Result<GenerationOutput> {
+    // Deltas from several providers at once can't be shown on one line.
+    if config.race {
+        return race::race(agent, config, messages);
+    }
+    let mut streamed = false;
+    let (mut output, answered_by, tokens) = with_fallback(agent, config, |agent, cfg| {
+        if std::mem::take(&mut streamed) {
+            on_event(StreamEvent::Restart);
+        }
+        let mut on_delta = |text: &str| {
+            streamed = true;
+            on_event(StreamEvent::Delta(text));
+        };
+        client_for(cfg.provider).generate_stream(agent, cfg, messages, &mut on_delta)
+    })?;
+    output.answered_by = answered_by;
+    output.usage = tokens;
+    Ok(output)
+}
+
+/// Runs `generate` against the configured provider, then against each
+/// `[[fallback]]` entry in order for as long as the failures are temporary.
+/// When every provider fails, the last error is returned.
+fn with_fallback<T>(
+    agent: &ureq::Agent,
+    config: &AppConfig,
+    mut generate: impl FnMut(&ureq::Agent, &AppConfig) -> Result<T>,
+) -> Result<(T, Option<AnsweredBy>, Option<TokenUsage>)> {
+    let mut err = match tracked(config, || generate(agent, config)) {
+        (Ok(value), tokens) => return Ok((value, None, tokens)),
+        (Err(err), _) => err,
+    };
+    for target in &config.fallback {
+        if !err.is_transient() {
+            break;
+        }
+        let fallback = config.with_target(target);
+        let agent = net::build_agent(&fallback)?;
+        match tracked(&fallback, || generate(&agent, &fallback)) {
+            (Ok(value), tokens) => {
+                let answered_by = AnsweredBy {
+                    provider: target.provider,
+                    model: target.model.clone(),
+                    via: Via::Fallback,
+                };
+                return Ok((value, Some(answered_by), tokens));
+            }
+            (Err(next), _) => err = next,
+        }
+    }
+    Err(err)
+}
+
+/// One provider call, with its tokens metered and the call appended to
+/// `usage.jsonl`.
+fn tracked<T>(
+    config: &AppConfig,
+    call: impl FnOnce() -> Result<T>,
+) -> (Result<T>, Option<TokenUsage>) {
+    let started = Instant::now();
+    let (result, tokens) = usage::metered(call);
+    let record = CallRecord {
+        timestamp: history::now(),
+        provider: config.provider.as_str().to_string(),
+        model: config.model.clone(),
+        latency_ms: millis(started.elapsed()),
+        outcome: if result.is_ok() {
+            CallOutcome::Answered
+        } else {
+            CallOutcome::Failed
+        },
+        raced: false,
+        tokens,
+    };
+    usage::remember(config.usage_path.as_deref(), &[record]);
+    (result, tokens)
+}
+
+fn millis(elapsed: Duration) -> u64 {
+    u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX)
+}
+
+/// Reverse mode: asks the model to annotate each part of an existing command.
+pub fn explain_command(agent: &ureq::Agent, config: &AppConfig, command: &str) -> Result<CommandExplanation> {
+    tracked(config, || explain(agent, config, command)).0
+}
+
+fn explain(agent: &ureq::Agent, config: &AppConfig, command: &str) -> Result<CommandExplanation> {
+    let client = client_for(config.provider);
+    let messages = vec![ChatMessage::user(format!("Command: {command}"))];
+    let schema = explanation_schema();
+    let request = Completion {
+        system: explain_system_prompt(),
+        messages: &messages,
+        schema_name: "explain_command",
+        schema: &schema,
+        temperature: 0.0,
     };
-    client.generate(agent, config, prompt)
+    let content = client.complete(agent, config, &request)?;
+    let mut explanation = finish_with_repair(&request, content, parse_explanation, |r| {
+        client.complete(agent, config, r)
+    })?;
+    explanation.command = command.to_string();
+    let analysis = safety::reconcile(&explanation.safety, command, config.shell);
+    explanation.safety = analysis.level.as_str().to_string();
+    explanation.safety_reasons = analysis.reasons;
+    Ok(explanation)
 }
 
 impl ProviderClient for OpenAIClient {
-    fn generate(&self, agent: &ureq::Agent, config: &AppConfig, prompt: &str) -> Result<GenerationOutput> {
-        openai_like(agent, config, prompt, OpenAILikeMode::OpenAI)
+    fn complete(&self, agent: &ureq::Agent, config: &AppConfig, request: &Completion) -> Result<String> {
+        openai_like_text(agent, config, request, OpenAILikeMode::OpenAI)
+    }
+
+    fn complete_stream(
+        &self,
+        agent: &ureq::Agent,
+        config: &AppConfig,
+        request: &Completion,
+        on_text: &mut dyn FnMut(&str),
+    ) -> Result<String> {
+        openai_like_stream_text(agent, config, request, OpenAILikeMode::OpenAI, on_text)
+    }
+
+    fn complete_many(
+        &self,
+        agent: &ureq::Agent,
+        config: &AppConfig,
+        request: &Completion,
+        n: usize,
+    ) -> Result<Vec<String>> {
+        openai_like_choices(agent, config, request, OpenAILikeMode::OpenAI, n)
     }
 }
 
 impl ProviderClient for OpenRouterClient {
-    fn generate(&self, agent: &ureq::Agent, config: &AppConfig, prompt: &str) -> Result<GenerationOutput> {
-        openai_like(agent, config, prompt, OpenAILikeMode::OpenRouter)
+    fn complete(&self, agent: &ureq::Agent, config: &AppConfig, request: &Completion) -> Result<String> {
+        openai_like_text(agent, config, request, OpenAILikeMode::OpenRouter)
+    }
+
+    fn complete_stream(
+        &self,
+        agent: &ureq::Agent,
+        config: &AppConfig,
+        request: &Completion,
+        on_text: &mut dyn FnMut(&str),
+    ) -> Result<String> {
+        openai_like_stream_text(agent, config, request, OpenAILikeMode::OpenRouter, on_text)
+    }
+
+    fn complete_many(
+        &self,
+        agent: &ureq::Agent,
+        config: &AppConfig,
+        request: &Completion,
+        n: usize,
+    ) -> Result<Vec<String>> {
+        openai_like_choices(agent, config, request, OpenAILikeMode::OpenRouter, n)
     }
 }
 
 impl ProviderClient for VercelClient {
-    fn generate(&self, agent: &ureq::Agent, config: &AppConfig, prompt: &str) -> Result<GenerationOutput> {
-        openai_like(agent, config, prompt, OpenAILikeMode::Vercel)
+    fn complete(&self, agent: &ureq::Agent, config: &AppConfig, request: &Completion) -> Result<String> {
+        openai_like_text(agent, config, request, OpenAILikeMode::Vercel)
+    }
+
+    fn complete_stream(
+        &self,
+        agent: &ureq::Agent,
+        config: &AppConfig,
+        request: &Completion,
+        on_text: &mut dyn FnMut(&str),
+    ) -> Result<String> {
+        openai_like_stream_text(agent, config, request, OpenAILikeMode::Vercel, on_text)
+    }
+
+    fn complete_many(
+        &self,
+        agent: &ureq::Agent,
+        config: &AppConfig,
+        request: &Completion,
+        n: usize,
+    ) -> Result<Vec<String>> {
+        openai_like_choices(agent, config, request, OpenAILikeMode::Vercel, n)
     }
 }
 
 impl ProviderClient for AnthropicClient {
-    fn generate(&self, agent: &ureq::Agent, config: &AppConfig, prompt: &str) -> Result<GenerationOutput> {
-        let url = format!("{}/v1/messages", config.base_url.trim_end_matches('/'));
+    fn complete(&self, agent: &ureq::Agent, config: &AppConfig, request: &Completion) -> Result<String> {
+        let body = anthropic_body(config, request, false);
+        let response: AnthropicResponse =
+            fetch(config, anthropic_request(agent, config), &body)?;
+        anthropic_content(response)
+    }
+
+    fn complete_stream(
+        &self,
+        agent: &ureq::Agent,
+        config: &AppConfig,
+        request: &Completion,
+        on_text: &mut dyn FnMut(&str),
+    ) -> Result<String> {
+        let deadline = net::Deadline::start(&config.network);
+        let body = anthropic_body(config, request, true);
+        let req = anthropic_request(agent, config);
+        let response = match send(config, &deadline, || req.clone(), &body) {
+            Ok(r) => r,
+            Err(AppError::Provider { status, message, .. })
+                if stream_rejected(status, &message) =>
+            {
+                return self.complete(agent, config, request);
+            }
+            Err(e) => return Err(e),
+        };
+        if !is_event_stream(&response) {
+            let parsed: AnthropicResponse = response.into_json()?;
+            return anthropic_content(parsed);
+        }
+
+        let mut content = String::new();
+        // Input tokens arrive with `message_start`, the running output count
+        // with each `message_delta`.
+        let mut tokens = None;
+        for event in SseReader::new(response.into_reader()) {
+            deadline.check()?;
+            let event = event?;
+            match event.event.as_deref() {
+                Some("message_stop") => break,
+                Some("message_start") => {
+                    let start: AnthropicStreamEvent = serde_json::from_str(&event.data)?;
+                    tokens = start.message.and_then(|m| m.usage).map(|u| u.tokens());
+                }
+                Some("message_delta") => {
+                    let delta: AnthropicStreamEvent = serde_json::from_str(&event.data)?;
+                    if let (Some(total), Some(used)) = (tokens.as_mut(), delta.usage) {
+                        total.output_tokens = used.output_tokens;
+                    }
+                }
+                Some("error") => {
+                    let value: Value = serde_json::from_str(&event.data)?;
+                    let message = value["error"]["message"].as_str().unwrap_or("stream error");
+                    return Err(AppError::from(format!("anthropic stream failed: {message}")));
+                }
+                Some("content_block_delta") => {
+                    let chunk: AnthropicStreamEvent = serde_json::from_str(&event.data)?;
+                    // Forced tool calls stream their input as `partial_json`.
+                    if let Some(text) = chunk.delta.and_then(|d| d.partial_json.or(d.text)) {
+                        content.push_str(&text);
+                        on_text(&text);
+                    }
+                }
+                _ => {}
+            }
+        }
+        if let Some(tokens) = tokens {
+            usage::report(tokens);
+        }
+        Ok(content)
+    }
+}
+
+fn anthropic_request(agent: &ureq::Agent, config: &AppConfig) -> ureq::Request {
+    let url = format!("{}/v1/messages", config.base_url.trim_end_matches('/'));
+    agent
+        .post(&url)
+        .set("Content-Type", "application/json")
+        .set("x-api-key", &config.api_key)
+        .set("anthropic-version", "2023-06-01")
+}
+
+fn anthropic_body(config: &AppConfig, request: &Completion, stream: bool) -> Value {
+    json!({
+        "model": config.model,
+        "max_tokens": 1024,
+        "temperature": request.temperature,
+        "stream": stream,
+        "system": request.system,
+        "messages": request.messages,
+        "tools": [{
+            "name": request.schema_name,
+            "description": "Return the structured result.",
+            "input_schema": request.schema
+        }],
+        "tool_choice": {"type": "tool", "name": request.schema_name}
+    })
+}
+
+fn anthropic_content(response: AnthropicResponse) -> Result<String> {
+    if let Some(used) = &response.usage {
+        usage::report(used.tokens());
+    }
+    let mut text = None;
+    for block in response.content {
+        match block.type_name.as_str() {
+            "tool_use" => {
+                if let Some(input) = block.input {
+                    return Ok(input.to_string());
+                }
+            }
+            "text" if text.is_none() => text = block.text,
+            _ => {}
+        }
+    }
+    text.ok_or_else(|| AppError::from("no text content returned"))
+}
+
+impl ProviderClient for OllamaClient {
+    fn complete(&self, agent: &ureq::Agent, config: &AppConfig, request: &Completion) -> Result<String> {
+        let url = format!("{}/api/chat", config.base_url.trim_end_matches('/'));
+        let mut turns = vec![json!({"role": "system", "content": request.system})];
+        turns.extend(request.messages.iter().map(|m| json!(m)));
         let body = json!({
             "model": config.model,
-            "max_tokens": 300,
-            "temperature": 0,
-            "system": system_prompt(),
-            "messages": [
-                {"role": "user", "content": format!("User request: {}", prompt)}
-            ]
+            "stream": false,
+            "format": request.schema,
+            "options": {"temperature": request.temperature},
+            "messages": turns
         });
 
-        let response: AnthropicResponse = agent
-            .post(&url)
-            .set("Content-Type", "application/json")
-            .set("x-api-key", &config.api_key)
-            .set("anthropic-version", "2023-06-01")
-            .send_json(body)?
-            .into_json()?;
+        let mut req = agent.post(&url).set("Content-Type", "application/json");
+        if !config.api_key.trim().is_empty() {
+            req = req.set("Authorization", &format!("Bearer {}", config.api_key));
+        }
 
-        let content = response
-            .content
-            .into_iter()
-            .find(|c| c.type_name == "text")
-            .and_then(|c| c.text)
-            .ok_or_else(|| AppError::from("no text content returned"))?;
+        let response: OllamaResponse = fetch(config, req, &body)?;
+        usage::report(TokenUsage {
+            input_tokens: response.prompt_eval_count,
+            output_tokens: response.eval_count,
+        });
+        Ok(response.message.content)
+    }
+}
+
+impl ProviderClient for LlamaCppClient {
+    fn complete(&self, agent: &ureq::Agent, config: &AppConfig, request: &Completion) -> Result<String> {
+        // The raw `/completion` endpoint applies no chat template, so the
+        // system prompt and conversation are flattened into a single text prompt.
+        let url = format!("{}/completion", config.base_url.trim_end_matches('/'));
+        let mut transcript = format!("{}\n\n", request.system);
+        for message in request.messages {
+            let speaker = match message.role {
+                ChatRole::User => "User",
+                ChatRole::Assistant => "Assistant",
+            };
+            transcript.push_str(&format!("{speaker}: {}\n", message.content));
+        }
+        transcript.push_str("Assistant:");
+        let body = json!({
+            "prompt": transcript,
+            "n_predict": 1024,
+            "temperature": request.temperature,
+            "json_schema": request.schema
+        });
 
-        parse_model_output(&content)
+        let mut req = agent.post(&url).set("Content-Type", "application/json");
+        if !config.api_key.trim().is_empty() {
+            req = req.set("Authorization", &format!("Bearer {}", config.api_key));
+        }
+
+        let response: LlamaCppResponse = fetch(config, req, &body)?;
+        usage::report(TokenUsage {
+            input_tokens: response.tokens_evaluated,
+            output_tokens: response.tokens_predicted,
+        });
+        Ok(response.content)
     }
 }
 
+#[derive(Clone, Copy)]
 enum OpenAILikeMode {
     OpenAI,
     OpenRouter,
     Vercel,
 }
 
-fn openai_like(
+fn openai_like_text(
     agent: &ureq::Agent,
     config: &AppConfig,
-    prompt: &str,
+    request: &Completion,
     mode: OpenAILikeMode,
-) -> Result<GenerationOutput> {
-    let url = format!("{}/chat/completions", config.base_url.trim_end_matches('/'));
-    let body = json!({
+) -> Result<String> {
+    let body = openai_like_body(config, request, false);
+    let response: OpenAIResponse =
+        fetch(config, openai_like_request(agent, config, mode), &body)?;
+    openai_content(response)
+}
+
+/// One request with `n` set, so the API samples every candidate at once.
+fn openai_like_choices(
+    agent: &ureq::Agent,
+    config: &AppConfig,
+    request: &Completion,
+    mode: OpenAILikeMode,
+    n: usize,
+) -> Result<Vec<String>> {
+    let mut body = openai_like_body(config, request, false);
+    body["n"] = json!(n);
+    let response: OpenAIResponse =
+        fetch(config, openai_like_request(agent, config, mode), &body)?;
+    if let Some(used) = &response.usage {
+        usage::report(used.tokens());
+    }
+    if response.choices.is_empty() {
+        return Err(AppError::from("no choices returned"));
+    }
+    Ok(response.choices.into_iter().map(|c| c.message.content).collect())
+}
+
+fn openai_like_stream_text(
+    agent: &ureq::Agent,
+    config: &AppConfig,
+    request: &Completion,
+    mode: OpenAILikeMode,
+    on_text: &mut dyn FnMut(&str),
+) -> Result<String> {
+    let deadline = net::Deadline::start(&config.network);
+    let body = openai_like_body(config, request, true);
+    let req = openai_like_request(agent, config, mode);
+    let response = match send(config, &deadline, || req.clone(), &body) {
+        Ok(r) => r,
+        Err(AppError::Provider { status, message, .. }) if stream_rejected(status, &message) => {
+            return openai_like_text(agent, config, request, mode);
+        }
+        Err(e) => return Err(e),
+    };
+    // Some compatible gateways ignore `stream` and answer with a plain body.
+    if !is_event_stream(&response) {
+        let parsed: OpenAIResponse = response.into_json()?;
+        return openai_content(parsed);
+    }
+
+    let mut content = String::new();
+    for event in SseReader::new(response.into_reader()) {
+        deadline.check()?;
+        let event = event?;
+        if event.data.trim() == "[DONE]" {
+            break;
+        }
+        let chunk: OpenAIStreamChunk = serde_json::from_str(&event.data)?;
+        // With `include_usage`, the last chunk carries usage and no choices.
+        if let Some(used) = &chunk.usage {
+            usage::report(used.tokens());
+        }
+        if let Some(text) = chunk.choices.into_iter().next().and_then(|c| c.delta.content) {
+            content.push_str(&text);
+            on_text(&text);
+        }
+    }
+    Ok(content)
+}
+
+fn openai_like_body(config: &AppConfig, request: &Completion, stream: bool) -> Value {
+    let mut turns = vec![json!({"role": "system", "content": request.system})];
+    turns.extend(request.messages.iter().map(|m| json!(m)));
+    let mut body = json!({
         "model": config.model,
-        "temperature": 0,
-        "messages": [
-            {"role": "system", "content": system_prompt()},
-            {"role": "user", "content": format!("User request: {}", prompt)}
-        ]
+        "temperature": request.temperature,
+        "stream": stream,
+        "messages": turns,
+        "response_format": {
+            "type": "json_schema",
+            "json_schema": {
+                "name": request.schema_name,
+                "strict": true,
+                "schema": request.schema
+            }
+        }
     });
+    if stream {
+        // Streams omit usage unless asked; it arrives as a final chunk.
+        body["stream_options"] = json!({"include_usage": true});
+    }
+    body
+}
 
+fn openai_content(response: OpenAIResponse) -> Result<String> {
+    if let Some(used) = &response.usage {
+        usage::report(used.tokens());
+    }
+    Ok(response
+        .choices
+        .into_iter()
+        .next()
+        .ok_or_else(|| AppError::from("no choices returned"))?
+        .message
+        .content)
+}
+
+fn openai_like_request(agent: &ureq::Agent, config: &AppConfig, mode: OpenAILikeMode) -> ureq::Request {
+    let url = format!("{}/chat/completions", config.base_url.trim_end_matches('/'));
     let mut req = agent
         .post(&url)
         .set("Content-Type", "application/json")
@@ -110,67 +770,295 @@ fn openai_like(
             req = req.set("X-Vercel-AI-Gateway-Api-Key", &config.api_key);
         }
     }
+    req
+}
 
-    let response: OpenAIResponse = req.send_json(body)?.into_json()?;
-    let content = response
-        .choices
-        .into_iter()
-        .next()
-        .ok_or_else(|| AppError::from("no choices returned"))?
-        .message
-        .content;
+/// Sends with retries until `deadline`, building each attempt's request with
+/// `request`. Once the server has answered with an error status, the failure
+/// becomes `AppError::Provider` carrying its error message.
+fn send(
+    config: &AppConfig,
+    deadline: &net::Deadline,
+    request: impl Fn() -> ureq::Request,
+    body: &Value,
+) -> Result<ureq::Response> {
+    let cassette = config.cassette.as_deref();
+    retry::send_json(&config.retry, deadline, || {
+        cassette::send_json(cassette, &request(), body)
+    })
+    .map_err(|err| match err {
+        AppError::Request(err) => provider_error(config.provider, err),
+        other => other,
+    })
+}
+
+/// A call whose reply is read in one go: every attempt carries the time left
+/// as ureq's overall timeout, so a trickling server can't hang the spinner.
+fn fetch<T: DeserializeOwned>(
+    config: &AppConfig,
+    request: ureq::Request,
+    body: &Value,
+) -> Result<T> {
+    let deadline = net::Deadline::start(&config.network);
+    let response = send(config, &deadline, || deadline.bound(request.clone()), body)?;
+    // A body cut off by the timeout reads as an I/O error; report the cause.
+    response.into_json().map_err(|err| deadline.check().err().unwrap_or(err.into()))
+}
+
+/// Whether the server refused to stream, so the same request is worth
+/// repeating without `stream`. 415/501 say so outright; a 400/422 only counts
+/// when its error names the stream fields, since it's usually a bad model,
+/// prompt or parameter that would fail again.
+fn stream_rejected(code: u16, message: &str) -> bool {
+    match code {
+        415 | 501 => true,
+        400 | 422 => message.to_ascii_lowercase().contains("stream"),
+        _ => false,
+    }
+}
 
-    parse_model_output(&content)
+fn is_event_stream(response: &ureq::Response) -> bool {
+    response.content_type().eq_ignore_ascii_case("text/event-stream")
+}
+
+/// The parts of the system prompt that differ per shell: the language's
+/// name and what to avoid from the bash models default to.
+fn dialect(shell: ShellKind) -> (&'static str, &'static str) {
+    match shell {
+        ShellKind::Nu => (
+            "Nushell (nu)",
+            "command must be valid Nushell, not bash (no backticks, no markdown, no leading $): prefer nu built-ins and structured pipelines (e.g. ls | where size > 10mb | sort-by modified), use $env.NAME for environment variables, ; instead of && to chain, and ^name to force an external program. Never use bash-only syntax such as $(...), export, [[ ]] or 2>&1.",
+        ),
+        ShellKind::Pwsh => (
+            "PowerShell 7 (pwsh)",
+            "command must be valid PowerShell, not bash (no backticks for line continuation, no markdown, no leading PS>): prefer cmdlets with full parameter names (e.g. Get-ChildItem -Recurse -File | Where-Object Length -gt 10MB), use $env:NAME for environment variables; native tools such as git or grep are also available. Never use bash-only syntax such as export NAME=value, [[ ]], ${NAME:-default} or `...` substitution.",
+        ),
+        ShellKind::Fish => (
+            "fish",
+            "command must be valid fish, not bash (no markdown, no leading $): use (cmd) for command substitution, set -gx NAME value instead of export, $status instead of $?, and close for/if/while/function blocks with end. Never use bash-only syntax such as [[ ]], ${NAME:-default}, <<EOF heredocs, do/done or fi.",
+        ),
+        ShellKind::Bash | ShellKind::Zsh => (
+            "bash",
+            "command must be plain bash (no backticks, no markdown, no leading $).",
+        ),
+    }
 }
 
-fn system_prompt() -> &'static str {
-    "You convert natural language intent into exactly one bash command. Return JSON only with keys: command, explanation, safety. safety must be one of safe|caution|risky. command must be plain bash (no backticks, no markdown, no leading $). Keep commands concise and practical for macOS/Linux."
+fn system_prompt(shell: ShellKind) -> String {
+    let (name, syntax) = dialect(shell);
+    format!(
+        "You convert natural language intent into exactly one {name} command. Return JSON only with keys: command, explanation, safety. safety must be one of safe|caution|risky. {syntax} Keep commands concise and practical for macOS/Linux. When an Environment block is given, use flags and tools that exist there (e.g. GNU vs BSD sed). A Refinement turn changes your previous command; return the complete updated command."
+    )
 }
 
-fn parse_model_output(content: &str) -> Result<GenerationOutput> {
-    if let Ok(mut parsed) = serde_json::from_str::<GenerationOutput>(content) {
-        parsed.safety = normalize_safety(&parsed.safety, &parsed.command);
-        return Ok(parsed);
+/// `system` for the `index`th of `n` separate candidate calls.
+fn alternative_system(system: &str, index: usize, n: usize) -> String {
+    let k = index + 1;
+    format!(
+        "{system} This is alternative {k} of {n}: rank the sensible approaches from most to least common and answer with number {k}, or the last one if there are fewer."
+    )
+}
+
+pub fn initial_messages(context: Option<&EnvContext>, prompt: &str) -> Vec<ChatMessage> {
+    match context {
+        Some(ctx) => vec![ChatMessage::user(format!("{}\nUser request: {prompt}", ctx.render()))],
+        None => vec![ChatMessage::user(format!("User request: {prompt}"))],
     }
+}
 
-    let cleaned = content.trim().trim_matches('`').trim().to_string();
-    let command = cleaned
-        .lines()
-        .find(|line| !line.trim().is_empty())
-        .unwrap_or("")
-        .trim()
-        .to_string();
-    if command.is_empty() {
-        return Err(AppError::from("model returned empty output"));
+/// Records `previous` as the assistant's reply and asks for `refinement` on
+/// top of it. `previous` may have been edited by the user, so it is
+/// re-serialised rather than taken from the raw reply.
+pub fn push_follow_up(
+    messages: &mut Vec<ChatMessage>,
+    previous: &GenerationOutput,
+    refinement: &str,
+) {
+    let reply = json!({
+        "command": previous.command,
+        "explanation": previous.explanation,
+        "safety": previous.safety,
+    });
+    messages.push(ChatMessage::assistant(reply.to_string()));
+    messages.push(ChatMessage::user(format!("Refinement: {refinement}")));
+}
+
+/// JSON schema for `GenerationOutput`, shared by every structured-output API.
+/// Forced tools and strict schemas weigh the description, so it names the
+/// same dialect as the system prompt.
+fn output_schema(shell: ShellKind) -> Value {
+    let (name, _) = dialect(shell);
+    let description = format!("Exactly one {name} command, without markdown or a prompt marker.");
+    json!({
+        "type": "object",
+        "properties": {
+            "command": {
+                "type": "string",
+                "description": description
+            },
+            "explanation": {"type": "string"},
+            "safety": {"type": "string", "enum": ["safe", "caution", "risky"]}
+        },
+        "required": ["command", "explanation", "safety"],
+        "additionalProperties": false
+    })
+}
+
+fn generation_request<'a>(
+    system: &'a str,
+    messages: &'a [ChatMessage],
+    schema: &'a Value,
+) -> Completion<'a> {
+    Completion {
+        system,
+        messages,
+        schema_name: "emit_command",
+        schema,
+        temperature: 0.0,
     }
+}
+
+fn explain_system_prompt() -> &'static str {
+    "You explain existing shell commands. Split the command into its parts in order: the program, each subcommand, flag (with its value), argument, pipe, redirection, list operator and command substitution. Give each part a short plain-English explanation. Pipelines and lists contain several programs; annotate each. Also give a one-sentence summary and a safety level of safe|caution|risky."
+}
 
-    Ok(GenerationOutput {
-        command: command.clone(),
-        explanation: "Model did not provide structured explanation.".to_string(),
-        safety: normalize_safety("caution", &command),
+/// JSON schema for `CommandExplanation`.
+fn explanation_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "summary": {"type": "string"},
+            "parts": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "text": {"type": "string"},
+                        "kind": {
+                            "type": "string",
+                            "enum": [
+                                "program", "subcommand", "flag", "argument", "pipe",
+                                "redirection", "operator", "substitution", "other"
+                            ]
+                        },
+                        "explanation": {"type": "string"}
+                    },
+                    "required": ["text", "kind", "explanation"],
+                    "additionalProperties": false
+                }
+            },
+            "safety": {"type": "string", "enum": ["safe", "caution", "risky"]}
+        },
+        "required": ["summary", "parts", "safety"],
+        "additionalProperties": false
     })
 }
 
-fn normalize_safety(raw: &str, command: &str) -> String {
-    let norm = raw.trim().to_ascii_lowercase();
-    if norm == "safe" || norm == "caution" || norm == "risky" {
-        return norm;
+/// Parses `content`, and when `parse` rejects it asks the model once more
+/// with the validation error appended to the conversation.
+fn finish_with_repair<T>(
+    request: &Completion,
+    content: String,
+    parse: impl Fn(&str) -> Result<T>,
+    resend: impl FnOnce(&Completion) -> Result<String>,
+) -> Result<T> {
+    let err = match parse(&content) {
+        Ok(output) => return Ok(output),
+        Err(err) => err,
+    };
+    let mut retry = request.messages.to_vec();
+    retry.push(ChatMessage::assistant(content));
+    retry.push(ChatMessage::user(format!(
+        "That reply was invalid: {err}. Respond again with only a JSON object matching the schema."
+    )));
+    parse(&resend(&request.with_messages(&retry))?)
+}
+
+/// `shell` is the dialect the command was asked for, for the safety check.
+fn parse_model_output(content: &str, shell: ShellKind) -> Result<GenerationOutput> {
+    let object = extract_json_object(content)
+        .ok_or_else(|| AppError::from("model reply was not a JSON object"))?;
+    let mut parsed: GenerationOutput = serde_json::from_str(object)
+        .map_err(|e| AppError::from(format!("model reply did not match the output schema ({e})")))?;
+
+    let command = parsed.command.trim();
+    if command.is_empty() {
+        return Err(AppError::from("model returned an empty command"));
+    }
+    if command.starts_with("```") || command.starts_with("$ ") {
+        return Err(AppError::from("model wrapped the command in markdown or a prompt marker"));
+    }
+    parsed.command = command.to_string();
+    let analysis = safety::reconcile(&parsed.safety, &parsed.command, shell);
+    parsed.safety = analysis.level.as_str().to_string();
+    parsed.safety_reasons = analysis.reasons;
+    Ok(parsed)
+}
+
+fn parse_explanation(content: &str) -> Result<CommandExplanation> {
+    let object = extract_json_object(content)
+        .ok_or_else(|| AppError::from("model reply was not a JSON object"))?;
+    let parsed: CommandExplanation = serde_json::from_str(object)
+        .map_err(|e| AppError::from(format!("model reply did not match the output schema ({e})")))?;
+    if parsed.parts.is_empty() {
+        return Err(AppError::from("model returned no command parts"));
     }
+    Ok(parsed)
+}
 
-    let lower = command.to_ascii_lowercase();
-    let risky = ["rm -rf", "mkfs", "dd if=", "shutdown", "reboot"];
-    if risky.iter().any(|p| lower.contains(p)) {
-        "risky".to_string()
-    } else if lower.contains("sudo") || lower.contains("chmod 777") {
-        "caution".to_string()
-    } else {
-        "safe".to_string()
+/// Keeps the successful results, or returns the first error if none succeeded.
+fn first_successes<T>(results: Vec<Result<T>>) -> Result<Vec<T>> {
+    let mut ok = Vec::new();
+    let mut first_err = None;
+    for result in results {
+        match result {
+            Ok(value) => ok.push(value),
+            Err(e) => {
+                first_err.get_or_insert(e);
+            }
+        }
+    }
+    match first_err {
+        Some(e) if ok.is_empty() => Err(e),
+        _ => Ok(ok),
     }
 }
 
+/// Commands that differ only in whitespace count as the same candidate.
+fn same_command(a: &str, b: &str) -> bool {
+    a.split_whitespace().eq(b.split_whitespace())
+}
+
+/// Finds the outermost JSON object, tolerating a markdown fence or a short
+/// preamble around it.
+fn extract_json_object(content: &str) -> Option<&str> {
+    let start = content.find('{')?;
+    let end = content.rfind('}')?;
+    (start < end).then(|| &content[start..=end])
+}
+
 #[derive(Debug, Deserialize)]
 struct OpenAIResponse {
     choices: Vec<OpenAIChoice>,
+    #[serde(default)]
+    usage: Option<OpenAIUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIUsage {
+    #[serde(default)]
+    prompt_tokens: u64,
+    #[serde(default)]
+    completion_tokens: u64,
+}
+
+impl OpenAIUsage {
+    fn tokens(&self) -> TokenUsage {
+        TokenUsage {
+            input_tokens: self.prompt_tokens,
+            output_tokens: self.completion_tokens,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -183,9 +1071,46 @@ struct OpenAIMessage {
     content: String,
 }
 
+#[derive(Debug, Deserialize)]
+struct OpenAIStreamChunk {
+    #[serde(default)]
+    choices: Vec<OpenAIStreamChoice>,
+    #[serde(default)]
+    usage: Option<OpenAIUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIStreamChoice {
+    delta: OpenAIStreamDelta,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIStreamDelta {
+    content: Option<String>,
+}
+
 #[derive(Debug, Deserialize)]
 struct AnthropicResponse {
     content: Vec<AnthropicContent>,
+    #[serde(default)]
+    usage: Option<AnthropicUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicUsage {
+    #[serde(default)]
+    input_tokens: u64,
+    #[serde(default)]
+    output_tokens: u64,
+}
+
+impl AnthropicUsage {
+    fn tokens(&self) -> TokenUsage {
+        TokenUsage {
+            input_tokens: self.input_tokens,
+            output_tokens: self.output_tokens,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -193,4 +1118,396 @@ struct AnthropicContent {
     #[serde(rename = "type")]
     type_name: String,
     text: Option<String>,
+    input: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaResponse {
+    message: OllamaMessage,
+    #[serde(default)]
+    prompt_eval_count: u64,
+    #[serde(default)]
+    eval_count: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaMessage {
+    content: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct LlamaCppResponse {
+    content: String,
+    #[serde(default)]
+    tokens_evaluated: u64,
+    #[serde(default)]
+    tokens_predicted: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicStreamEvent {
+    delta: Option<AnthropicStreamDelta>,
+    message: Option<AnthropicStreamMessage>,
+    usage: Option<AnthropicUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicStreamMessage {
+    usage: Option<AnthropicUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicStreamDelta {
+    text: Option<String>,
+    partial_json: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_model_output_unwraps_fenced_json() {
+        let content = "```json\n{\"command\": \"ls -la\", \"explanation\": \"list\", \"safety\": \"SAFE\"}\n```";
+        let out = parse_model_output(content, ShellKind::Bash).expect("parse failed");
+        assert_eq!(out.command, "ls -la");
+        assert_eq!(out.safety, "safe");
+    }
+
+    #[test]
+    fn parse_model_output_rejects_prose() {
+        let parse = |content| parse_model_output(content, ShellKind::Bash);
+        assert!(parse("Sure! You can run ls -la to see files.").is_err());
+        assert!(parse("{\"command\": \"\", \"explanation\": \"\", \"safety\": \"safe\"}").is_err());
+    }
+
+    #[test]
+    fn parse_explanation_needs_parts_and_the_schema() {
+        let reply = r#"Here you go:
+```json
+{"summary": "Lists files", "parts": [{"text": "ls", "kind": "program", "explanation": "list"}],
+ "safety": "safe"}
+```"#;
+        let parsed = parse_explanation(reply).expect("fenced JSON should parse");
+        assert_eq!((parsed.summary.as_str(), parsed.parts.len()), ("Lists files", 1));
+        assert_eq!(parsed.parts[0].kind, "program");
+
+        let no_parts = r#"{"summary": "Lists files", "parts": [], "safety": "safe"}"#;
+        assert!(parse_explanation(no_parts).unwrap_err().to_string().contains("no command parts"));
+        let missing = r#"{"summary": "Lists files", "safety": "safe"}"#;
+        assert!(parse_explanation(missing).unwrap_err().to_string().contains("output schema"));
+        let prose = "ls lists the files in the current directory.";
+        assert!(parse_explanation(prose).unwrap_err().to_string().contains("not a JSON object"));
+    }
+
+    #[test]
+    fn explain_command_repairs_replies_and_rechecks_safety() {
+        let answer = |content: &str| {
+            json!({"choices": [{"message": {"content": content}}]}).to_string()
+        };
+        let prose = answer("It deletes everything.");
+        let valid = answer(
+            r#"{"summary": "Deletes the root", "safety": "safe", "parts": [
+                {"text": "rm", "kind": "program", "explanation": "remove"},
+                {"text": "-rf", "kind": "flag", "explanation": "recursive, forced"},
+                {"text": "/", "kind": "argument", "explanation": "the root"}]}"#,
+        );
+        let config = replaying(
+            "openai",
+            &[
+                ("/chat/completions", 200, "application/json", &prose),
+                ("/chat/completions", 200, "application/json", &valid),
+            ],
+        );
+        let agent = net::build_agent(&config).expect("agent failed");
+        let explained = explain_command(&agent, &config, "rm -rf /").expect("replay failed");
+        assert_eq!(explained.command, "rm -rf /");
+        assert_eq!(explained.parts.len(), 3);
+        // The model's "safe" doesn't survive the local rules.
+        assert_eq!(explained.safety, "risky");
+        assert!(!explained.safety_reasons.is_empty());
+    }
+
+    #[test]
+    fn finish_with_repair_resends_with_error_context() {
+        let messages = initial_messages(None, "list files");
+        let schema = output_schema(ShellKind::Bash);
+        let system = system_prompt(ShellKind::Bash);
+        let request = generation_request(&system, &messages, &schema);
+        let parse = |content: &str| parse_model_output(content, ShellKind::Bash);
+        let out = finish_with_repair(&request, "ls -la".to_string(), parse, |retry| {
+            assert_eq!(retry.messages.len(), 3);
+            assert_eq!(retry.messages[1].role, ChatRole::Assistant);
+            Ok("{\"command\": \"ls\", \"explanation\": \"x\", \"safety\": \"safe\"}".to_string())
+        })
+        .expect("repair failed");
+        assert_eq!(out.command, "ls");
+    }
+
+    #[test]
+    fn prompt_and_schema_name_the_same_dialect() {
+        for (shell, name) in [
+            (ShellKind::Bash, "bash"),
+            (ShellKind::Fish, "fish"),
+            (ShellKind::Nu, "Nushell (nu)"),
+        ] {
+            let system = system_prompt(shell);
+            assert!(system.contains(&format!("exactly one {name} command")));
+            assert!(system.ends_with("return the complete updated command."));
+            let schema = output_schema(shell);
+            let description = schema["properties"]["command"]["description"].as_str();
+            assert_eq!(
+                description,
+                Some(format!("Exactly one {name} command, without markdown or a prompt marker."))
+                    .as_deref()
+            );
+        }
+    }
+
+    #[test]
+    fn candidates_tolerate_partial_failures_and_whitespace() {
+        let results = vec![Ok("a"), Err(AppError::from("timeout")), Ok("b")];
+        assert_eq!(first_successes(results).expect("should keep successes"), vec!["a", "b"]);
+        assert!(first_successes::<&str>(vec![Err(AppError::from("timeout"))]).is_err());
+        assert!(same_command("ls  -la\n", "ls -la"));
+        assert!(!same_command("ls -la", "ls -al"));
+    }
+
+    #[test]
+    fn separate_candidate_calls_ask_for_different_approaches() {
+        use std::sync::Mutex;
+
+        struct Recorder(Mutex<Vec<String>>);
+        impl ProviderClient for Recorder {
+            fn complete(
+                &self,
+                _: &ureq::Agent,
+                _: &AppConfig,
+                request: &Completion,
+            ) -> Result<String> {
+                let mut seen = self.0.lock().expect("lock");
+                seen.push(request.system.to_string());
+                let n = seen.len();
+                Ok(format!(r#"{{"command":"cmd{n}","explanation":"x","safety":"safe"}}"#))
+            }
+        }
+
+        let client = Recorder(Mutex::new(Vec::new()));
+        let config = crate::config::parse_cli(Vec::new(), None).expect("parse failed");
+        let messages = initial_messages(None, "find big files");
+        let agent = ureq::agent();
+        let outputs = client.generate_candidates(&agent, &config, &messages, 3);
+        assert_eq!(outputs.expect("candidates").len(), 3);
+        let mut systems = client.0.into_inner().expect("lock");
+        assert!(systems.iter().all(|s| s.contains(CANDIDATES_NOTE)));
+        systems.sort();
+        systems.dedup();
+        assert_eq!(systems.len(), 3);
+    }
+
+    #[test]
+    fn only_stream_complaints_trigger_the_plain_retry() {
+        assert!(stream_rejected(501, "Not Implemented"));
+        assert!(stream_rejected(400, "Unrecognized request argument: stream_options"));
+        assert!(stream_rejected(422, "'stream' is not supported"));
+        assert!(!stream_rejected(400, "model 'gpt-9' does not exist"));
+        assert!(!stream_rejected(404, "stream endpoint not found"));
+    }
+
+    #[test]
+    fn push_follow_up_alternates_roles() {
+        let mut messages = initial_messages(None, "find logs");
+        let previous = parse_model_output(
+            "{\"command\": \"find . -name '*.log'\", \"explanation\": \"x\", \"safety\": \"safe\"}",
+            ShellKind::Bash,
+        )
+        .expect("parse failed");
+        push_follow_up(&mut messages, &previous, "also exclude node_modules");
+        let roles: Vec<ChatRole> = messages.iter().map(|m| m.role).collect();
+        assert_eq!(roles, vec![ChatRole::User, ChatRole::Assistant, ChatRole::User]);
+        assert!(messages[1].content.contains("find . -name '*.log'"));
+        assert_eq!(messages[2].content, "Refinement: also exclude node_modules");
+    }
+
+    #[test]
+    fn fallback_only_follows_temporary_failures() {
+        let file_config: crate::types::FileConfig = toml::from_str(
+            "provider = \"ollama\"\n\
+             [[fallback]]\nprovider = \"llamacpp\"\n\
+             [[fallback]]\nprovider = \"anthropic\"\nmodel = \"claude-x\"\napi_key = \"k\"\n",
+        )
+        .expect("config should parse");
+        let args = vec!["--provider".to_string(), "ollama".to_string()];
+        let config = crate::config::parse_cli(args, Some(file_config)).expect("parse failed");
+        let status = |provider, status| AppError::Provider {
+            provider,
+            status,
+            kind: crate::error::ProviderErrorKind::Other,
+            message: String::new(),
+        };
+
+        let mut tried = Vec::new();
+        let agent = net::build_agent(&config).expect("agent failed");
+        let (answer, by, _) = with_fallback(&agent, &config, |_, cfg| {
+            tried.push(cfg.provider);
+            match cfg.provider {
+                Provider::Ollama => Err(status(Provider::Ollama, 503)),
+                Provider::Llamacpp => Err(status(Provider::Llamacpp, 429)),
+                _ => Ok(cfg.api_key.clone()),
+            }
+        })
+        .expect("a fallback should answer");
+        assert_eq!(tried, vec![Provider::Ollama, Provider::Llamacpp, Provider::Anthropic]);
+        assert_eq!(answer, "k");
+        assert_eq!(by.map(|b| b.model), Some("claude-x".to_string()));
+
+        let mut calls = 0;
+        let err = with_fallback(&agent, &config, |_, _| -> Result<()> {
+            calls += 1;
+            Err(status(Provider::Ollama, 400))
+        })
+        .expect_err("a bad request should not fall through");
+        assert_eq!((calls, err.is_transient()), (1, false));
+    }
+
+    /// A config for `provider` whose requests are answered, in order, by
+    /// `responses` of (endpoint, status, content type, body).
+    pub(super) fn replaying(provider: &str, responses: &[(&str, u16, &str, &str)]) -> AppConfig {
+        use cassette::{Cassette, Interaction, RecordedRequest, RecordedResponse};
+
+        let args = ["--provider", provider, "--api-key", "k", "--base-url", "http://replay"];
+        let args = args.iter().map(|a| a.to_string()).collect();
+        let mut config = crate::config::parse_cli(args, None).expect("parse failed");
+        config.retry.base_delay_ms = 0;
+        let interactions = responses
+            .iter()
+            .map(|&(endpoint, status, content_type, body)| Interaction {
+                request: RecordedRequest {
+                    method: "POST".to_string(),
+                    url: format!("http://recorded{endpoint}"),
+                    body: Value::Null,
+                },
+                response: RecordedResponse {
+                    status,
+                    status_text: String::new(),
+                    headers: [("content-type".to_string(), content_type.to_string())].into(),
+                    body: body.to_string(),
+                },
+            })
+            .collect();
+        config.cassette = Some(std::sync::Arc::new(Cassette::replaying(
+            "test.json".into(),
+            interactions,
+        )));
+        config
+    }
+
+    /// Collects streamed text into `shown`, with `|` where a fallback restarted.
+    fn show(shown: &mut String) -> impl FnMut(StreamEvent) + '_ {
+        |event| match event {
+            StreamEvent::Delta(text) => shown.push_str(text),
+            StreamEvent::Restart => shown.push('|'),
+        }
+    }
+
+    #[test]
+    fn a_fallback_restarts_a_stream_that_failed_partway() {
+        let partial = format!(
+            "data: {}\n\n",
+            json!({"choices": [{"delta": {"content": r#"{"command": "ls -l"#}}]})
+        );
+        let event = |name: &str, data: Value| format!("event: {name}\ndata: {data}\n\n");
+        let answer = r#"{"command": "ls -la", "explanation": "x", "safety": "safe"}"#;
+        let stream = [
+            event("content_block_delta", json!({"delta": {"partial_json": answer}})),
+            event("message_stop", json!({})),
+        ]
+        .concat();
+        // OpenAI's stream stops short and the repair request hits a 503.
+        let mut config = replaying(
+            "openai",
+            &[
+                ("/chat/completions", 200, "text/event-stream", &partial),
+                ("/chat/completions", 503, "application/json", "{}"),
+                ("/v1/messages", 200, "text/event-stream", &stream),
+            ],
+        );
+        config.retry.max_attempts = 1;
+        config.fallback = vec![crate::types::ProviderTarget {
+            provider: Provider::Anthropic,
+            model: "claude-test".to_string(),
+            base_url: "http://replay".to_string(),
+            api_key: "k".to_string(),
+        }];
+        let agent = net::build_agent(&config).expect("agent failed");
+        let messages = initial_messages(None, "list all files");
+        let mut shown = String::new();
+        let output = generate_stream(&agent, &config, &messages, &mut show(&mut shown))
+            .expect("the fallback should answer");
+        assert_eq!(output.command, "ls -la");
+        assert_eq!(output.answered_by.map(|by| by.provider), Some(Provider::Anthropic));
+        assert_eq!(shown, "ls -l|ls -la");
+    }
+
+    #[test]
+    fn replays_anthropic_tool_stream_with_usage() {
+        let event = |name: &str, data: Value| format!("event: {name}\ndata: {data}\n\n");
+        let tokens = |input: u64, output: u64| {
+            json!({"input_tokens": input, "output_tokens": output})
+        };
+        let stream = [
+            event("message_start", json!({"message": {"usage": tokens(50, 1)}})),
+            event("content_block_delta", json!({"delta": {"partial_json": r#"{"command": "ls"#}})),
+            event(
+                "content_block_delta",
+                json!({"delta": {"partial_json": r#" -a", "explanation": "x", "safety": "safe"}"#}}),
+            ),
+            event("message_delta", json!({"delta": {}, "usage": {"output_tokens": 12}})),
+            event("message_stop", json!({})),
+        ]
+        .concat();
+        let config = replaying("anthropic", &[("/v1/messages", 200, "text/event-stream", &stream)]);
+        let agent = net::build_agent(&config).expect("agent failed");
+        let messages = initial_messages(None, "list all files");
+        let mut shown = String::new();
+        let output = generate_stream(&agent, &config, &messages, &mut show(&mut shown))
+            .expect("replay failed");
+        assert_eq!(output.command, "ls -a");
+        assert_eq!(shown, "ls -a");
+        assert_eq!(output.usage, Some(TokenUsage { input_tokens: 50, output_tokens: 12 }));
+    }
+
+    #[test]
+    fn replays_retries_repairs_and_provider_errors() {
+        let answer = |content: &str| {
+            json!({
+                "choices": [{"message": {"content": content}}],
+                "usage": {"prompt_tokens": 10, "completion_tokens": 5}
+            })
+            .to_string()
+        };
+        let prose = answer("Sure! Run ls -la to see everything.");
+        let valid = answer(r#"{"command": "ls -la", "explanation": "x", "safety": "safe"}"#);
+        let config = replaying(
+            "openai",
+            &[
+                ("/chat/completions", 429, "application/json", "{}"),
+                ("/chat/completions", 200, "application/json", &prose),
+                ("/chat/completions", 200, "application/json", &valid),
+            ],
+        );
+        let agent = net::build_agent(&config).expect("agent failed");
+        let messages = initial_messages(None, "list all files");
+        let output = generate_once(&agent, &config, &messages).expect("replay failed");
+        assert_eq!(output.command, "ls -la");
+        assert_eq!(output.usage, Some(TokenUsage { input_tokens: 20, output_tokens: 10 }));
+        let err = generate_once(&agent, &config, &messages).expect_err("cassette is used up");
+        assert!(err.to_string().contains("no unplayed response"), "{err}");
+
+        let denied = r#"{"error":{"message":"Incorrect API key","code":"invalid_api_key"}}"#;
+        let config = replaying("openai", &[("/chat/completions", 401, "application/json", denied)]);
+        let err = generate_once(&agent, &config, &messages).expect_err("401 should fail");
+        let invalid_key = crate::error::ProviderErrorKind::InvalidKey;
+        assert!(matches!(err, AppError::Provider { status: 401, kind, .. } if kind == invalid_key));
+    }
 }
@@ -0,0 +1,236 @@
+use super::{client_for, millis};
+use crate::error::{AppError, Result};
+use crate::history;
+use crate::net;
+use crate::types::{AnsweredBy, AppConfig, ChatMessage, GenerationOutput, Via};
+use crate::usage::{self, CallOutcome, CallRecord, TokenUsage};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Sends the same request to the configured provider and every fallback at
+/// once and keeps the first answer that parses. ureq can't cancel a request
+/// in flight, so the slower ones finish on their own threads and are ignored.
+pub(super) fn race(
+    agent: &ureq::Agent,
+    config: &AppConfig,
+    messages: &[ChatMessage],
+) -> Result<GenerationOutput> {
+    let entrants: Vec<AppConfig> = std::iter::once(config.clone())
+        .chain(config.fallback.iter().map(|target| config.with_target(target)))
+        .collect();
+    // Fallbacks may sit behind a different proxy, so each gets its own agent.
+    let agents = std::iter::once(Ok(agent.clone()))
+        .chain(entrants[1..].iter().map(net::build_agent))
+        .collect::<Result<Vec<_>>>()?;
+    let started = Instant::now();
+    let (tx, rx) = mpsc::channel();
+    for (index, (entrant, agent)) in entrants.iter().cloned().zip(agents).enumerate() {
+        let tx = tx.clone();
+        let messages = messages.to_vec();
+        thread::spawn(move || {
+            let (result, tokens) = usage::metered(|| {
+                client_for(entrant.provider).generate(&agent, &entrant, &messages)
+            });
+            let _ = tx.send((index, started.elapsed(), result, tokens));
+        });
+    }
+    drop(tx);
+
+    let mut finished: Vec<Option<(Duration, CallOutcome, Option<TokenUsage>)>> =
+        vec![None; entrants.len()];
+    let mut errors: Vec<Option<AppError>> = entrants.iter().map(|_| None).collect();
+    let mut winner = None;
+    for (index, elapsed, result, tokens) in rx.iter() {
+        match result {
+            Ok(mut output) => {
+                finished[index] = Some((elapsed, CallOutcome::Answered, tokens));
+                output.usage = tokens;
+                winner = Some((index, elapsed, output));
+                break;
+            }
+            Err(err) => {
+                finished[index] = Some((elapsed, CallOutcome::Failed, tokens));
+                errors[index] = Some(err);
+            }
+        }
+    }
+
+    let abandoned_at = started.elapsed();
+    let timestamp = history::now();
+    let records: Vec<CallRecord> = entrants
+        .iter()
+        .zip(&finished)
+        .map(|(entrant, finished)| {
+            let (elapsed, outcome, tokens) =
+                finished.unwrap_or((abandoned_at, CallOutcome::Abandoned, None));
+            CallRecord {
+                timestamp,
+                provider: entrant.provider.as_str().to_string(),
+                model: entrant.model.clone(),
+                latency_ms: millis(elapsed),
+                outcome,
+                raced: true,
+                tokens,
+            }
+        })
+        .collect();
+    usage::remember(config.usage_path.as_deref(), &records);
+
+    match winner {
+        Some((index, elapsed, mut output)) => {
+            output.answered_by = Some(AnsweredBy {
+                provider: entrants[index].provider,
+                model: entrants[index].model.clone(),
+                via: Via::Race {
+                    latency_ms: millis(elapsed),
+                },
+            });
+            Ok(output)
+        }
+        // Every entrant failed; the configured provider's error is the one
+        // the user would have seen without racing.
+        None => Err(errors
+            .into_iter()
+            .flatten()
+            .next()
+            .unwrap_or_else(|| AppError::from("no provider answered"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::cassette::Cassette;
+    use crate::providers::tests::replaying;
+    use crate::types::{Provider, ProviderTarget};
+    use serde_json::json;
+    use std::fs;
+    use std::path::PathBuf;
+
+    const JSON: &str = "application/json";
+
+    /// OpenAI raced against an Anthropic fallback, both answering from
+    /// `responses`, with usage recorded under a fresh directory.
+    fn racing(name: &str, responses: &[(&str, u16, &str, &str)]) -> (PathBuf, AppConfig) {
+        let dir = std::env::temp_dir().join(format!("b-race-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut config = replaying("openai", responses);
+        config.race = true;
+        config.fallback = vec![ProviderTarget {
+            provider: Provider::Anthropic,
+            model: "claude-test".to_string(),
+            base_url: "http://replay".to_string(),
+            api_key: "k".to_string(),
+        }];
+        config.usage_path = Some(dir.join("usage.jsonl"));
+        (dir, config)
+    }
+
+    fn anthropic_answer(command: &str) -> String {
+        json!({
+            "content": [{
+                "type": "tool_use",
+                "input": {"command": command, "explanation": "x", "safety": "safe"}
+            }],
+            "usage": {"input_tokens": 7, "output_tokens": 3}
+        })
+        .to_string()
+    }
+
+    fn cassette(config: &AppConfig) -> &Cassette {
+        config.cassette.as_deref().expect("replaying")
+    }
+
+    fn outcomes(config: &AppConfig) -> Vec<(String, CallOutcome, Option<TokenUsage>)> {
+        let path = config.usage_path.as_deref().expect("usage path");
+        let records = usage::load(path).expect("load usage");
+        assert!(records.iter().all(|r| r.raced));
+        records.into_iter().map(|r| (r.provider, r.outcome, r.tokens)).collect()
+    }
+
+    fn run(config: &AppConfig) -> Result<GenerationOutput> {
+        let agent = net::build_agent(config).expect("agent failed");
+        let messages = [ChatMessage::user("User request: list files".to_string())];
+        race(&agent, config, &messages)
+    }
+
+    #[test]
+    fn first_valid_answer_wins_and_every_entrant_is_recorded() {
+        let bad_model = r#"{"error":{"message":"The model `gpt-x` does not exist"}}"#;
+        let answer = anthropic_answer("ls");
+        let (dir, config) = racing(
+            "winner",
+            &[("/chat/completions", 400, JSON, bad_model), ("/v1/messages", 200, JSON, &answer)],
+        );
+        // Anthropic answers only once OpenAI's failure is in.
+        cassette(&config).hold_until_done("/v1/messages", "/chat/completions");
+
+        let output = run(&config).expect("the fallback should win");
+        assert_eq!(output.command, "ls");
+        let by = output.answered_by.expect("winner named");
+        assert_eq!((by.provider, by.model.as_str()), (Provider::Anthropic, "claude-test"));
+        assert!(matches!(by.via, Via::Race { .. }));
+        let tokens = TokenUsage { input_tokens: 7, output_tokens: 3 };
+        assert_eq!(output.usage, Some(tokens));
+        assert_eq!(
+            outcomes(&config),
+            vec![
+                ("openai".to_string(), CallOutcome::Failed, None),
+                ("anthropic".to_string(), CallOutcome::Answered, Some(tokens)),
+            ]
+        );
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn the_configured_providers_error_is_returned_when_all_fail() {
+        let bad_model = r#"{"error":{"message":"The model `gpt-x` does not exist"}}"#;
+        let denied = r#"{"type":"error","error":{"type":"authentication_error","message":"no"}}"#;
+        let (dir, config) = racing(
+            "all-fail",
+            &[("/chat/completions", 404, JSON, bad_model), ("/v1/messages", 401, JSON, denied)],
+        );
+
+        let err = run(&config).expect_err("nobody answered");
+        assert!(
+            matches!(err, AppError::Provider { provider: Provider::Openai, status: 404, .. }),
+            "{err}"
+        );
+        let failed: Vec<_> = outcomes(&config).into_iter().map(|(_, o, _)| o).collect();
+        assert_eq!(failed, vec![CallOutcome::Failed, CallOutcome::Failed]);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn slower_entrants_are_recorded_as_abandoned() {
+        let content = r#"{"command": "ls", "explanation": "x", "safety": "safe"}"#;
+        let answer = json!({
+            "choices": [{"message": {"content": content}}],
+            "usage": {"prompt_tokens": 10, "completion_tokens": 5}
+        })
+        .to_string();
+        let (dir, config) = racing(
+            "abandoned",
+            &[
+                ("/chat/completions", 200, JSON, &answer),
+                ("/v1/messages", 200, JSON, &anthropic_answer("ls")),
+            ],
+        );
+        // Anthropic is still waiting for its answer when OpenAI's is taken.
+        let release = cassette(&config).hold("/v1/messages");
+
+        let output = run(&config).expect("openai should win");
+        drop(release);
+        assert_eq!(output.answered_by.map(|by| by.provider), Some(Provider::Openai));
+        let tokens = TokenUsage { input_tokens: 10, output_tokens: 5 };
+        assert_eq!(
+            outcomes(&config),
+            vec![
+                ("openai".to_string(), CallOutcome::Answered, Some(tokens)),
+                ("anthropic".to_string(), CallOutcome::Abandoned, None),
+            ]
+        );
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,302 @@
+use super::{cassette, error_body};
+use crate::error::{AppError, Result};
+use crate::net::Deadline;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// `[retry]` section of `config.toml`.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct RetrySettings {
+    /// Total attempts including the first; 1 disables retries.
+    pub max_attempts: u32,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+    /// Give up rather than wait past this much time in total.
+    pub max_total_secs: u64,
+}
+
+impl Default for RetrySettings {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            base_delay_ms: 500,
+            max_delay_ms: 8_000,
+            max_total_secs: 30,
+        }
+    }
+}
+
+/// Announced before each wait, e.g. to drive the spinner text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryNotice {
+    pub reason: String,
+    pub wait: Duration,
+}
+
+type Observer = Box<dyn Fn(&RetryNotice)>;
+
+thread_local! {
+    static OBSERVER: RefCell<Option<Observer>> = const { RefCell::new(None) };
+}
+
+/// Runs `job` with `observer` receiving every retry notice raised on this
+/// thread. Provider clients don't need to know who is listening.
+pub fn observe<T>(observer: impl Fn(&RetryNotice) + 'static, job: impl FnOnce() -> T) -> T {
+    let previous = OBSERVER.with(|slot| slot.replace(Some(Box::new(observer))));
+    let result = job();
+    OBSERVER.with(|slot| *slot.borrow_mut() = previous);
+    result
+}
+
+/// `send`, retried on 429, 5xx and dropped connections with exponential
+/// backoff and jitter. A `Retry-After` header replaces the computed delay.
+/// No wait runs past `deadline`, and once it has passed the error is a
+/// timeout.
+pub fn send_json(
+    settings: &RetrySettings,
+    deadline: &Deadline,
+    mut send: impl FnMut() -> Result<ureq::Response>,
+) -> Result<ureq::Response> {
+    let started = Instant::now();
+    let budget = Duration::from_secs(settings.max_total_secs);
+    let mut attempt = 1;
+    loop {
+        let err = match send() {
+            Ok(response) => return Ok(response),
+            Err(err) => err,
+        };
+        let (err, exhausted) = check_quota(err)?;
+        if exhausted {
+            return Err(err);
+        }
+        let Some(reason) = retry_reason(&err) else {
+            return Err(err);
+        };
+        deadline.check()?;
+        if attempt >= settings.max_attempts {
+            return Err(err);
+        }
+        let wait = retry_after(&err).unwrap_or_else(|| backoff(settings, attempt));
+        if started.elapsed() + wait > budget || deadline.remaining().is_some_and(|l| wait >= l) {
+            return Err(err);
+        }
+        let notice = RetryNotice { reason, wait };
+        OBSERVER.with(|slot| {
+            if let Some(observer) = slot.borrow().as_ref() {
+                observer(&notice);
+            }
+        });
+        thread::sleep(wait);
+        attempt += 1;
+    }
+}
+
+/// Reads a 429's body to tell a rate limit from a used-up quota or balance,
+/// which no retry fixes. Either way the error comes back with its body
+/// intact, so it can still be reported.
+fn check_quota(err: AppError) -> Result<(AppError, bool)> {
+    let AppError::Request(inner) = err else {
+        return Ok((err, false));
+    };
+    match *inner {
+        ureq::Error::Status(429, response) => {
+            let (body, err) = cassette::buffer_error(429, response)?;
+            Ok((err, error_body::is_quota_exhausted(&body)))
+        }
+        other => Ok((AppError::Request(Box::new(other)), false)),
+    }
+}
+
+fn retry_reason(err: &AppError) -> Option<String> {
+    let AppError::Request(err) = err else {
+        return None;
+    };
+    match &**err {
+        ureq::Error::Status(429, _) => Some("rate limited".to_string()),
+        // 529 is Anthropic's "overloaded".
+        ureq::Error::Status(code @ (500 | 502 | 503 | 504 | 529), _) => {
+            Some(format!("server error {code}"))
+        }
+        // Unreachable hosts fail fast so the cache fallback can kick in;
+        // only connections that dropped mid-request are retried.
+        ureq::Error::Transport(t) if t.kind() == ureq::ErrorKind::Io => {
+            Some("connection dropped".to_string())
+        }
+        _ => None,
+    }
+}
+
+/// `retry-after` as seconds, as both OpenAI and Anthropic send it, or as an
+/// HTTP date, which some proxies and gateways use.
+fn retry_after(err: &AppError) -> Option<Duration> {
+    let AppError::Request(err) = err else {
+        return None;
+    };
+    let ureq::Error::Status(_, response) = &**err else {
+        return None;
+    };
+    parse_retry_after(response.header("retry-after")?, SystemTime::now())
+}
+
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<f64>() {
+        return (secs.is_finite() && secs >= 0.0).then(|| Duration::from_secs_f64(secs));
+    }
+    let at = UNIX_EPOCH + Duration::from_secs(http_date(value)?);
+    // A date already past means "now".
+    Some(at.duration_since(now).unwrap_or(Duration::ZERO))
+}
+
+/// Unix seconds for an IMF-fixdate such as `Sun, 06 Nov 1994 08:49:37 GMT`,
+/// the only date form HTTP/1.1 senders may generate.
+fn http_date(value: &str) -> Option<u64> {
+    let (_, rest) = value.split_once(", ")?;
+    let fields: Vec<&str> = rest.split_whitespace().collect();
+    let [day, month, year, time, "GMT"] = fields[..] else {
+        return None;
+    };
+    const MONTHS: [&str; 12] =
+        ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let month = MONTHS.iter().position(|m| *m == month)? as i64 + 1;
+    let (day, year): (i64, i64) = (day.parse().ok()?, year.parse().ok()?);
+    let mut hms = time.split(':').map(|n| n.parse::<i64>().ok());
+    let (h, m, s) = (hms.next()??, hms.next()??, hms.next()??);
+    if hms.next().is_some() || !(1..=31).contains(&day) || h > 23 || m > 59 || s > 60 {
+        return None;
+    }
+    // Days since the epoch (Howard Hinnant's algorithm), as in `usage::civil_date`.
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    u64::try_from(days * 86_400 + h * 3_600 + m * 60 + s).ok()
+}
+
+/// Exponential backoff with "equal jitter": half the delay is fixed, the
+/// other half random, so concurrent clients spread out.
+fn backoff(settings: &RetrySettings, attempt: u32) -> Duration {
+    let exp = settings
+        .base_delay_ms
+        .saturating_mul(1u64 << (attempt - 1).min(16))
+        .min(settings.max_delay_ms);
+    let half = exp / 2;
+    Duration::from_millis(half + jitter(half))
+}
+
+fn jitter(max: u64) -> u64 {
+    if max == 0 {
+        return 0;
+    }
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    u64::from(nanos) % (max + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_and_is_capped() {
+        let settings = RetrySettings {
+            base_delay_ms: 400,
+            max_delay_ms: 1_000,
+            ..RetrySettings::default()
+        };
+        let first = backoff(&settings, 1);
+        assert!(first >= Duration::from_millis(200) && first <= Duration::from_millis(400));
+        let second = backoff(&settings, 2);
+        assert!(second >= Duration::from_millis(400) && second <= Duration::from_millis(800));
+        let capped = backoff(&settings, 10);
+        assert!(capped >= Duration::from_millis(500) && capped <= Duration::from_millis(1_000));
+    }
+
+    #[test]
+    fn retry_after_takes_seconds_or_an_http_date() {
+        let now = UNIX_EPOCH + Duration::from_secs(784_111_777); // Sun, 06 Nov 1994 08:49:37 GMT
+        assert_eq!(parse_retry_after(" 2 ", now), Some(Duration::from_secs(2)));
+        assert_eq!(parse_retry_after("0.5", now), Some(Duration::from_millis(500)));
+        let later = "Sun, 06 Nov 1994 08:50:07 GMT";
+        assert_eq!(parse_retry_after(later, now), Some(Duration::from_secs(30)));
+        let earlier = "Sat, 05 Nov 1994 08:49:37 GMT";
+        assert_eq!(parse_retry_after(earlier, now), Some(Duration::ZERO));
+        assert_eq!(http_date("Tue, 29 Feb 2028 00:00:00 GMT"), Some(1_835_395_200));
+        assert_eq!(parse_retry_after("Sunday, 06-Nov-94 08:49:37 GMT", now), None);
+        assert_eq!(parse_retry_after("soon", now), None);
+        assert_eq!(parse_retry_after("-1", now), None);
+    }
+
+    #[test]
+    fn observe_restores_previous_observer() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        let seen = Rc::new(Cell::new(0));
+        let counter = Rc::clone(&seen);
+        observe(
+            move |_| counter.set(counter.get() + 1),
+            || {
+                OBSERVER.with(|slot| {
+                    let notice = RetryNotice { reason: "x".to_string(), wait: Duration::ZERO };
+                    (slot.borrow().as_ref().expect("observer set"))(&notice);
+                });
+            },
+        );
+        assert_eq!(seen.get(), 1);
+        assert!(OBSERVER.with(|slot| slot.borrow().is_none()));
+    }
+
+    #[test]
+    fn exhausted_quota_is_not_retried() {
+        let settings = RetrySettings { base_delay_ms: 0, ..RetrySettings::default() };
+        let deadline = Deadline::start(&crate::net::NetworkSettings::default());
+        let rate_limited = |body: &str| -> Result<ureq::Response> {
+            let raw = format!("HTTP/1.1 429 Too Many Requests\r\n\r\n{body}");
+            Err(ureq::Error::Status(429, raw.parse()?).into())
+        };
+        let quota = r#"{"error":{"message":"Check your billing.","code":"insufficient_quota"}}"#;
+        let mut calls = 0;
+        let result = send_json(&settings, &deadline, || {
+            calls += 1;
+            rate_limited(quota)
+        });
+        assert_eq!(calls, 1);
+        // The body is still there for the error message.
+        let Err(AppError::Request(err)) = result else {
+            panic!("expected the 429 back");
+        };
+        let err = error_body::provider_error(crate::types::Provider::Openai, err);
+        assert!(err.to_string().contains("Check your billing"), "{err}");
+
+        let mut calls = 0;
+        let _ = send_json(&settings, &deadline, || {
+            calls += 1;
+            rate_limited(r#"{"error":{"message":"Rate limit reached for requests"}}"#)
+        });
+        assert_eq!(calls, settings.max_attempts);
+    }
+
+    #[test]
+    fn no_retry_waits_past_the_deadline() {
+        let network = crate::net::NetworkSettings { timeout_secs: 1, ..Default::default() };
+        let deadline = Deadline::start(&network);
+        let mut calls = 0;
+        let result = send_json(&RetrySettings::default(), &deadline, || {
+            calls += 1;
+            let response: ureq::Response =
+                "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 5\r\n\r\n".parse()?;
+            Err(ureq::Error::Status(429, response).into())
+        });
+        assert!(matches!(result, Err(AppError::Request(_))));
+        assert_eq!(calls, 1);
+    }
+}
@@ -0,0 +1,211 @@
+use crate::error::Result;
+use std::io::{BufRead, BufReader, Read};
+
+/// One dispatched server-sent event.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SseEvent {
+    pub event: Option<String>,
+    pub data: String,
+}
+
+/// Minimal `text/event-stream` reader: groups `event:`/`data:` lines into
+/// events separated by blank lines and ignores comments and other fields.
+pub struct SseReader<R: Read> {
+    lines: std::io::Lines<BufReader<R>>,
+    done: bool,
+}
+
+impl<R: Read> SseReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            lines: BufReader::new(reader).lines(),
+            done: false,
+        }
+    }
+}
+
+impl<R: Read> Iterator for SseReader<R> {
+    type Item = Result<SseEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let mut event = SseEvent::default();
+        let mut has_data = false;
+        loop {
+            let line = match self.lines.next() {
+                Some(Ok(line)) => line,
+                Some(Err(e)) => return Some(Err(e.into())),
+                None => {
+                    self.done = true;
+                    return has_data.then_some(Ok(event));
+                }
+            };
+            let line = line.trim_end_matches('\r');
+            if line.is_empty() {
+                if has_data {
+                    return Some(Ok(event));
+                }
+                event.event = None;
+                continue;
+            }
+            if line.starts_with(':') {
+                continue;
+            }
+            let (field, value) = match line.split_once(':') {
+                Some((f, v)) => (f, v.strip_prefix(' ').unwrap_or(v)),
+                None => (line, ""),
+            };
+            match field {
+                "event" => event.event = Some(value.to_string()),
+                "data" => {
+                    if has_data {
+                        event.data.push('\n');
+                    }
+                    event.data.push_str(value);
+                    has_data = true;
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum ExtractState {
+    #[default]
+    Seeking,
+    InString(usize),
+    Done,
+}
+
+/// Incrementally pulls the `command` string out of a JSON object that is
+/// still being streamed, so the UI can echo it before the object closes.
+#[derive(Debug, Default)]
+pub struct CommandExtractor {
+    buf: String,
+    state: ExtractState,
+}
+
+impl CommandExtractor {
+    /// Appends raw model text and returns any newly decoded command characters.
+    pub fn push(&mut self, chunk: &str) -> String {
+        self.buf.push_str(chunk);
+        if self.state == ExtractState::Seeking {
+            if let Some(start) = find_command_value(&self.buf) {
+                self.state = ExtractState::InString(start);
+            }
+        }
+        let ExtractState::InString(pos) = self.state else {
+            return String::new();
+        };
+
+        let mut out = String::new();
+        let bytes = self.buf.as_bytes();
+        let mut i = pos;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'"' => {
+                    self.state = ExtractState::Done;
+                    return out;
+                }
+                b'\\' => match decode_escape(&self.buf[i..]) {
+                    Some((ch, len)) => {
+                        out.push(ch);
+                        i += len;
+                    }
+                    None => break,
+                },
+                _ => {
+                    let ch = self.buf[i..].chars().next().unwrap_or_default();
+                    out.push(ch);
+                    i += ch.len_utf8();
+                }
+            }
+        }
+        self.state = ExtractState::InString(i);
+        out
+    }
+}
+
+/// Returns the byte offset just past the opening quote of the `command` value.
+fn find_command_value(buf: &str) -> Option<usize> {
+    let key = "\"command\"";
+    let mut from = 0;
+    while let Some(rel) = buf[from..].find(key) {
+        let at = from + rel;
+        from = at + key.len();
+        if at > 0 && buf.as_bytes()[at - 1] == b'\\' {
+            continue;
+        }
+        let rest = buf[from..].trim_start();
+        let Some(rest) = rest.strip_prefix(':') else {
+            continue;
+        };
+        let rest_trimmed = rest.trim_start();
+        if rest_trimmed.starts_with('"') {
+            return Some(buf.len() - rest_trimmed.len() + 1);
+        }
+    }
+    None
+}
+
+/// Decodes one JSON escape at the start of `s`, or `None` if it is incomplete.
+fn decode_escape(s: &str) -> Option<(char, usize)> {
+    let mut chars = s.chars();
+    chars.next()?;
+    let ch = match chars.next()? {
+        'n' => '\n',
+        't' => '\t',
+        'r' => '\r',
+        'b' => '\u{8}',
+        'f' => '\u{c}',
+        'u' => {
+            let high = u32::from_str_radix(s.get(2..6)?, 16).ok()?;
+            if (0xD800..0xDC00).contains(&high) {
+                let low = s.get(6..12)?.strip_prefix("\\u")?;
+                let low = u32::from_str_radix(low, 16).ok()?;
+                let code = 0x10000 + ((high - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF);
+                return Some((char::from_u32(code).unwrap_or('\u{FFFD}'), 12));
+            }
+            return Some((char::from_u32(high).unwrap_or('\u{FFFD}'), 6));
+        }
+        other => other,
+    };
+    Some((ch, 2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sse_reader_groups_multiline_events() {
+        let raw = "event: ping\ndata: {}\n\n: comment\ndata: a\ndata: b\n\ndata: [DONE]\n";
+        let events: Vec<SseEvent> = SseReader::new(raw.as_bytes())
+            .collect::<Result<_>>()
+            .expect("read failed");
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].event.as_deref(), Some("ping"));
+        assert_eq!(events[1].data, "a\nb");
+        assert_eq!(events[2].data, "[DONE]");
+    }
+
+    #[test]
+    fn command_extractor_handles_split_escapes() {
+        let mut ex = CommandExtractor::default();
+        let mut out = String::new();
+        for chunk in ["{\"comm", "and\": \"grep \\", "\"a b\\", "\" *.txt\", \"explanation\": \"x\"}"] {
+            out.push_str(&ex.push(chunk));
+        }
+        assert_eq!(out, "grep \"a b\" *.txt");
+    }
+
+    #[test]
+    fn command_extractor_ignores_escaped_key_in_prose() {
+        let mut ex = CommandExtractor::default();
+        let out = ex.push("{\"explanation\": \"the \\\"command\\\": \\\"x\\\"\", \"command\": \"ls\"}");
+        assert_eq!(out, "ls");
+    }
+}
@@ -0,0 +1,585 @@
+pub mod parser;
+mod policy;
+
+pub use policy::{Policy, Verdict};
+
+use crate::shell_integration::ShellKind;
+use parser::{parse, Command, Pipeline, Redirect, Script, SimpleCommand};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SafetyLevel {
+    Safe,
+    Caution,
+    Risky,
+}
+
+impl SafetyLevel {
+    pub fn parse(input: &str) -> Option<Self> {
+        match input.trim().to_ascii_lowercase().as_str() {
+            "safe" => Some(Self::Safe),
+            "caution" => Some(Self::Caution),
+            "risky" => Some(Self::Risky),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SafetyLevel::Safe => "safe",
+            SafetyLevel::Caution => "caution",
+            SafetyLevel::Risky => "risky",
+        }
+    }
+}
+
+/// Result of static analysis: the overall level and why it was chosen.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Analysis {
+    pub level: SafetyLevel,
+    pub reasons: Vec<String>,
+}
+
+impl Analysis {
+    fn flag(&mut self, level: SafetyLevel, reason: impl Into<String>) {
+        let reason = reason.into();
+        if !self.reasons.contains(&reason) {
+            self.reasons.push(reason);
+        }
+        self.level = self.level.max(level);
+    }
+}
+
+/// Combines the model's label with static analysis. The analysis can only
+/// raise the level: a model calling `rm -rf /` "safe" is overridden, while
+/// a model's extra caution is kept.
+pub fn reconcile(model_label: &str, command: &str, shell: ShellKind) -> Analysis {
+    let mut analysis = analyze(command, shell);
+    if let Some(model) = SafetyLevel::parse(model_label) {
+        if model > analysis.level {
+            analysis.flag(model, format!("model rated this command {}", model.as_str()));
+        }
+    }
+    analysis
+}
+
+/// The rules read commands as bash. nu and pwsh lines are still run through
+/// them, which catches `rm -r ~` and a few PowerShell cmdlets, but their
+/// quoting and expansion differ, so such a line is never rated safe.
+pub fn analyze(command: &str, shell: ShellKind) -> Analysis {
+    let mut analysis = Analysis {
+        level: SafetyLevel::Safe,
+        reasons: Vec::new(),
+    };
+    match parse(command) {
+        Ok(script) => check_script(&script, &mut analysis, 0),
+        Err(_) if !shell.reads_like_bash() => {}
+        Err(e) => analysis.flag(
+            SafetyLevel::Caution,
+            format!("could not parse command ({e}); review it manually"),
+        ),
+    }
+    if !shell.reads_like_bash() {
+        analysis.flag(
+            SafetyLevel::Caution,
+            format!("{} syntax is only partly checked; review it manually", shell.as_str()),
+        );
+    }
+    analysis
+}
+
+/// Limit for re-parsing `bash -c '...'` payloads.
+const MAX_NESTING: usize = 4;
+
+const SHELLS: [&str; 12] = [
+    "sh", "bash", "zsh", "dash", "ksh", "fish", "python", "python3", "perl", "ruby", "node", "php",
+];
+
+/// Top-level paths whose recursive deletion or permission change is never intended.
+const CRITICAL_PATHS: [&str; 22] = [
+    "/", "/*", "~", "~/*", "$HOME", "${HOME}", "*", ".", "..", "./*", "/bin", "/boot", "/dev",
+    "/etc", "/home", "/lib", "/opt", "/root", "/sbin", "/usr", "/var", "/Users",
+];
+
+fn check_script(script: &Script, analysis: &mut Analysis, depth: usize) {
+    for pipeline in &script.pipelines {
+        check_pipeline(pipeline, analysis);
+        for command in &pipeline.commands {
+            match command {
+                Command::Simple(simple) => {
+                    check_redirects(&simple.redirects, analysis);
+                    check_argv(&simple_argv(simple), analysis, depth);
+                    let words = simple.words.iter().chain(&simple.assignments);
+                    for word in words.chain(simple.redirects.iter().map(|r| &r.target)) {
+                        for sub in &word.substitutions {
+                            check_script(sub, analysis, depth);
+                        }
+                    }
+                }
+                Command::Group(inner, redirects) => {
+                    check_redirects(redirects, analysis);
+                    check_script(inner, analysis, depth);
+                }
+            }
+        }
+    }
+}
+
+/// Flags `curl ... | sh` style pipelines.
+fn check_pipeline(pipeline: &Pipeline, analysis: &mut Analysis) {
+    let programs: Vec<Option<(String, Vec<String>)>> = pipeline
+        .commands
+        .iter()
+        .map(|c| match c {
+            Command::Simple(simple) => {
+                let unwrapped = unwrap_wrappers(&simple_argv(simple));
+                unwrapped.program.map(|p| (p, unwrapped.args))
+            }
+            Command::Group(..) => None,
+        })
+        .collect();
+
+    for (idx, entry) in programs.iter().enumerate().skip(1) {
+        let Some((program, args)) = entry else {
+            continue;
+        };
+        if !SHELLS.contains(&program.as_str()) || !reads_script_from_stdin(args) {
+            continue;
+        }
+        let downloads = programs[..idx]
+            .iter()
+            .flatten()
+            .any(|(p, _)| matches!(p.as_str(), "curl" | "wget" | "fetch"));
+        if downloads {
+            analysis.flag(
+                SafetyLevel::Risky,
+                format!("pipes a downloaded script straight into {program}"),
+            );
+        } else {
+            analysis.flag(SafetyLevel::Caution, format!("pipes generated text into {program}"));
+        }
+    }
+}
+
+fn reads_script_from_stdin(args: &[String]) -> bool {
+    let mut operands = args.iter().filter(|a| !a.starts_with('-') || a.as_str() == "-");
+    match operands.next() {
+        None => !args.iter().any(|a| a == "-c" || a == "-e"),
+        Some(first) => first == "-" || args.iter().any(|a| a == "-s"),
+    }
+}
+
+fn check_redirects(redirects: &[Redirect], analysis: &mut Analysis) {
+    for redirect in redirects {
+        if !matches!(redirect.op, ">" | ">>" | ">|" | "&>" | "&>>" | "<>") {
+            continue;
+        }
+        let target = redirect.target.value.as_str();
+        if is_block_device(target) {
+            analysis.flag(
+                SafetyLevel::Risky,
+                format!("writes directly to block device {target}"),
+            );
+        } else if ["/etc/", "/boot/", "/usr/", "/bin/", "/sbin/", "/lib/"]
+            .iter()
+            .any(|prefix| target.starts_with(prefix))
+        {
+            analysis.flag(
+                SafetyLevel::Caution,
+                format!("overwrites system file {target}"),
+            );
+        }
+    }
+}
+
+fn simple_argv(simple: &SimpleCommand) -> Vec<String> {
+    simple.argv().into_iter().map(str::to_string).collect()
+}
+
+fn check_argv(argv: &[String], analysis: &mut Analysis, depth: usize) {
+    let unwrapped = unwrap_wrappers(argv);
+    if let Some(via) = &unwrapped.elevated_by {
+        analysis.flag(SafetyLevel::Caution, format!("runs as root via {via}"));
+    }
+    let Some(program) = unwrapped.program else {
+        return;
+    };
+    let args: Vec<&str> = unwrapped.args.iter().map(String::as_str).collect();
+
+    match program.as_str() {
+        "rm" => check_rm(&args, analysis),
+        "find" => check_find(&args, analysis, depth),
+        "dd" => check_dd(&args, analysis),
+        "chmod" | "chown" | "chgrp" => check_permissions(&program, &args, analysis),
+        "mv" => check_mv(&args, analysis),
+        "git" => check_git(&args, analysis),
+        // `kill -1 <pid>` is SIGHUP; only a trailing `-1` target means "every process".
+        "kill" if args.len() >= 2 && args.last() == Some(&"-1") => {
+            analysis.flag(SafetyLevel::Risky, "kill -1 signals every process you own");
+        }
+        "killall" | "pkill" => {
+            analysis.flag(SafetyLevel::Caution, format!("{program} kills processes by name"));
+        }
+        "shutdown" | "reboot" | "halt" | "poweroff" => {
+            analysis.flag(SafetyLevel::Risky, format!("{program} stops or restarts the machine"));
+        }
+        "init" | "telinit" if matches!(args.first(), Some(&"0") | Some(&"6")) => {
+            analysis.flag(SafetyLevel::Risky, "changes runlevel (shutdown/reboot)");
+        }
+        "systemctl"
+            if args
+                .iter()
+                .any(|a| matches!(*a, "poweroff" | "reboot" | "halt" | "kexec" | "rescue" | "emergency")) =>
+        {
+            analysis.flag(SafetyLevel::Risky, "systemctl stops or restarts the machine");
+        }
+        "mkswap" | "wipefs" | "fdisk" | "sfdisk" | "gdisk" | "sgdisk" | "parted" | "shred" => {
+            analysis.flag(SafetyLevel::Risky, format!("{program} can destroy disk data"));
+        }
+        p if p.starts_with("mkfs") || p == "mke2fs" => {
+            analysis.flag(SafetyLevel::Risky, format!("{program} formats a filesystem"));
+        }
+        "diskutil"
+            if args.iter().any(|a| {
+                let lower = a.to_ascii_lowercase();
+                lower.starts_with("erase") || lower == "zerodisk" || lower == "partitiondisk"
+            }) =>
+        {
+            analysis.flag(SafetyLevel::Risky, "diskutil erases or repartitions a disk");
+        }
+        "crontab" if args.contains(&"-r") => {
+            analysis.flag(SafetyLevel::Risky, "crontab -r removes every cron job");
+        }
+        "truncate" => analysis.flag(SafetyLevel::Caution, "truncate discards file contents"),
+        "eval" => analysis.flag(SafetyLevel::Caution, "eval runs dynamically built code"),
+        "terraform" | "tofu" if args.contains(&"destroy") => {
+            analysis.flag(SafetyLevel::Risky, format!("{program} destroy tears down infrastructure"));
+        }
+        "kubectl" if args.contains(&"delete") => {
+            analysis.flag(SafetyLevel::Caution, "kubectl delete removes cluster resources");
+        }
+        "docker" | "podman" if args.contains(&"prune") => {
+            analysis.flag(SafetyLevel::Caution, format!("{program} prune deletes unused data"));
+        }
+        // PowerShell names are case-insensitive; these only come up with `--shell pwsh`.
+        p if p.eq_ignore_ascii_case("Remove-Item") || p.eq_ignore_ascii_case("ri") => {
+            check_remove_item(&args, analysis)
+        }
+        p if ["Format-Volume", "Clear-Disk", "Initialize-Disk", "Remove-Partition"]
+            .iter()
+            .any(|c| p.eq_ignore_ascii_case(c)) =>
+        {
+            analysis.flag(SafetyLevel::Risky, format!("{program} can destroy disk data"));
+        }
+        p if ["Stop-Computer", "Restart-Computer"].iter().any(|c| p.eq_ignore_ascii_case(c)) => {
+            analysis.flag(SafetyLevel::Risky, format!("{program} stops or restarts the machine"));
+        }
+        shell if SHELLS.contains(&shell) => {
+            // `bash -c '...'`: analyse the payload as a command line of its own.
+            if let Some(pos) = args.iter().position(|a| *a == "-c") {
+                if let Some(payload) = args.get(pos + 1) {
+                    if depth >= MAX_NESTING {
+                        analysis.flag(SafetyLevel::Caution, "deeply nested shell invocation");
+                    } else if let Ok(script) = parse(payload) {
+                        check_script(&script, analysis, depth + 1);
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The program that actually runs once wrapper prefixes are stripped.
+struct Unwrapped {
+    program: Option<String>,
+    args: Vec<String>,
+    elevated_by: Option<String>,
+}
+
+/// Strips `sudo`, `env`, `xargs` and similar prefixes; `program` is a basename.
+fn unwrap_wrappers(argv: &[String]) -> Unwrapped {
+    let mut elevated_by = None;
+    let mut i = 0;
+    while i < argv.len() {
+        let name = basename(&argv[i]);
+        let takes_value: &[&str] = match name.as_str() {
+            "sudo" | "doas" => {
+                elevated_by = Some(name.clone());
+                &["-u", "-g", "-C", "-D", "-h", "-p", "-r", "-t", "-U"]
+            }
+            "env" => &["-u", "-C", "-S"],
+            "xargs" => &["-I", "-n", "-P", "-L", "-d", "-E", "-s", "-a"],
+            "nice" => &["-n"],
+            "ionice" => &["-c", "-n"],
+            "stdbuf" | "nohup" | "time" | "command" | "builtin" | "exec" => &[],
+            "timeout" => {
+                i += 1;
+                while i < argv.len() && argv[i].starts_with('-') {
+                    i += 1;
+                }
+                // Skip the duration operand.
+                i += 1;
+                continue;
+            }
+            // Reserved words that can precede a command inside compound statements.
+            "if" | "then" | "else" | "elif" | "do" | "while" | "until" | "!" => {
+                i += 1;
+                continue;
+            }
+            _ => break,
+        };
+        i += 1;
+        while i < argv.len() {
+            let arg = &argv[i];
+            if arg == "--" {
+                i += 1;
+                break;
+            }
+            if name == "env" && parser_is_assignment(arg) {
+                i += 1;
+            } else if arg.starts_with('-') && arg.len() > 1 {
+                i += if takes_value.contains(&arg.as_str()) { 2 } else { 1 };
+            } else {
+                break;
+            }
+        }
+    }
+    Unwrapped {
+        program: argv.get(i).map(|p| basename(p)),
+        args: argv.get(i + 1..).map(<[String]>::to_vec).unwrap_or_default(),
+        elevated_by,
+    }
+}
+
+fn check_rm(args: &[&str], analysis: &mut Analysis) {
+    let mut recursive = false;
+    let mut force = false;
+    let mut targets = Vec::new();
+    let mut options_done = false;
+    for arg in args {
+        if options_done || !arg.starts_with('-') || *arg == "-" {
+            targets.push(*arg);
+        } else if *arg == "--" {
+            options_done = true;
+        } else if let Some(long) = arg.strip_prefix("--") {
+            match long {
+                "recursive" => recursive = true,
+                "force" => force = true,
+                "no-preserve-root" => {
+                    analysis.flag(SafetyLevel::Risky, "rm --no-preserve-root disables root protection")
+                }
+                _ => {}
+            }
+        } else {
+            for flag in arg[1..].chars() {
+                match flag {
+                    'r' | 'R' => recursive = true,
+                    'f' => force = true,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    if let Some(target) = targets.iter().find(|t| is_critical_path(t)) {
+        let level = if recursive { SafetyLevel::Risky } else { SafetyLevel::Caution };
+        analysis.flag(level, format!("deletes {target}"));
+    }
+    if recursive && force {
+        analysis.flag(SafetyLevel::Risky, "recursive forced delete (rm -rf)");
+    } else if recursive {
+        analysis.flag(SafetyLevel::Caution, "recursive delete");
+    } else {
+        analysis.flag(SafetyLevel::Caution, "deletes files");
+    }
+}
+
+/// `Remove-Item`; PowerShell accepts any unambiguous prefix of a parameter,
+/// `-r` for `-Recurse` and `-fo` for `-Force` (`-f` could be `-Filter`).
+fn check_remove_item(args: &[&str], analysis: &mut Analysis) {
+    let given = |name: &str, shortest: usize| {
+        args.iter().any(|a| {
+            a.strip_prefix('-').is_some_and(|p| {
+                p.len() >= shortest && name.starts_with(&p.to_ascii_lowercase())
+            })
+        })
+    };
+    let recursive = given("recurse", 1);
+    let force = given("force", 2);
+    let critical = ["C:\\", "C:\\*", "$env:USERPROFILE", "$env:SystemRoot", "$HOME/*"];
+    let targets = args.iter().filter(|a| !a.starts_with('-'));
+    if let Some(target) = targets
+        .clone()
+        .find(|t| is_critical_path(t) || critical.iter().any(|c| t.eq_ignore_ascii_case(c)))
+    {
+        let level = if recursive { SafetyLevel::Risky } else { SafetyLevel::Caution };
+        analysis.flag(level, format!("deletes {target}"));
+    }
+    if recursive && force {
+        analysis.flag(SafetyLevel::Risky, "recursive forced delete (Remove-Item -Recurse -Force)");
+    } else if recursive {
+        analysis.flag(SafetyLevel::Caution, "recursive delete");
+    } else {
+        analysis.flag(SafetyLevel::Caution, "deletes files");
+    }
+}
+
+fn check_find(args: &[&str], analysis: &mut Analysis, depth: usize) {
+    if args.contains(&"-delete") {
+        analysis.flag(SafetyLevel::Risky, "find -delete removes every match");
+    }
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if matches!(*arg, "-exec" | "-execdir" | "-ok" | "-okdir") {
+            let inner: Vec<String> = iter
+                .by_ref()
+                .take_while(|a| **a != ";" && **a != "+")
+                .map(|a| a.to_string())
+                .collect();
+            check_argv(&inner, analysis, depth);
+        }
+    }
+}
+
+fn check_dd(args: &[&str], analysis: &mut Analysis) {
+    let Some(target) = args.iter().find_map(|a| a.strip_prefix("of=")) else {
+        return;
+    };
+    if is_block_device(target) {
+        analysis.flag(SafetyLevel::Risky, format!("dd overwrites block device {target}"));
+    } else {
+        analysis.flag(SafetyLevel::Caution, format!("dd overwrites {target}"));
+    }
+}
+
+fn check_permissions(program: &str, args: &[&str], analysis: &mut Analysis) {
+    let recursive = args
+        .iter()
+        .any(|a| *a == "--recursive" || (a.starts_with('-') && !a.starts_with("--") && a.contains('R')));
+    let operands: Vec<&str> = args.iter().copied().filter(|a| !a.starts_with('-')).collect();
+    if let Some(target) = operands.iter().skip(1).find(|t| is_critical_path(t)) {
+        let level = if recursive { SafetyLevel::Risky } else { SafetyLevel::Caution };
+        analysis.flag(level, format!("{program} changes ownership/permissions of {target}"));
+    }
+    if program == "chmod" {
+        if let Some(mode) = operands.first() {
+            if mode.ends_with("777") || mode.contains("o+w") || mode.contains("a+w") {
+                analysis.flag(SafetyLevel::Caution, "makes files world-writable");
+            }
+        }
+    }
+}
+
+fn check_mv(args: &[&str], analysis: &mut Analysis) {
+    let operands: Vec<&str> = args.iter().copied().filter(|a| !a.starts_with('-')).collect();
+    if operands.last() == Some(&"/dev/null") {
+        analysis.flag(SafetyLevel::Risky, "moving files to /dev/null destroys them");
+    }
+    if let Some(source) = operands.iter().rev().skip(1).find(|t| is_critical_path(t)) {
+        analysis.flag(SafetyLevel::Risky, format!("moves {source}"));
+    }
+}
+
+fn check_git(args: &[&str], analysis: &mut Analysis) {
+    match args.iter().find(|a| !a.starts_with('-')).copied() {
+        Some("push") if args.iter().any(|a| a.starts_with("--force") || *a == "-f") => {
+            analysis.flag(SafetyLevel::Caution, "force-push can overwrite remote history");
+        }
+        Some("reset") if args.contains(&"--hard") => {
+            analysis.flag(SafetyLevel::Caution, "git reset --hard discards uncommitted changes");
+        }
+        Some("clean") if args.iter().any(|a| a.starts_with('-') && a.contains('f')) => {
+            analysis.flag(SafetyLevel::Caution, "git clean deletes untracked files");
+        }
+        _ => {}
+    }
+}
+
+fn is_critical_path(target: &str) -> bool {
+    let trimmed = if target.len() > 1 {
+        target.trim_end_matches('/')
+    } else {
+        target
+    };
+    CRITICAL_PATHS.contains(&trimmed)
+        || CRITICAL_PATHS.contains(&trimmed.trim_end_matches("/*"))
+}
+
+fn is_block_device(target: &str) -> bool {
+    ["/dev/sd", "/dev/hd", "/dev/vd", "/dev/xvd", "/dev/nvme", "/dev/mmcblk", "/dev/disk", "/dev/rdisk", "/dev/mapper/"]
+        .iter()
+        .any(|prefix| target.starts_with(prefix))
+}
+
+fn basename(program: &str) -> String {
+    Path::new(program)
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| program.to_string())
+}
+
+fn parser_is_assignment(word: &str) -> bool {
+    word.split_once('=')
+        .is_some_and(|(name, _)| !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level(command: &str) -> SafetyLevel {
+        analyze(command, ShellKind::Bash).level
+    }
+
+    #[test]
+    fn catches_rm_flag_variants() {
+        assert_eq!(level("rm -rf build"), SafetyLevel::Risky);
+        assert_eq!(level("rm -fr build"), SafetyLevel::Risky);
+        assert_eq!(level("rm -r -f build"), SafetyLevel::Risky);
+        assert_eq!(level("sudo rm --recursive --force build"), SafetyLevel::Risky);
+        assert_eq!(level("rm -r /"), SafetyLevel::Risky);
+        assert_eq!(level("rm notes.txt"), SafetyLevel::Caution);
+    }
+
+    #[test]
+    fn catches_indirect_destruction() {
+        assert_eq!(level("find . -name '*.tmp' -delete"), SafetyLevel::Risky);
+        assert_eq!(level("find . -exec rm -rf {} +"), SafetyLevel::Risky);
+        assert_eq!(level("echo hi > /dev/sda"), SafetyLevel::Risky);
+        assert_eq!(level("chmod -R 777 /"), SafetyLevel::Risky);
+        assert_eq!(level("bash -c 'rm -rf ~'"), SafetyLevel::Risky);
+        assert_eq!(level("curl -fsSL https://x.sh | sh"), SafetyLevel::Risky);
+        assert_eq!(level("ls $(rm -rf /tmp/x)"), SafetyLevel::Risky);
+    }
+
+    #[test]
+    fn ignores_dangerous_text_in_arguments() {
+        assert_eq!(level("echo \"rm -rf\""), SafetyLevel::Safe);
+        assert_eq!(level("grep -r 'mkfs' docs/"), SafetyLevel::Safe);
+        assert_eq!(level("ls -la > /dev/null 2>&1"), SafetyLevel::Safe);
+    }
+
+    #[test]
+    fn reconcile_overrides_model_safe_label() {
+        let analysis = reconcile("safe", "rm -rf /", ShellKind::Bash);
+        assert_eq!(analysis.level, SafetyLevel::Risky);
+        assert!(!analysis.reasons.is_empty());
+
+        let analysis = reconcile("risky", "ls", ShellKind::Bash);
+        assert_eq!(analysis.level, SafetyLevel::Risky);
+    }
+
+    #[test]
+    fn nu_and_pwsh_commands_are_never_safe() {
+        let pwsh = |command| analyze(command, ShellKind::Pwsh).level;
+        assert_eq!(pwsh("Remove-Item -Recurse -Force ~"), SafetyLevel::Risky);
+        assert_eq!(pwsh("ri -r -fo 'C:\\'"), SafetyLevel::Risky);
+        assert_eq!(pwsh("Remove-Item notes.txt"), SafetyLevel::Caution);
+        assert_eq!(pwsh("Stop-Computer"), SafetyLevel::Risky);
+        assert_eq!(pwsh("Get-ChildItem | Sort-Object Length"), SafetyLevel::Caution);
+        let nu = |command| analyze(command, ShellKind::Nu).level;
+        assert_eq!(nu("rm -r ~/*"), SafetyLevel::Risky);
+        assert_eq!(nu("ls | where size > 10mb"), SafetyLevel::Caution);
+    }
+}
@@ -0,0 +1,715 @@
+use std::fmt;
+
+/// A parsed command line: pipelines joined by `;`, `&`, `&&`, `||` or newlines.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Script {
+    pub pipelines: Vec<Pipeline>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Pipeline {
+    pub commands: Vec<Command>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Simple(SimpleCommand),
+    /// `( ... )` or `{ ...; }`, with any redirections applied to the group.
+    Group(Script, Vec<Redirect>),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SimpleCommand {
+    /// Leading `NAME=value` words.
+    pub assignments: Vec<Word>,
+    pub words: Vec<Word>,
+    pub redirects: Vec<Redirect>,
+}
+
+impl SimpleCommand {
+    pub fn argv(&self) -> Vec<&str> {
+        self.words.iter().map(|w| w.value.as_str()).collect()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Word {
+    /// The word with quotes removed and escapes resolved. Expansions such as
+    /// `$HOME` or `$(cmd)` are kept as literal text.
+    pub value: String,
+    pub quoted: bool,
+    /// Parsed bodies of `$(...)`, backtick and `<(...)` substitutions.
+    pub substitutions: Vec<Script>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redirect {
+    pub fd: Option<u32>,
+    pub op: &'static str,
+    pub target: Word,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError(pub String);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+type ParseResult<T> = std::result::Result<T, ParseError>;
+
+/// Nesting limit for substitutions, so hostile input cannot blow the stack.
+const MAX_DEPTH: usize = 16;
+
+pub fn parse(input: &str) -> ParseResult<Script> {
+    parse_at_depth(input, 0)
+}
+
+fn parse_at_depth(input: &str, depth: usize) -> ParseResult<Script> {
+    if depth > MAX_DEPTH {
+        return Err(ParseError("command nests too deeply".to_string()));
+    }
+    let tokens = Lexer::new(input, depth).tokenize()?;
+    let mut parser = Parser { tokens, pos: 0 };
+    let script = parser.script()?;
+    match parser.peek() {
+        None => Ok(script),
+        Some(tok) => Err(ParseError(format!("unexpected `{}`", tok.describe()))),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(Word),
+    Op(&'static str),
+    Redirect(Option<u32>, &'static str),
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Token::Word(w) => w.value.clone(),
+            Token::Op(op) => op.to_string(),
+            Token::Redirect(_, op) => op.to_string(),
+        }
+    }
+}
+
+struct Lexer {
+    chars: Vec<char>,
+    pos: usize,
+    depth: usize,
+    /// Heredoc delimiters (and whether tabs are stripped) awaiting the next newline.
+    pending_heredocs: Vec<(String, bool)>,
+}
+
+impl Lexer {
+    fn new(input: &str, depth: usize) -> Self {
+        Self {
+            chars: input.chars().collect(),
+            pos: 0,
+            depth,
+            pending_heredocs: Vec::new(),
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn starts_with(&self, s: &str) -> bool {
+        s.chars().enumerate().all(|(i, c)| self.peek_at(i) == Some(c))
+    }
+
+    fn tokenize(mut self) -> ParseResult<Vec<Token>> {
+        let mut tokens = Vec::new();
+        loop {
+            while matches!(self.peek(), Some(' ' | '\t')) {
+                self.pos += 1;
+            }
+            let Some(c) = self.peek() else {
+                break;
+            };
+            if self.starts_with("\\\n") {
+                self.pos += 2;
+                continue;
+            }
+            if c == '#' {
+                while !matches!(self.peek(), None | Some('\n')) {
+                    self.pos += 1;
+                }
+                continue;
+            }
+            if c == '\n' {
+                self.pos += 1;
+                self.skip_heredoc_bodies()?;
+                tokens.push(Token::Op(";"));
+                continue;
+            }
+            // `!` negates a pipeline only at the start of a command; elsewhere
+            // (`[ ! -f x ]`) it is an ordinary word.
+            let at_command_start = matches!(tokens.last(), None | Some(Token::Op(_)));
+            if c == '!' && at_command_start && matches!(self.peek_at(1), None | Some(' ' | '\t')) {
+                self.pos += 1;
+                tokens.push(Token::Op("!"));
+                continue;
+            }
+            if let Some(op) = self.operator() {
+                tokens.push(Token::Op(op));
+                continue;
+            }
+            if (c == '<' || c == '>') && self.peek_at(1) == Some('(') {
+                tokens.push(Token::Word(self.word()?));
+                continue;
+            }
+            if let Some(op) = self.redirect_op() {
+                if op == "<<" || op == "<<-" {
+                    self.queue_heredoc(op == "<<-")?;
+                }
+                tokens.push(Token::Redirect(None, op));
+                continue;
+            }
+
+            let word = self.word()?;
+            let is_fd = !word.quoted
+                && !word.value.is_empty()
+                && word.value.chars().all(|c| c.is_ascii_digit())
+                && matches!(self.peek(), Some('<' | '>'))
+                && self.peek_at(1) != Some('(');
+            if is_fd {
+                let op = self
+                    .redirect_op()
+                    .ok_or_else(|| ParseError("bad redirection".to_string()))?;
+                if op == "<<" || op == "<<-" {
+                    self.queue_heredoc(op == "<<-")?;
+                }
+                tokens.push(Token::Redirect(word.value.parse().ok(), op));
+            } else {
+                tokens.push(Token::Word(word));
+            }
+        }
+        Ok(tokens)
+    }
+
+    fn operator(&mut self) -> Option<&'static str> {
+        const OPS: [&str; 9] = ["&&", "||", "|&", ";;", "|", "&", ";", "(", ")"];
+        // `&>` and `&>>` are redirections, not background + redirect.
+        if self.starts_with("&>") {
+            return None;
+        }
+        let op = OPS.into_iter().find(|op| self.starts_with(op))?;
+        self.pos += op.len();
+        // `;;` ends a `case` arm; for our purposes it is just a separator.
+        Some(if op == ";;" { ";" } else { op })
+    }
+
+    fn redirect_op(&mut self) -> Option<&'static str> {
+        // Longest operators first so `>>` is not read as `>`.
+        const OPS: [&str; 12] = [
+            "&>>", "<<<", "<<-", "&>", ">>", ">&", ">|", "<<", "<&", "<>", ">", "<",
+        ];
+        let op = OPS.into_iter().find(|op| self.starts_with(op))?;
+        self.pos += op.len();
+        Some(op)
+    }
+
+    fn queue_heredoc(&mut self, strip_tabs: bool) -> ParseResult<()> {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.pos += 1;
+        }
+        // Peek the delimiter without consuming it; it is also the redirect target.
+        let save = self.pos;
+        let delimiter = self.word()?.value;
+        self.pos = save;
+        self.pending_heredocs.push((delimiter, strip_tabs));
+        Ok(())
+    }
+
+    fn skip_heredoc_bodies(&mut self) -> ParseResult<()> {
+        for (delimiter, strip_tabs) in std::mem::take(&mut self.pending_heredocs) {
+            loop {
+                if self.peek().is_none() {
+                    return Err(ParseError(format!("unterminated heredoc `{delimiter}`")));
+                }
+                let start = self.pos;
+                while !matches!(self.peek(), None | Some('\n')) {
+                    self.pos += 1;
+                }
+                let line: String = self.chars[start..self.pos].iter().collect();
+                if self.peek() == Some('\n') {
+                    self.pos += 1;
+                }
+                let line = if strip_tabs { line.trim_start_matches('\t') } else { &line };
+                if line == delimiter {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn word(&mut self) -> ParseResult<Word> {
+        let mut word = Word::default();
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' | '\n' | '|' | '&' | ';' | ')' => break,
+                '(' if word.value.ends_with('=') && is_assignment(&word.value) => {
+                    // Array assignment `name=(a b c)`.
+                    let inner = self.balanced('(', ')')?;
+                    word.value.push_str(&format!("({inner})"));
+                }
+                '(' => break,
+                '<' | '>' => {
+                    if self.peek_at(1) != Some('(') {
+                        break;
+                    }
+                    // Process substitution `<(cmd)` / `>(cmd)`.
+                    self.pos += 1;
+                    let inner = self.balanced('(', ')')?;
+                    word.substitutions.push(parse_at_depth(&inner, self.depth + 1)?);
+                    word.value.push_str(&format!("{c}({inner})"));
+                }
+                '\'' => {
+                    self.pos += 1;
+                    word.quoted = true;
+                    loop {
+                        match self.peek() {
+                            None => return Err(ParseError("unterminated single quote".to_string())),
+                            Some('\'') => {
+                                self.pos += 1;
+                                break;
+                            }
+                            Some(ch) => {
+                                word.value.push(ch);
+                                self.pos += 1;
+                            }
+                        }
+                    }
+                }
+                '"' => {
+                    self.pos += 1;
+                    word.quoted = true;
+                    self.double_quoted(&mut word)?;
+                }
+                '\\' => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some('\n') => self.pos += 1,
+                        Some(ch) => {
+                            word.value.push(ch);
+                            self.pos += 1;
+                        }
+                        None => word.value.push('\\'),
+                    }
+                }
+                '$' => self.dollar(&mut word)?,
+                '`' => self.backtick(&mut word)?,
+                _ => {
+                    word.value.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+        Ok(word)
+    }
+
+    fn double_quoted(&mut self, word: &mut Word) -> ParseResult<()> {
+        loop {
+            match self.peek() {
+                None => return Err(ParseError("unterminated double quote".to_string())),
+                Some('"') => {
+                    self.pos += 1;
+                    return Ok(());
+                }
+                Some('\\') => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some(ch @ ('$' | '`' | '"' | '\\')) => {
+                            word.value.push(ch);
+                            self.pos += 1;
+                        }
+                        Some('\n') => self.pos += 1,
+                        _ => word.value.push('\\'),
+                    }
+                }
+                Some('$') => self.dollar(word)?,
+                Some('`') => self.backtick(word)?,
+                Some(ch) => {
+                    word.value.push(ch);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    fn dollar(&mut self, word: &mut Word) -> ParseResult<()> {
+        if self.starts_with("$((") {
+            self.pos += 1;
+            let inner = self.balanced('(', ')')?;
+            word.value.push_str(&format!("$({inner})"));
+        } else if self.starts_with("$(") {
+            self.pos += 1;
+            let inner = self.balanced('(', ')')?;
+            word.substitutions.push(parse_at_depth(&inner, self.depth + 1)?);
+            word.value.push_str(&format!("$({inner})"));
+        } else if self.starts_with("${") {
+            self.pos += 1;
+            let inner = self.balanced('{', '}')?;
+            word.value.push_str(&format!("${{{inner}}}"));
+        } else if self.starts_with("$'") {
+            // ANSI-C quoting: keep escapes as written, they rarely matter here.
+            self.pos += 2;
+            word.quoted = true;
+            loop {
+                match self.peek() {
+                    None => return Err(ParseError("unterminated $'...' quote".to_string())),
+                    Some('\'') => {
+                        self.pos += 1;
+                        break;
+                    }
+                    Some('\\') => {
+                        word.value.push('\\');
+                        self.pos += 1;
+                        if let Some(ch) = self.peek() {
+                            word.value.push(ch);
+                            self.pos += 1;
+                        }
+                    }
+                    Some(ch) => {
+                        word.value.push(ch);
+                        self.pos += 1;
+                    }
+                }
+            }
+        } else {
+            word.value.push('$');
+            self.pos += 1;
+        }
+        Ok(())
+    }
+
+    fn backtick(&mut self, word: &mut Word) -> ParseResult<()> {
+        self.pos += 1;
+        let mut inner = String::new();
+        loop {
+            match self.peek() {
+                None => return Err(ParseError("unterminated backtick".to_string())),
+                Some('`') => {
+                    self.pos += 1;
+                    break;
+                }
+                Some('\\') if matches!(self.peek_at(1), Some('`' | '\\' | '$')) => {
+                    inner.push(self.peek_at(1).unwrap_or_default());
+                    self.pos += 2;
+                }
+                Some(ch) => {
+                    inner.push(ch);
+                    self.pos += 1;
+                }
+            }
+        }
+        word.substitutions.push(parse_at_depth(&inner, self.depth + 1)?);
+        word.value.push_str(&format!("`{inner}`"));
+        Ok(())
+    }
+
+    /// Reads from an opening delimiter to its match, skipping quoted text,
+    /// and returns the text in between.
+    fn balanced(&mut self, open: char, close: char) -> ParseResult<String> {
+        debug_assert_eq!(self.peek(), Some(open));
+        self.pos += 1;
+        let start = self.pos;
+        let mut depth = 1usize;
+        while let Some(ch) = self.peek() {
+            match ch {
+                '\\' => self.pos += 1,
+                '\'' => {
+                    self.pos += 1;
+                    while !matches!(self.peek(), None | Some('\'')) {
+                        self.pos += 1;
+                    }
+                }
+                '"' => {
+                    self.pos += 1;
+                    while !matches!(self.peek(), None | Some('"')) {
+                        if self.peek() == Some('\\') {
+                            self.pos += 1;
+                        }
+                        self.pos += 1;
+                    }
+                }
+                c if c == open => depth += 1,
+                c if c == close => {
+                    depth -= 1;
+                    if depth == 0 {
+                        let inner = self.chars[start..self.pos].iter().collect();
+                        self.pos += 1;
+                        return Ok(inner);
+                    }
+                }
+                _ => {}
+            }
+            self.pos += 1;
+        }
+        Err(ParseError(format!("unterminated `{open}`")))
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.peek() {
+            Some(Token::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn peek_reserved(&self, name: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(w)) if !w.quoted && w.value == name)
+    }
+
+    fn script(&mut self) -> ParseResult<Script> {
+        let mut script = Script::default();
+        loop {
+            while matches!(self.peek_op(), Some(";" | "&")) {
+                self.pos += 1;
+            }
+            if self.peek().is_none() || self.peek_op() == Some(")") || self.peek_reserved("}") {
+                return Ok(script);
+            }
+            script.pipelines.push(self.pipeline()?);
+            match self.peek_op() {
+                Some(";" | "&" | "&&" | "||") => self.pos += 1,
+                _ => return Ok(script),
+            }
+        }
+    }
+
+    fn pipeline(&mut self) -> ParseResult<Pipeline> {
+        if self.peek_op() == Some("!") {
+            self.pos += 1;
+        }
+        let mut pipeline = Pipeline::default();
+        pipeline.commands.push(self.command()?);
+        while matches!(self.peek_op(), Some("|" | "|&")) {
+            self.pos += 1;
+            pipeline.commands.push(self.command()?);
+        }
+        Ok(pipeline)
+    }
+
+    fn command(&mut self) -> ParseResult<Command> {
+        if self.peek_op() == Some("(") {
+            self.pos += 1;
+            let inner = self.script()?;
+            if self.peek_op() != Some(")") {
+                return Err(ParseError("missing `)`".to_string()));
+            }
+            self.pos += 1;
+            return Ok(Command::Group(inner, self.redirects()?));
+        }
+        if self.peek_reserved("{") {
+            self.pos += 1;
+            let inner = self.script()?;
+            if !self.peek_reserved("}") {
+                return Err(ParseError("missing `}`".to_string()));
+            }
+            self.pos += 1;
+            return Ok(Command::Group(inner, self.redirects()?));
+        }
+        self.simple()
+    }
+
+    fn redirects(&mut self) -> ParseResult<Vec<Redirect>> {
+        let mut out = Vec::new();
+        while let Some(Token::Redirect(fd, op)) = self.peek().cloned() {
+            self.pos += 1;
+            out.push(Redirect {
+                fd,
+                op,
+                target: self.redirect_target(op)?,
+            });
+        }
+        Ok(out)
+    }
+
+    fn redirect_target(&mut self, op: &str) -> ParseResult<Word> {
+        match self.peek().cloned() {
+            Some(Token::Word(w)) => {
+                self.pos += 1;
+                Ok(w)
+            }
+            _ => Err(ParseError(format!("missing target after `{op}`"))),
+        }
+    }
+
+    fn simple(&mut self) -> ParseResult<Command> {
+        let mut cmd = SimpleCommand::default();
+        loop {
+            match self.peek().cloned() {
+                Some(Token::Word(w)) => {
+                    if cmd.words.is_empty() && !w.quoted && w.value == "}" {
+                        break;
+                    }
+                    self.pos += 1;
+                    if cmd.words.is_empty() && is_assignment(&w.value) {
+                        cmd.assignments.push(w);
+                    } else {
+                        cmd.words.push(w);
+                    }
+                }
+                Some(Token::Redirect(fd, op)) => {
+                    self.pos += 1;
+                    cmd.redirects.push(Redirect {
+                        fd,
+                        op,
+                        target: self.redirect_target(op)?,
+                    });
+                }
+                Some(Token::Op("(")) if cmd.words.len() == 1 => {
+                    // Function definition `name() { ...; }`: analyse the body.
+                    self.pos += 1;
+                    if self.peek_op() != Some(")") {
+                        return Err(ParseError("unexpected `(`".to_string()));
+                    }
+                    self.pos += 1;
+                    while self.peek_op() == Some(";") {
+                        self.pos += 1;
+                    }
+                    return self.command();
+                }
+                _ => break,
+            }
+        }
+        if cmd.words.is_empty() && cmd.assignments.is_empty() && cmd.redirects.is_empty() {
+            return Err(match self.peek() {
+                Some(tok) => ParseError(format!("unexpected `{}`", tok.describe())),
+                None => ParseError("expected a command".to_string()),
+            });
+        }
+        Ok(Command::Simple(cmd))
+    }
+}
+
+fn is_assignment(word: &str) -> bool {
+    match word.split_once('=') {
+        Some((name, _)) => {
+            !name.is_empty()
+                && !name.starts_with(|c: char| c.is_ascii_digit())
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn argvs(input: &str) -> Vec<Vec<String>> {
+        fn walk(script: &Script, out: &mut Vec<Vec<String>>) {
+            for command in script.pipelines.iter().flat_map(|p| &p.commands) {
+                match command {
+                    Command::Simple(cmd) => {
+                        out.push(cmd.argv().into_iter().map(str::to_string).collect());
+                        for word in &cmd.words {
+                            for sub in &word.substitutions {
+                                walk(sub, out);
+                            }
+                        }
+                    }
+                    Command::Group(inner, _) => walk(inner, out),
+                }
+            }
+        }
+        let mut out = Vec::new();
+        walk(&parse(input).expect("parse failed"), &mut out);
+        out
+    }
+
+    #[test]
+    fn parses_pipelines_lists_and_quotes() {
+        assert_eq!(
+            argvs("FOO=1 grep -r 'a b' \"$HOME/x\" | sort && echo done; ls"),
+            vec![
+                vec!["grep", "-r", "a b", "$HOME/x"],
+                vec!["sort"],
+                vec!["echo", "done"],
+                vec!["ls"],
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_redirections_with_fds() {
+        let script = parse("cmd 2>&1 >> out.log < in.txt &> /dev/null").expect("parse failed");
+        let Command::Simple(cmd) = &script.pipelines[0].commands[0] else {
+            panic!("expected simple command");
+        };
+        let ops: Vec<(Option<u32>, &str, &str)> = cmd
+            .redirects
+            .iter()
+            .map(|r| (r.fd, r.op, r.target.value.as_str()))
+            .collect();
+        assert_eq!(
+            ops,
+            vec![
+                (Some(2), ">&", "1"),
+                (None, ">>", "out.log"),
+                (None, "<", "in.txt"),
+                (None, "&>", "/dev/null"),
+            ]
+        );
+    }
+
+    #[test]
+    fn descends_into_subshells_and_substitutions() {
+        assert_eq!(
+            argvs("(cd /tmp && rm x) ; echo \"$(date +%s)\" `whoami`"),
+            vec![
+                vec!["cd", "/tmp"],
+                vec!["rm", "x"],
+                vec!["echo", "$(date +%s)", "`whoami`"],
+                vec!["date", "+%s"],
+                vec!["whoami"],
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_heredoc_bodies() {
+        assert_eq!(
+            argvs("cat <<EOF > out.txt\nrm -rf /\nEOF\necho ok"),
+            vec![vec!["cat"], vec!["echo", "ok"]]
+        );
+    }
+
+    #[test]
+    fn handles_negation_functions_and_arrays() {
+        assert_eq!(
+            argvs("! [ ! -f x ] && arr=(a b) f() { rm -r y; }"),
+            vec![vec!["[", "!", "-f", "x", "]"], vec!["rm", "-r", "y"]]
+        );
+    }
+
+    #[test]
+    fn reports_unterminated_quotes() {
+        assert!(parse("echo 'oops").is_err());
+        assert!(parse("echo \"oops").is_err());
+        assert!(parse("echo $(oops").is_err());
+    }
+}
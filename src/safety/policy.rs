@@ -0,0 +1,356 @@
+use super::parser::{parse, Command, Script, SimpleCommand};
+use super::{simple_argv, unwrap_wrappers, SafetyLevel, MAX_NESTING, SHELLS};
+use crate::shell_integration::ShellKind;
+use serde::{Deserialize, Serialize};
+
+/// What happens when the user tries to use a command at a given safety level.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LevelAction {
+    Run,
+    Confirm,
+    TypeName,
+    Block,
+}
+
+impl LevelAction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LevelAction::Run => "run",
+            LevelAction::Confirm => "confirm",
+            LevelAction::TypeName => "type_name",
+            LevelAction::Block => "block",
+        }
+    }
+}
+
+/// Rules loaded from `policy.toml` or the `[policy]` section of `config.toml`.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct Policy {
+    /// Glob patterns (`*`, `?`) matched against the whole line and each
+    /// command it runs, with `sudo`/`env`-style wrappers stripped and
+    /// `sh -c` payloads and `find -exec` commands included.
+    pub deny: Vec<String>,
+    /// When non-empty, every program in the line must be listed here. nu and
+    /// pwsh lines can't be checked reliably, so they are blocked.
+    pub allow_only: Vec<String>,
+    pub safe: LevelAction,
+    pub caution: LevelAction,
+    pub risky: LevelAction,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            deny: Vec::new(),
+            allow_only: Vec::new(),
+            safe: LevelAction::Run,
+            caution: LevelAction::Run,
+            risky: LevelAction::Run,
+        }
+    }
+}
+
+/// Outcome of checking a command against the policy; `rule` names what fired.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    Confirm { rule: String },
+    TypeName { program: String, rule: String },
+    Block { rule: String },
+}
+
+impl Policy {
+    /// `shell` is the dialect `command` is written in; deny patterns still
+    /// apply to nu and pwsh lines as text and as read by the bash parser.
+    pub fn check(&self, command: &str, level: SafetyLevel, shell: ShellKind) -> Verdict {
+        let script = parse(command).ok();
+        let mut runs = Runs {
+            texts: vec![command.trim().to_string()],
+            programs: Vec::new(),
+        };
+        if let Some(script) = &script {
+            runs.add_script(script, 0);
+        }
+
+        for pattern in &self.deny {
+            if runs.texts.iter().any(|text| glob_match(pattern, text)) {
+                return Verdict::Block {
+                    rule: format!("matches deny pattern `{pattern}`"),
+                };
+            }
+        }
+
+        let programs = runs.programs;
+        if !self.allow_only.is_empty() {
+            if !shell.reads_like_bash() {
+                return Verdict::Block {
+                    rule: format!(
+                        "allow_only is set and {} commands can't be checked",
+                        shell.as_str()
+                    ),
+                };
+            }
+            if script.is_none() {
+                return Verdict::Block {
+                    rule: "allow_only is set and the command could not be parsed".to_string(),
+                };
+            }
+            if let Some(program) = programs.iter().find(|p| !self.allow_only.contains(p)) {
+                return Verdict::Block {
+                    rule: format!("`{program}` is not in allow_only"),
+                };
+            }
+        }
+
+        let (action, key) = match level {
+            SafetyLevel::Safe => (self.safe, "safe"),
+            SafetyLevel::Caution => (self.caution, "caution"),
+            SafetyLevel::Risky => (self.risky, "risky"),
+        };
+        let rule = format!("{key} = \"{}\"", action.as_str());
+        match action {
+            LevelAction::Run => Verdict::Allow,
+            LevelAction::Confirm => Verdict::Confirm { rule },
+            LevelAction::TypeName => match programs.into_iter().next() {
+                Some(program) => Verdict::TypeName { program, rule },
+                None => Verdict::Confirm { rule },
+            },
+            LevelAction::Block => Verdict::Block { rule },
+        }
+    }
+}
+
+/// Every simple command in the script, including those in groups and in
+/// substitutions, redirect targets' included.
+fn simple_commands(script: &Script) -> Vec<&SimpleCommand> {
+    let mut out = Vec::new();
+    for command in script.pipelines.iter().flat_map(|p| &p.commands) {
+        match command {
+            Command::Simple(simple) => {
+                out.push(simple);
+                let words = simple.words.iter().chain(&simple.assignments);
+                for word in words.chain(simple.redirects.iter().map(|r| &r.target)) {
+                    for sub in &word.substitutions {
+                        out.extend(simple_commands(sub));
+                    }
+                }
+            }
+            Command::Group(inner, redirects) => {
+                out.extend(simple_commands(inner));
+                for sub in redirects.iter().flat_map(|r| &r.target.substitutions) {
+                    out.extend(simple_commands(sub));
+                }
+            }
+        }
+    }
+    out
+}
+
+/// What a command line would run, seen through wrappers and nested shells.
+struct Runs {
+    /// Text deny patterns are matched against: the line, `sh -c` payloads,
+    /// and each command both as written and unwrapped.
+    texts: Vec<String>,
+    /// Programs that would run, each followed by `sudo`/`doas` when they
+    /// wrap it.
+    programs: Vec<String>,
+}
+
+impl Runs {
+    fn add_script(&mut self, script: &Script, depth: usize) {
+        for cmd in simple_commands(script) {
+            self.add_argv(simple_argv(cmd), depth);
+        }
+    }
+
+    /// Mirrors the nesting `analyze` follows: `sh -c '...'` payloads and
+    /// `find -exec ... ;` commands; `xargs` is stripped as a wrapper.
+    fn add_argv(&mut self, argv: Vec<String>, depth: usize) {
+        let unwrapped = unwrap_wrappers(&argv);
+        self.push_text(argv.join(" "));
+        let Some(program) = unwrapped.program else {
+            return;
+        };
+        let args = unwrapped.args;
+        self.push_text(format!("{program} {}", args.join(" ")).trim_end().to_string());
+        for program in std::iter::once(program.clone()).chain(unwrapped.elevated_by) {
+            if !self.programs.contains(&program) {
+                self.programs.push(program);
+            }
+        }
+        if depth >= MAX_NESTING {
+            return;
+        }
+
+        if SHELLS.contains(&program.as_str()) {
+            let payload = args.iter().position(|a| a == "-c").and_then(|pos| args.get(pos + 1));
+            if let Some(payload) = payload {
+                self.push_text(payload.trim().to_string());
+                if let Ok(script) = parse(payload) {
+                    self.add_script(&script, depth + 1);
+                }
+            }
+        } else if program == "find" {
+            let mut args = args.iter();
+            while let Some(arg) = args.next() {
+                if matches!(arg.as_str(), "-exec" | "-execdir" | "-ok" | "-okdir") {
+                    let inner = args
+                        .by_ref()
+                        .take_while(|a| *a != ";" && *a != "+")
+                        .cloned()
+                        .collect();
+                    self.add_argv(inner, depth + 1);
+                }
+            }
+        }
+    }
+
+    fn push_text(&mut self, text: String) {
+        if !self.texts.contains(&text) {
+            self.texts.push(text);
+        }
+    }
+}
+
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = text.chars().collect();
+    let (mut pi, mut ti) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            backtrack = Some((pi, ti));
+            pi += 1;
+        } else if let Some((star, matched)) = backtrack {
+            pi = star + 1;
+            ti = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deny_patterns_match_commands_not_quoted_text() {
+        let policy = Policy {
+            deny: vec!["git push *--force*".to_string()],
+            ..Policy::default()
+        };
+        assert_eq!(
+            policy.check(
+                "git fetch && git push origin main --force",
+                SafetyLevel::Caution,
+                ShellKind::Bash
+            ),
+            Verdict::Block {
+                rule: "matches deny pattern `git push *--force*`".to_string()
+            }
+        );
+        assert_eq!(
+            policy.check("echo 'git push origin --force'", SafetyLevel::Safe, ShellKind::Bash),
+            Verdict::Allow
+        );
+    }
+
+    #[test]
+    fn deny_patterns_see_through_wrappers_and_nested_shells() {
+        let policy = Policy {
+            deny: vec!["rm -rf *".to_string()],
+            ..Policy::default()
+        };
+        for command in [
+            "rm -rf /",
+            "sudo rm -rf /",
+            "env X=1 rm -rf /",
+            "X=1 nice -n 5 /bin/rm -rf /",
+            "bash -c 'rm -rf /'",
+            "sh -c \"cd / && sudo rm -rf *\"",
+            "find / -exec rm -rf {} +",
+            "find / -name x -exec sudo rm -rf {} \\;",
+            "find / -print0 | xargs -0 rm -rf --",
+            "echo $(bash -c 'rm -rf ~')",
+            "ls > \"$(rm -rf ~)\"",
+            "{ ls; } 2> \"$(sudo rm -rf /)\"",
+        ] {
+            assert!(
+                matches!(
+                    policy.check(command, SafetyLevel::Risky, ShellKind::Bash),
+                    Verdict::Block { .. }
+                ),
+                "{command} should be blocked"
+            );
+        }
+        assert_eq!(
+            policy.check("echo 'rm -rf /'", SafetyLevel::Safe, ShellKind::Bash),
+            Verdict::Allow
+        );
+    }
+
+    #[test]
+    fn allow_only_checks_every_program() {
+        let policy = Policy {
+            allow_only: vec!["ls".to_string(), "grep".to_string()],
+            ..Policy::default()
+        };
+        assert_eq!(
+            policy.check("ls -la | grep foo", SafetyLevel::Safe, ShellKind::Bash),
+            Verdict::Allow
+        );
+        assert_eq!(
+            policy.check("ls | sudo grep x /root/log", SafetyLevel::Caution, ShellKind::Bash),
+            Verdict::Block {
+                rule: "`sudo` is not in allow_only".to_string()
+            }
+        );
+        let policy = Policy {
+            allow_only: vec!["ls".to_string(), "bash".to_string(), "find".to_string()],
+            ..Policy::default()
+        };
+        assert!(matches!(
+            policy.check("ls", SafetyLevel::Safe, ShellKind::Pwsh),
+            Verdict::Block { .. }
+        ));
+        for command in [
+            "bash -c 'ls; rm x'",
+            "find . -exec rm {} ;",
+            "ls > \"$(rm -rf ~)\"",
+            "{ ls; } > \"$(rm -rf ~)\"",
+        ] {
+            assert_eq!(
+                policy.check(command, SafetyLevel::Safe, ShellKind::Bash),
+                Verdict::Block {
+                    rule: "`rm` is not in allow_only".to_string()
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn level_actions_apply_after_rules() {
+        let toml = "risky = \"type_name\"\ncaution = \"block\"\n";
+        let policy: Policy = toml::from_str(toml).expect("policy should parse");
+        assert_eq!(policy.safe, LevelAction::Run);
+        assert_eq!(
+            policy.check("sudo rm -rf build", SafetyLevel::Risky, ShellKind::Bash),
+            Verdict::TypeName {
+                program: "rm".to_string(),
+                rule: "risky = \"type_name\"".to_string()
+            }
+        );
+        assert!(matches!(
+            policy.check("git reset --hard", SafetyLevel::Caution, ShellKind::Bash),
+            Verdict::Block { .. }
+        ));
+    }
+}
@@ -1,34 +1,121 @@
 use crate::error::{AppError, Result};
+use serde::{Deserialize, Serialize};
+use std::env;
 use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
 
 const BEGIN_MARKER: &str = "# >>> baishify integration >>>";
 const END_MARKER: &str = "# <<< baishify integration <<<";
+/// First line inside the block, so `b init --status` can tell which release
+/// wrote it.
+const VERSION_PREFIX: &str = "# baishify ";
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
 pub enum ShellKind {
+    #[default]
     Bash,
     Zsh,
+    Fish,
+    Nu,
+    Pwsh,
 }
 
 impl ShellKind {
+    pub const ALL: [ShellKind; 5] = [
+        ShellKind::Bash,
+        ShellKind::Zsh,
+        ShellKind::Fish,
+        ShellKind::Nu,
+        ShellKind::Pwsh,
+    ];
+
     pub fn as_str(self) -> &'static str {
         match self {
             ShellKind::Bash => "bash",
             ShellKind::Zsh => "zsh",
+            ShellKind::Fish => "fish",
+            ShellKind::Nu => "nu",
+            ShellKind::Pwsh => "pwsh",
         }
     }
 
-    fn rc_filename(self) -> &'static str {
+    /// Whether generated commands must be written in this shell's own
+    /// syntax; bash and zsh both run bash-style commands.
+    pub fn has_own_dialect(self) -> bool {
+        matches!(self, ShellKind::Fish | ShellKind::Nu | ShellKind::Pwsh)
+    }
+
+    /// Whether simple commands split into words as in bash, so the safety
+    /// rules read them as written. fish's substitutions and blocks don't
+    /// parse as bash and are flagged for review like any parse failure.
+    pub fn reads_like_bash(self) -> bool {
+        matches!(self, ShellKind::Bash | ShellKind::Zsh | ShellKind::Fish)
+    }
+
+    /// The file `b init` writes to when no `--rc-file` is given.
+    pub fn rc_path(self) -> Result<PathBuf> {
+        let home =
+            dirs::home_dir().ok_or_else(|| AppError::from("unable to locate home directory"))?;
+        Ok(self.rc_path_in(&home))
+    }
+
+    fn rc_path_in(self, home: &Path) -> PathBuf {
         match self {
-            ShellKind::Bash => ".bashrc",
-            ShellKind::Zsh => ".zshrc",
+            ShellKind::Bash => bash_rc(home),
+            ShellKind::Zsh => env::var_os("ZDOTDIR")
+                .filter(|dir| !dir.is_empty())
+                .map_or_else(|| home.to_path_buf(), PathBuf::from)
+                .join(".zshrc"),
+            // fish sources every file in conf.d, so the integration gets a
+            // file of its own instead of a block in config.fish.
+            ShellKind::Fish => xdg_config_home(home).join("fish/conf.d/baishify.fish"),
+            // nu honours `$XDG_CONFIG_HOME` when set and otherwise uses the
+            // same lookup as `dirs`, which is Application Support on macOS.
+            ShellKind::Nu => env::var_os("XDG_CONFIG_HOME")
+                .filter(|dir| !dir.is_empty())
+                .map(PathBuf::from)
+                .or_else(dirs::config_dir)
+                .unwrap_or_else(|| xdg_config_home(home))
+                .join("nushell/config.nu"),
+            ShellKind::Pwsh => {
+                xdg_config_home(home).join("powershell/Microsoft.PowerShell_profile.ps1")
+            }
         }
     }
 
     fn wrapper_block(self) -> String {
         let body = match self {
-            ShellKind::Bash => r#"b() {
+            ShellKind::Bash => r#"__b_precmd() {
+  local __b_status=$? __b_line __b_num
+  __b_line="$(HISTTIMEFORMAT= builtin history 1)"
+  [[ $__b_line =~ ^[[:space:]]*([0-9]+)[[:space:]]+(.*)$ ]] || return $__b_status
+  __b_num="${BASH_REMATCH[1]}"
+  __b_line="${BASH_REMATCH[2]}"
+  [[ "$__b_num" == "$__b_last_num" ]] && return $__b_status
+  __b_last_num="$__b_num"
+  case "$__b_line" in
+    b|"b "*) ;;
+    *)
+      __b_last_cmd="$__b_line"
+      __b_last_status=$__b_status
+      ;;
+  esac
+  if [[ -n "$__b_stderr_file" ]]; then
+    cp "$__b_stderr_file" "$__b_stderr_file.last" 2>/dev/null
+    : > "$__b_stderr_file"
+  fi
+  return $__b_status
+}
+if [[ -n "$BAISHIFY_CAPTURE_STDERR" && -z "$__b_stderr_file" ]]; then
+  __b_stderr_file="$(mktemp)"
+  exec 2> >(tee -a "$__b_stderr_file" >&2)
+fi
+if [[ "$PROMPT_COMMAND" != *__b_precmd* ]]; then
+  PROMPT_COMMAND="__b_precmd${PROMPT_COMMAND:+;$PROMPT_COMMAND}"
+fi
+b() {
   if [[ ! -t 0 || ! -t 1 ]]; then
     command b "$@"
     return $?
@@ -41,6 +128,17 @@ impl ShellKind {
         ;;
     esac
   done
+  if [[ "$1" == "explain" || "$1" == "history" || "$1" == "cache" || "$1" == "usage" ]]; then
+    command b "$@"
+    return $?
+  fi
+  if [[ "$1" == "fix" ]]; then
+    shift
+    set -- fix --last-command "$__b_last_cmd" --last-status "$__b_last_status" "$@"
+    if [[ -n "$__b_stderr_file" && -s "$__b_stderr_file.last" ]]; then
+      set -- "$@" --stderr-file "$__b_stderr_file.last"
+    fi
+  fi
   local __b_tmp
   __b_tmp="$(mktemp)" || return 1
   command b --output-file "$__b_tmp" "$@" || {
@@ -55,8 +153,53 @@ impl ShellKind {
   printf '%s\n' "$cmd"
   history -s "$cmd"
   eval "$cmd"
-}"#,
-            ShellKind::Zsh => r#"b() {
+  __b_last_status=$?
+  __b_last_cmd="$cmd"
+  return $__b_last_status
+}
+__b_readline() {
+  [[ -z "$READLINE_LINE" ]] && return
+  local __b_tmp cmd
+  __b_tmp="$(mktemp)" || return
+  command b --output-file "$__b_tmp" "$READLINE_LINE" </dev/tty
+  cmd="$(cat "$__b_tmp")"
+  rm -f "$__b_tmp"
+  if [[ -n "$cmd" ]]; then
+    READLINE_LINE="$cmd"
+    READLINE_POINT=${#READLINE_LINE}
+  fi
+}
+if [[ $- == *i* ]]; then
+  bind -x '"\C-g": __b_readline'
+fi"#,
+            ShellKind::Zsh => r#"__b_preexec() {
+  __b_pending_cmd="$1"
+}
+__b_precmd() {
+  local __b_status=$?
+  [[ -z "$__b_pending_cmd" ]] && return $__b_status
+  case "$__b_pending_cmd" in
+    b|"b "*) ;;
+    *)
+      __b_last_cmd="$__b_pending_cmd"
+      __b_last_status=$__b_status
+      ;;
+  esac
+  __b_pending_cmd=""
+  if [[ -n "$__b_stderr_file" ]]; then
+    cp "$__b_stderr_file" "$__b_stderr_file.last" 2>/dev/null
+    : > "$__b_stderr_file"
+  fi
+  return $__b_status
+}
+if [[ -n "$BAISHIFY_CAPTURE_STDERR" && -z "$__b_stderr_file" ]]; then
+  __b_stderr_file="$(mktemp)"
+  exec 2> >(tee -a "$__b_stderr_file" >&2)
+fi
+autoload -Uz add-zsh-hook
+add-zsh-hook preexec __b_preexec
+precmd_functions=(__b_precmd ${precmd_functions:#__b_precmd})
+b() {
   if [[ ! -t 0 || ! -t 1 ]]; then
     command b "$@"
     return $?
@@ -69,6 +212,17 @@ impl ShellKind {
         ;;
     esac
   done
+  if [[ "$1" == "explain" || "$1" == "history" || "$1" == "cache" || "$1" == "usage" ]]; then
+    command b "$@"
+    return $?
+  fi
+  if [[ "$1" == "fix" ]]; then
+    shift
+    set -- fix --last-command "$__b_last_cmd" --last-status "$__b_last_status" "$@"
+    if [[ -n "$__b_stderr_file" && -s "$__b_stderr_file.last" ]]; then
+      set -- "$@" --stderr-file "$__b_stderr_file.last"
+    fi
+  fi
   local __b_tmp
   __b_tmp="$(mktemp)" || return 1
   command b --output-file "$__b_tmp" "$@" || {
@@ -83,9 +237,138 @@ impl ShellKind {
   printf '%s\n' "$cmd"
   print -s -- "$cmd"
   eval "$cmd"
+  __b_last_status=$?
+  __b_last_cmd="$cmd"
+  return $__b_last_status
+}
+__b_widget() {
+  [[ -z "$BUFFER" ]] && return
+  local __b_tmp cmd
+  __b_tmp="$(mktemp)" || return 1
+  zle -I
+  command b --output-file "$__b_tmp" "$BUFFER" </dev/tty
+  cmd="$(<"$__b_tmp")"
+  rm -f "$__b_tmp"
+  if [[ -n "$cmd" ]]; then
+    BUFFER="$cmd"
+    CURSOR=${#BUFFER}
+  fi
+  zle reset-prompt
+}
+zle -N __b_widget
+bindkey '^G' __b_widget"#,
+            // No BAISHIFY_CAPTURE_STDERR here: fish can't tee its own stderr.
+            ShellKind::Fish => r#"function __b_postexec --on-event fish_postexec
+  set -l __b_status $status
+  test -z "$argv[1]"; and return
+  switch $argv[1]
+    case b 'b *'
+    case '*'
+      set -g __b_last_cmd $argv[1]
+      set -g __b_last_status $__b_status
+  end
+end
+function b
+  if not isatty stdin; or not isatty stdout
+    command b $argv
+    return $status
+  end
+  for arg in $argv
+    switch $arg
+      case setup init -h --help --json --plain
+        command b $argv
+        return $status
+    end
+  end
+  switch "$argv[1]"
+    case explain history cache usage
+      command b $argv
+      return $status
+    case fix
+      set argv fix --last-command "$__b_last_cmd" --last-status "$__b_last_status" $argv[2..-1]
+  end
+  set -l __b_tmp (mktemp); or return 1
+  command b --shell fish --output-file $__b_tmp $argv
+  or begin
+    set -l __b_status $status
+    rm -f $__b_tmp
+    return $__b_status
+  end
+  set -l cmd (string collect < $__b_tmp)
+  rm -f $__b_tmp
+  test -z "$cmd"; and return 1
+  printf '%s\n' $cmd
+  history append -- $cmd
+  eval $cmd
+  set -g __b_last_status $status
+  set -g __b_last_cmd $cmd
+  return $__b_last_status
+end"#,
+            // nu can't evaluate a string in the caller's scope, so the
+            // command is left on the prompt to run (and enter history) with
+            // Enter.
+            ShellKind::Nu => r#"def --wrapped b [...args] {
+  let last_status = $env.LAST_EXIT_CODE
+  let passthrough = ['setup' 'init' '-h' '--help' '--json' '--plain']
+  if ($args | any {|arg| $arg in $passthrough }) or ($args.0? in ['explain' 'history' 'cache' 'usage']) {
+    ^b ...$args
+    return
+  }
+  let args = if $args.0? == 'fix' {
+    let last = (history | last 100 | get command | where {|c| $c !~ '^b( |$)' } | last 1)
+    ['fix' '--last-command' ($last.0? | default '') '--last-status' ($last_status | into string)
+      ...($args | skip 1)]
+  } else {
+    $args
+  }
+  let tmp = (mktemp -t)
+  try { ^b --shell nu --output-file $tmp ...$args } catch { rm -f $tmp; return }
+  let cmd = (open --raw $tmp | str trim --right)
+  rm -f $tmp
+  if ($cmd | is-empty) { return }
+  commandline edit --replace $cmd
+}"#,
+            ShellKind::Pwsh => r#"function b {
+  $lastStatus = $global:LASTEXITCODE
+  $native = Get-Command -Name b -CommandType Application -ErrorAction SilentlyContinue |
+    Select-Object -First 1
+  if (-not $native) {
+    Write-Error 'b: executable not found on PATH'
+    return
+  }
+  $passthrough = @('setup', 'init', '-h', '--help', '--json', '--plain')
+  if ([Console]::IsInputRedirected -or [Console]::IsOutputRedirected -or
+      ($args | Where-Object { $passthrough -contains $_ }) -or
+      ($args.Count -gt 0 -and @('explain', 'history', 'cache', 'usage') -contains $args[0])) {
+    & $native @args
+    return
+  }
+  $rest = @($args)
+  if ($args.Count -gt 0 -and $args[0] -eq 'fix') {
+    $last = Get-History -Count 100 | Where-Object { $_.CommandLine -notmatch '^b(\s|$)' } |
+      Select-Object -Last 1
+    $rest = @('fix', '--last-command', "$($last.CommandLine)", '--last-status', "$lastStatus") +
+      @($args | Select-Object -Skip 1)
+  }
+  $tmp = New-TemporaryFile
+  try {
+    & $native --shell pwsh --output-file $tmp.FullName @rest
+    if ($LASTEXITCODE -ne 0) { return }
+    $cmd = Get-Content -Raw -LiteralPath $tmp.FullName
+  } finally {
+    Remove-Item -LiteralPath $tmp.FullName -ErrorAction SilentlyContinue
+  }
+  if ([string]::IsNullOrWhiteSpace($cmd)) { return }
+  $cmd = $cmd.TrimEnd()
+  Write-Host $cmd
+  if (Get-Module PSReadLine) {
+    [Microsoft.PowerShell.PSConsoleReadLine]::AddToHistory($cmd)
+  }
+  Invoke-Expression $cmd
 }"#,
         };
-        format!("{BEGIN_MARKER}\n{body}\n{END_MARKER}\n")
+        let version = env!("CARGO_PKG_VERSION");
+        format!("{BEGIN_MARKER}\n{VERSION_PREFIX}{version}\n{body}\n{END_MARKER}\n")
     }
 }
 
@@ -93,6 +376,33 @@ pub struct InstallResult {
     pub shell: ShellKind,
     pub rc_path: PathBuf,
     pub updated: bool,
+    /// The file as it was before the update, if there was one.
+    pub backup: Option<PathBuf>,
+}
+
+pub struct UninstallResult {
+    pub shell: ShellKind,
+    pub rc_path: PathBuf,
+    /// The file as it was before the block came out; `None` when there
+    /// was no block to remove.
+    pub backup: Option<PathBuf>,
+}
+
+/// What `b init --status` found in one shell's rc file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockStatus {
+    NotInstalled,
+    Current,
+    /// Written by another release; `None` when it predates version stamps.
+    Outdated(Option<String>),
+    /// This release's version, but edited by hand.
+    Modified,
+}
+
+pub struct ShellStatus {
+    pub shell: ShellKind,
+    pub rc_path: PathBuf,
+    pub status: BlockStatus,
 }
 
 pub fn detect_shell_from_env() -> Option<ShellKind> {
@@ -101,6 +411,9 @@ pub fn detect_shell_from_env() -> Option<ShellKind> {
     match name.as_ref() {
         "zsh" => Some(ShellKind::Zsh),
         "bash" => Some(ShellKind::Bash),
+        "fish" => Some(ShellKind::Fish),
+        "nu" => Some(ShellKind::Nu),
+        "pwsh" => Some(ShellKind::Pwsh),
         _ => None,
     }
 }
@@ -109,50 +422,207 @@ pub fn parse_shell_name(input: &str) -> Option<ShellKind> {
     match input.trim().to_ascii_lowercase().as_str() {
         "zsh" => Some(ShellKind::Zsh),
         "bash" => Some(ShellKind::Bash),
+        "fish" => Some(ShellKind::Fish),
+        "nu" | "nushell" => Some(ShellKind::Nu),
+        "pwsh" | "powershell" => Some(ShellKind::Pwsh),
         _ => None,
     }
 }
 
-pub fn install(shell: ShellKind) -> Result<InstallResult> {
-    let home =
-        dirs::home_dir().ok_or_else(|| AppError::from("unable to locate home directory"))?;
-    let rc_path = home.join(shell.rc_filename());
-    let block = shell.wrapper_block();
+/// `$XDG_CONFIG_HOME`, or `~/.config` on every platform, as fish and
+/// PowerShell resolve it.
+fn xdg_config_home(home: &Path) -> PathBuf {
+    env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| home.join(".config"))
+}
 
-    let existing = fs::read_to_string(&rc_path).unwrap_or_default();
-    let (new_content, updated) = upsert_block(&existing, &block);
-    if updated {
-        fs::write(&rc_path, new_content)?;
+/// The rc file bash reads in this setup. `.bashrc` unless a login profile
+/// exists that doesn't source it and login shells are the norm (macOS), or
+/// there is no `.bashrc` at all. A file already holding the block wins.
+fn bash_rc(home: &Path) -> PathBuf {
+    let bashrc = home.join(".bashrc");
+    let candidates = [bashrc.clone(), home.join(".bash_profile"), home.join(".bash_login")];
+    if let Some(installed) = candidates.iter().find(|path| {
+        fs::read_to_string(path).is_ok_and(|content| content.contains(BEGIN_MARKER))
+    }) {
+        return installed.clone();
     }
+    // bash only reads the first login file that exists; `.profile` is
+    // skipped because sh and dash read it too.
+    let Some(login) = candidates[1..].iter().find(|path| path.exists()) else {
+        return bashrc;
+    };
+    let sources_bashrc =
+        fs::read_to_string(login).is_ok_and(|content| content.contains(".bashrc"));
+    if !sources_bashrc && (!bashrc.exists() || cfg!(target_os = "macos")) {
+        return login.clone();
+    }
+    bashrc
+}
 
+/// Adds or refreshes the block in `rc_path`, backing up the old file first.
+pub fn install(shell: ShellKind, rc_path: PathBuf) -> Result<InstallResult> {
+    let block = shell.wrapper_block();
+    let existing = read_rc(&rc_path)?;
+    let (new_content, updated) = upsert_block(&existing, &block);
+    let backup = if updated { rewrite(&rc_path, &new_content)? } else { None };
     Ok(InstallResult {
         shell,
         rc_path,
         updated,
+        backup,
+    })
+}
+
+/// Removes the block from `rc_path`, keeping a backup of the previous
+/// contents. A regular file left empty is deleted.
+pub fn uninstall(shell: ShellKind, rc_path: PathBuf) -> Result<UninstallResult> {
+    let existing = read_rc(&rc_path)?;
+    let (new_content, removed) = remove_block(&existing);
+    let mut backup = None;
+    if removed {
+        let is_link = fs::symlink_metadata(&rc_path).is_ok_and(|m| m.file_type().is_symlink());
+        if new_content.trim().is_empty() && !is_link {
+            backup = Some(back_up(&rc_path)?);
+            fs::remove_file(&rc_path)?;
+        } else {
+            backup = rewrite(&rc_path, &new_content)?;
+        }
+    }
+    Ok(UninstallResult {
+        shell,
+        rc_path,
+        backup,
+    })
+}
+
+/// The state of the integration block in `rc_path`.
+pub fn status(shell: ShellKind, rc_path: PathBuf) -> Result<ShellStatus> {
+    let existing = read_rc(&rc_path)?;
+    Ok(ShellStatus {
+        shell,
+        status: block_status(&existing, &shell.wrapper_block()),
+        rc_path,
     })
 }
 
+/// The rc file's contents, empty when it doesn't exist yet. Any other read
+/// error is returned, so an unreadable file is never rewritten from scratch.
+fn read_rc(path: &Path) -> Result<String> {
+    match fs::read_to_string(path) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+        result => Ok(result?),
+    }
+}
+
+/// Writes `content` to the file `path` points at, following symlinks so a
+/// dotfiles repo keeps its link, after backing up what was there.
+fn rewrite(path: &Path, content: &str) -> Result<Option<PathBuf>> {
+    let target = link_target(path);
+    let backup = if target.exists() { Some(back_up(path)?) } else { None };
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&target, content)?;
+    Ok(backup)
+}
+
+/// Where a chain of symlinks ends, even if the final file doesn't exist yet.
+fn link_target(path: &Path) -> PathBuf {
+    let mut path = path.to_path_buf();
+    // Same limit as Linux's ELOOP, so a cycle can't hang `b init`.
+    for _ in 0..40 {
+        let Ok(target) = fs::read_link(&path) else {
+            break;
+        };
+        path = match path.parent() {
+            Some(dir) => dir.join(target),
+            None => target,
+        };
+    }
+    path
+}
+
+/// Copies `path` to `<path>.baishify.bak`, replacing an older backup. The
+/// backup sits next to the name the user knows, not a symlink's target.
+fn back_up(path: &Path) -> Result<PathBuf> {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".baishify.bak");
+    let backup = path.with_file_name(name);
+    fs::copy(path, &backup)?;
+    Ok(backup)
+}
+
+/// Byte range of the block, markers included.
+fn find_block(existing: &str) -> Option<(usize, usize)> {
+    let start = existing.find(BEGIN_MARKER)?;
+    let end_rel = existing[start..].find(END_MARKER)?;
+    Some((start, start + end_rel + END_MARKER.len()))
+}
+
+fn block_status(existing: &str, block: &str) -> BlockStatus {
+    let Some((start, end)) = find_block(existing) else {
+        return BlockStatus::NotInstalled;
+    };
+    let installed = &existing[start..end];
+    if installed == block.trim_end_matches('\n') {
+        return BlockStatus::Current;
+    }
+    let version = installed
+        .lines()
+        .nth(1)
+        .and_then(|line| line.strip_prefix(VERSION_PREFIX))
+        .map(str::to_string);
+    if version.as_deref() == Some(env!("CARGO_PKG_VERSION")) {
+        BlockStatus::Modified
+    } else {
+        BlockStatus::Outdated(version)
+    }
+}
+
+/// Undoes `upsert_block`: the block goes, and so does the blank line that
+/// was added to separate it from the rest of the file.
+fn remove_block(existing: &str) -> (String, bool) {
+    let Some((start, end)) = find_block(existing) else {
+        return (existing.to_string(), false);
+    };
+    let mut before = existing[..start].to_string();
+    let after = existing[end..].strip_prefix('\n').unwrap_or(&existing[end..]);
+    if after.is_empty() {
+        if before.ends_with("\n\n") {
+            before.pop();
+        }
+        return (before, true);
+    }
+    let separated = before.is_empty() || before.ends_with("\n\n");
+    let after = match after.strip_prefix('\n') {
+        Some(rest) if separated => rest,
+        _ => after,
+    };
+    before.push_str(after);
+    (before, true)
+}
+
 fn upsert_block(existing: &str, block: &str) -> (String, bool) {
-    if let Some(start) = existing.find(BEGIN_MARKER) {
-        if let Some(end_rel) = existing[start..].find(END_MARKER) {
-            let end = start + end_rel + END_MARKER.len();
-            let mut out = String::new();
-            out.push_str(&existing[..start]);
-            if !out.ends_with('\n') && !out.is_empty() {
-                out.push('\n');
-            }
-            out.push_str(block);
-            let trailing = existing[end..].trim_start_matches('\n');
-            if !trailing.is_empty() {
+    if let Some((start, end)) = find_block(existing) {
+        let mut out = String::new();
+        out.push_str(&existing[..start]);
+        if !out.ends_with('\n') && !out.is_empty() {
+            out.push('\n');
+        }
+        out.push_str(block);
+        let trailing = existing[end..].trim_start_matches('\n');
+        if !trailing.is_empty() {
+            out.push('\n');
+            out.push_str(trailing);
+            if !out.ends_with('\n') {
                 out.push('\n');
-                out.push_str(trailing);
-                if !out.ends_with('\n') {
-                    out.push('\n');
-                }
             }
-            let changed = out != existing;
-            return (out, changed);
         }
+        let changed = out != existing;
+        return (out, changed);
     }
 
     let mut out = existing.to_string();
@@ -178,6 +648,146 @@ mod tests {
         assert!(z.contains("if [[ ! -t 0 || ! -t 1 ]]; then"));
     }
 
+    #[test]
+    fn wrapper_block_records_last_command_for_fix() {
+        let bash = ShellKind::Bash.wrapper_block();
+        assert!(bash.contains("PROMPT_COMMAND=\"__b_precmd"));
+        let zsh = ShellKind::Zsh.wrapper_block();
+        assert!(zsh.contains("add-zsh-hook preexec __b_preexec"));
+        for block in [bash, zsh] {
+            assert!(block.contains("set -- fix --last-command \"$__b_last_cmd\""));
+        }
+    }
+
+    #[test]
+    fn ctrl_g_replaces_the_buffer_without_running_it() {
+        let zsh = ShellKind::Zsh.wrapper_block();
+        assert!(zsh.contains("zle -N __b_widget\nbindkey '^G' __b_widget"));
+        assert!(zsh.contains("command b --output-file \"$__b_tmp\" \"$BUFFER\" </dev/tty"));
+        let bash = ShellKind::Bash.wrapper_block();
+        assert!(bash.contains("bind -x '\"\\C-g\": __b_readline'"));
+        assert!(bash.contains("READLINE_LINE=\"$cmd\""));
+        for (block, widget) in [(zsh, "__b_widget() {"), (bash, "__b_readline() {")] {
+            let start = block.find(widget).expect("widget missing");
+            assert!(!block[start..].contains("eval"));
+        }
+    }
+
+    #[test]
+    fn fish_wrapper_uses_conf_d_and_fish_history() {
+        let fish = ShellKind::Fish.wrapper_block();
+        assert!(fish.contains("--on-event fish_postexec"));
+        assert!(fish.contains("command b --shell fish --output-file $__b_tmp $argv"));
+        assert!(fish.contains("history append -- $cmd"));
+        assert!(fish.contains("set argv fix --last-command \"$__b_last_cmd\""));
+        assert!(ShellKind::Fish
+            .rc_path_in(Path::new("/home/u"))
+            .ends_with("fish/conf.d/baishify.fish"));
+        assert!(matches!(parse_shell_name("Fish"), Some(ShellKind::Fish)));
+    }
+
+    #[test]
+    fn nu_and_pwsh_wrappers_ask_for_their_dialect() {
+        let nu = ShellKind::Nu.wrapper_block();
+        assert!(nu.contains("^b --shell nu --output-file $tmp ...$args"));
+        assert!(nu.contains("commandline edit --replace $cmd"));
+        let pwsh = ShellKind::Pwsh.wrapper_block();
+        assert!(pwsh.contains("& $native --shell pwsh --output-file $tmp.FullName @rest"));
+        assert!(pwsh.contains("Invoke-Expression $cmd"));
+        for block in [nu, pwsh] {
+            assert!(block.starts_with(BEGIN_MARKER));
+            assert!(block.contains("'--last-status'"));
+        }
+        assert_eq!(parse_shell_name("powershell"), Some(ShellKind::Pwsh));
+        assert!(ShellKind::Fish.has_own_dialect() && !ShellKind::Zsh.has_own_dialect());
+        assert!(ShellKind::Fish.reads_like_bash() && !ShellKind::Nu.reads_like_bash());
+    }
+
+    #[test]
+    fn remove_block_undoes_upsert() {
+        let block = ShellKind::Zsh.wrapper_block();
+        for original in ["", "export A=1\n", "export A=1\n\n# tail\n"] {
+            let (installed, _) = upsert_block(original, &block);
+            assert_eq!(remove_block(&installed), (original.to_string(), true));
+        }
+        let (middle, _) = upsert_block("top\n", &block);
+        let middle = format!("{middle}\nbottom\n");
+        assert_eq!(remove_block(&middle).0, "top\n\nbottom\n");
+        assert_eq!(remove_block("top\n"), ("top\n".to_string(), false));
+    }
+
+    #[test]
+    fn block_status_compares_with_this_release() {
+        let block = ShellKind::Bash.wrapper_block();
+        let (installed, _) = upsert_block("export A=1\n", &block);
+        assert_eq!(block_status(&installed, &block), BlockStatus::Current);
+        assert_eq!(block_status("export A=1\n", &block), BlockStatus::NotInstalled);
+        let edited = installed.replace("history -s", "history  -s");
+        assert_eq!(block_status(&edited, &block), BlockStatus::Modified);
+        let old = installed.replace(env!("CARGO_PKG_VERSION"), "0.0.1");
+        assert_eq!(block_status(&old, &block), BlockStatus::Outdated(Some("0.0.1".to_string())));
+        let unstamped = format!("{BEGIN_MARKER}\nb() {{ :; }}\n{END_MARKER}\n");
+        assert_eq!(block_status(&unstamped, &block), BlockStatus::Outdated(None));
+    }
+
+    #[test]
+    fn bash_rc_follows_login_only_setups() {
+        let home = std::env::temp_dir().join(format!("b-bash-rc-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&home);
+        fs::create_dir_all(&home).expect("create temp home");
+        assert_eq!(bash_rc(&home), home.join(".bashrc"));
+        fs::write(home.join(".bash_profile"), "export PATH=$HOME/bin:$PATH\n").expect("write");
+        assert_eq!(bash_rc(&home), home.join(".bash_profile"));
+        fs::write(home.join(".bash_profile"), "[ -f ~/.bashrc ] && . ~/.bashrc\n").expect("write");
+        assert_eq!(bash_rc(&home), home.join(".bashrc"));
+        fs::write(home.join(".bash_login"), format!("{BEGIN_MARKER}\n{END_MARKER}\n"))
+            .expect("write");
+        assert_eq!(bash_rc(&home), home.join(".bash_login"));
+        let _ = fs::remove_dir_all(&home);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn install_writes_through_symlinks_and_backs_up() {
+        let dir = std::env::temp_dir().join(format!("b-symlink-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("dotfiles")).expect("create temp dir");
+        fs::write(dir.join("dotfiles/zshrc"), "export A=1\n").expect("write");
+        let rc = dir.join(".zshrc");
+        std::os::unix::fs::symlink("dotfiles/zshrc", &rc).expect("symlink");
+
+        let result = install(ShellKind::Zsh, rc.clone()).expect("install");
+        assert!(fs::symlink_metadata(&rc).expect("stat").file_type().is_symlink());
+        let linked = fs::read_to_string(dir.join("dotfiles/zshrc")).expect("read");
+        assert!(linked.contains(BEGIN_MARKER));
+        assert_eq!(result.backup, Some(dir.join(".zshrc.baishify.bak")));
+        let backup = fs::read_to_string(dir.join(".zshrc.baishify.bak")).expect("read");
+        assert_eq!(backup, "export A=1\n");
+
+        uninstall(ShellKind::Zsh, rc.clone()).expect("uninstall");
+        assert!(fs::symlink_metadata(&rc).expect("stat").file_type().is_symlink());
+        assert_eq!(fs::read_to_string(&rc).expect("read"), "export A=1\n");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn unreadable_rc_files_are_left_alone() {
+        let dir = std::env::temp_dir().join(format!("b-unreadable-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("create temp dir");
+        let rc = dir.join(".bashrc");
+        fs::write(&rc, b"export A=\xff\n").expect("write");
+
+        assert!(install(ShellKind::Bash, rc.clone()).is_err());
+        assert!(uninstall(ShellKind::Bash, rc.clone()).is_err());
+        assert_eq!(fs::read(&rc).expect("read"), b"export A=\xff\n");
+        assert!(!dir.join(".bashrc.baishify.bak").exists());
+
+        let missing = dir.join(".zshrc");
+        assert!(install(ShellKind::Zsh, missing.clone()).expect("install").updated);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
     #[test]
     fn upsert_block_is_idempotent() {
         let block = ShellKind::Bash.wrapper_block();
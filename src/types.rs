@@ -1,4 +1,16 @@
+use crate::cache::CacheSettings;
+use crate::context::EnvContext;
+use crate::fix::FailedCommand;
+use crate::net::NetworkSettings;
+use crate::providers::cassette::Cassette;
+use crate::providers::retry::RetrySettings;
+use crate::safety::Policy;
+use crate::shell_integration::ShellKind;
+use crate::usage::{Price, TokenUsage};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
 
 pub const DEFAULT_OPENAI_BASE_URL: &str = "https://api.openai.com/v1";
 pub const DEFAULT_OPENAI_MODEL: &str = "gpt-4o-mini";
@@ -8,6 +20,10 @@ pub const DEFAULT_OPENROUTER_BASE_URL: &str = "https://openrouter.ai/api/v1";
 pub const DEFAULT_OPENROUTER_MODEL: &str = "openai/gpt-4o-mini";
 pub const DEFAULT_VERCEL_BASE_URL: &str = "https://ai-gateway.vercel.sh/v1";
 pub const DEFAULT_VERCEL_MODEL: &str = "openai/gpt-4o-mini";
+pub const DEFAULT_OLLAMA_BASE_URL: &str = "http://localhost:11434";
+pub const DEFAULT_OLLAMA_MODEL: &str = "llama3.2";
+pub const DEFAULT_LLAMACPP_BASE_URL: &str = "http://localhost:8080";
+pub const DEFAULT_LLAMACPP_MODEL: &str = "default";
 
 #[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
@@ -16,6 +32,8 @@ pub enum Provider {
     Anthropic,
     Openrouter,
     Vercel,
+    Ollama,
+    Llamacpp,
 }
 
 impl Provider {
@@ -25,6 +43,8 @@ impl Provider {
             "anthropic" => Some(Self::Anthropic),
             "openrouter" => Some(Self::Openrouter),
             "vercel" | "vercel-ai-gateway" | "gateway" => Some(Self::Vercel),
+            "ollama" => Some(Self::Ollama),
+            "llamacpp" | "llama.cpp" | "llama-cpp" => Some(Self::Llamacpp),
             _ => None,
         }
     }
@@ -35,6 +55,8 @@ impl Provider {
             Provider::Anthropic => "anthropic",
             Provider::Openrouter => "openrouter",
             Provider::Vercel => "vercel",
+            Provider::Ollama => "ollama",
+            Provider::Llamacpp => "llamacpp",
         }
     }
 
@@ -44,6 +66,8 @@ impl Provider {
             Provider::Anthropic => DEFAULT_ANTHROPIC_BASE_URL,
             Provider::Openrouter => DEFAULT_OPENROUTER_BASE_URL,
             Provider::Vercel => DEFAULT_VERCEL_BASE_URL,
+            Provider::Ollama => DEFAULT_OLLAMA_BASE_URL,
+            Provider::Llamacpp => DEFAULT_LLAMACPP_BASE_URL,
         }
     }
 
@@ -53,8 +77,26 @@ impl Provider {
             Provider::Anthropic => DEFAULT_ANTHROPIC_MODEL,
             Provider::Openrouter => DEFAULT_OPENROUTER_MODEL,
             Provider::Vercel => DEFAULT_VERCEL_MODEL,
+            Provider::Ollama => DEFAULT_OLLAMA_MODEL,
+            Provider::Llamacpp => DEFAULT_LLAMACPP_MODEL,
         }
     }
+
+    /// Environment variable holding the key, for hosted providers.
+    pub fn api_key_env(self) -> Option<&'static str> {
+        match self {
+            Provider::Openai => Some("OPENAI_API_KEY"),
+            Provider::Anthropic => Some("ANTHROPIC_API_KEY"),
+            Provider::Openrouter => Some("OPENROUTER_API_KEY"),
+            Provider::Vercel => Some("VERCEL_AI_GATEWAY_API_KEY"),
+            Provider::Ollama | Provider::Llamacpp => None,
+        }
+    }
+
+    /// Local servers (Ollama, llama.cpp) run without credentials by default.
+    pub fn requires_api_key(self) -> bool {
+        !matches!(self, Provider::Ollama | Provider::Llamacpp)
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -64,6 +106,49 @@ pub struct FileConfig {
     pub base_url: Option<String>,
     pub api_key: Option<String>,
     pub no_fun: Option<bool>,
+    /// Set to `true` to stop sending OS/shell/cwd details to the provider.
+    pub no_context: Option<bool>,
+    /// Set to `true` to always race the provider against its fallbacks.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub race: Option<bool>,
+    /// Inline safety policy; a `policy.toml` next to this file takes precedence.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub policy: Option<Policy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache: Option<CacheSettings>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetrySettings>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network: Option<NetworkSettings>,
+    /// `[[fallback]]` entries, tried in order when the provider above is down.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fallback: Vec<FallbackEntry>,
+    /// USD per million tokens by model (or `provider/model`) name prefix, on
+    /// top of the built-in table.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub prices: BTreeMap<String, Price>,
+}
+
+/// A backup provider. Unset fields use the provider's defaults; the key also
+/// falls back to its usual environment variable.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct FallbackEntry {
+    pub provider: Provider,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+}
+
+/// A fully resolved `FallbackEntry`.
+#[derive(Debug, Clone)]
+pub struct ProviderTarget {
+    pub provider: Provider,
+    pub model: String,
+    pub base_url: String,
+    pub api_key: String,
 }
 
 #[derive(Debug, Clone)]
@@ -79,11 +164,84 @@ pub struct AppConfig {
     pub setup: bool,
     pub prompt: Option<String>,
     pub output_file: Option<String>,
+    /// Number of alternatives requested with `--candidates`; 1 means a single command.
+    pub candidates: usize,
+    pub no_context: bool,
+    /// Collected once at startup unless `no_context` is set.
+    pub context: Option<EnvContext>,
+    /// Set by `b fix`: the failed command to repair.
+    pub fix: Option<FailedCommand>,
+    /// Set by `b explain <command>`: annotate `prompt` as an existing command.
+    pub explain_existing: bool,
+    pub policy: Policy,
+    /// Where interactive results are recorded; `None` disables history.
+    pub history_path: Option<PathBuf>,
+    pub cache: CacheSettings,
+    /// `None` disables the response cache entirely.
+    pub cache_path: Option<PathBuf>,
+    /// `--no-cache`: skip lookups but still refresh the stored answer.
+    pub no_cache: bool,
+    /// `--offline`: answer only from the cache or history, never the network.
+    pub offline: bool,
+    pub retry: RetrySettings,
+    pub network: NetworkSettings,
+    /// Tried in order when the configured provider fails with a temporary error.
+    pub fallback: Vec<ProviderTarget>,
+    /// `--race`: ask the provider and every fallback at once, keep the fastest.
+    pub race: bool,
+    /// Where per-call latency is recorded; `None` disables it.
+    pub usage_path: Option<PathBuf>,
+    /// `--record` / `--replay`: provider traffic saved to or served from a file.
+    pub cassette: Option<Arc<Cassette>>,
+    /// The shell commands are generated for; nu and pwsh get their own syntax.
+    pub shell: ShellKind,
 }
 
 impl AppConfig {
+    /// The same settings aimed at another provider.
+    pub fn with_target(&self, target: &ProviderTarget) -> AppConfig {
+        AppConfig {
+            provider: target.provider,
+            model: target.model.clone(),
+            base_url: target.base_url.clone(),
+            api_key: target.api_key.clone(),
+            ..self.clone()
+        }
+    }
+
     pub fn provider_api_key_missing(&self) -> bool {
-        self.api_key.trim().is_empty()
+        let replaying = self.cassette.as_ref().is_some_and(|c| c.is_replay());
+        self.provider.requires_api_key() && self.api_key.trim().is_empty() && !replaying
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatRole {
+    User,
+    Assistant,
+}
+
+/// One conversation turn sent to a provider; the system prompt is passed separately.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatMessage {
+    pub role: ChatRole,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn user(content: impl Into<String>) -> Self {
+        Self {
+            role: ChatRole::User,
+            content: content.into(),
+        }
+    }
+
+    pub fn assistant(content: impl Into<String>) -> Self {
+        Self {
+            role: ChatRole::Assistant,
+            content: content.into(),
+        }
     }
 }
 
@@ -92,6 +250,60 @@ pub struct GenerationOutput {
     pub command: String,
     pub explanation: String,
     pub safety: String,
+    /// Filled in by the static analyser, never by the model.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub safety_reasons: Vec<String>,
+    /// Set when a fallback answered instead of the configured provider, and
+    /// always in race mode.
+    #[serde(skip)]
+    pub answered_by: Option<AnsweredBy>,
+    /// Tokens the provider reported for this answer, retries and repairs
+    /// included. `None` for cached answers.
+    #[serde(skip)]
+    pub usage: Option<TokenUsage>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct AnsweredBy {
+    pub provider: Provider,
+    pub model: String,
+    pub via: Via,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Via {
+    /// The providers before it failed.
+    Fallback,
+    /// It was the fastest of the providers raced.
+    Race { latency_ms: u64 },
+}
+
+/// Reverse-mode result for `b explain <command>`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct CommandExplanation {
+    #[serde(default)]
+    pub command: String,
+    pub summary: String,
+    pub parts: Vec<CommandPart>,
+    pub safety: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub safety_reasons: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct CommandPart {
+    pub text: String,
+    pub kind: String,
+    pub explanation: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExplainJsonOutput {
+    pub provider: String,
+    pub model: String,
+    #[serde(flatten)]
+    pub explanation: CommandExplanation,
 }
 
 #[derive(Debug, Serialize)]
@@ -101,4 +313,16 @@ pub struct JsonOutput {
     pub command: String,
     pub explanation: String,
     pub safety: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub safety_reasons: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub original_command: Option<String>,
+    /// The configured provider, when a fallback answered in its place.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fallback_from: Option<String>,
+    /// Time to the winning answer in race mode.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<TokenUsage>,
 }
@@ -1,24 +1,70 @@
-use crate::error::Result;
-use crate::providers::generate_once;
-use crate::types::{AppConfig, GenerationOutput, JsonOutput};
+use crate::cache::{self, Source};
+use crate::editor::LineEditor;
+use crate::error::{AppError, Result};
+use crate::fix::{word_diff, DiffPart};
+use crate::history::{self, HistoryEntry, Outcome};
+use crate::providers::retry::{self, RetryNotice};
+use crate::providers::{
+    explain_command, generate_candidates, generate_stream, initial_messages, push_follow_up,
+    StreamEvent,
+};
+use crate::safety::{self, Policy, SafetyLevel, Verdict};
+use crate::shell_integration::ShellKind;
+use crate::types::{
+    AppConfig, ChatMessage, CommandExplanation, ExplainJsonOutput, GenerationOutput, JsonOutput,
+    Provider, Via,
+};
+use crate::usage::{TokenUsage, UsageRow};
 use dialoguer::console::{Key, Term};
+use dialoguer::theme::ColorfulTheme;
+use dialoguer::{FuzzySelect, Select};
 use std::fs;
 use std::io::{self, IsTerminal, Write};
+use std::path::Path;
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
 use std::time::{Duration, Instant};
 
+/// The conversation behind the interactive card: the original prompt, any
+/// follow-up refinements, and the messages sent to the provider.
+struct Session {
+    prompt: String,
+    refinements: Vec<String>,
+    messages: Vec<ChatMessage>,
+}
+
 pub fn run_interactive(agent: &ureq::Agent, config: &AppConfig, prompt: &str) -> Result<()> {
-    let current_prompt = prompt.to_string();
+    let mut session = Session {
+        prompt: prompt.to_string(),
+        refinements: Vec::new(),
+        messages: initial_messages(config.context.as_ref(), prompt),
+    };
+    let mut editor = LineEditor::new();
+    let mut follow_ups = LineEditor::new();
+    // Set once the user regenerates: from then on the cache is skipped.
+    let mut fresh = false;
     loop {
-        let output = generate_with_loader(agent, config, &current_prompt)?;
-        render_result_card(config, prompt, &output);
+        let generated = if config.candidates > 1 && !config.offline {
+            match pick_candidate(agent, config, &session)? {
+                Some(generated) => generated,
+                None => return Ok(()),
+            }
+        } else if session.refinements.is_empty() {
+            generate_first_turn(agent, config, &session, fresh)?
+        } else {
+            generate_with_loader(agent, config, &session)?
+        };
+        render_result_card(config, &session, &generated.output, generated.streamed.as_deref());
+        let mut output = generated.output;
+        let mut copied = false;
 
         loop {
             print!(
-                "{}  {}  {}  {}  {}",
+                "{}  {}  {}  {}  {}  {}  {}",
                 paint("[Enter] use", Ansi::Dim),
+                paint("[i] edit", Ansi::Dim),
+                paint("[f] follow-up", Ansi::Dim),
                 paint("[r] regenerate", Ansi::Dim),
                 paint("[e] explain", Ansi::Dim),
                 paint("[c] copy", Ansi::Dim),
@@ -38,14 +84,70 @@ pub fn run_interactive(agent: &ureq::Agent, config: &AppConfig, prompt: &str) ->
                         println!("{}", paint("Generated command was empty.", Ansi::Yellow));
                         continue;
                     }
+                    let term = Term::stdout();
+                    let (policy, shell) = (&config.policy, config.shell);
+                    let refusal =
+                        enforce_policy(policy, shell, cmd, &output.safety, &term, "Run")?;
+                    if let Some(refusal) = refusal {
+                        println!("{}", paint(&refusal, Ansi::Red));
+                        continue;
+                    }
                     if let Some(path) = config.output_file.as_deref() {
                         fs::write(path, format!("{cmd}\n"))?;
+                        record_history(config, &session, &output, Outcome::Written, None);
                         return Ok(());
                     }
-                    run_command(cmd)?;
+                    let status = run_command(cmd, config.shell)?;
+                    record_history(config, &session, &output, Outcome::Executed, status);
                     return Ok(());
                 }
+                Key::Char(c) if c.eq_ignore_ascii_case(&'i') => {
+                    let term = Term::stdout();
+                    let label = paint("edit > ", Ansi::Dim);
+                    let Some(edited) = editor.edit(&term, &label, output.command.trim())? else {
+                        continue;
+                    };
+                    if edited.is_empty() || edited == output.command.trim() {
+                        continue;
+                    }
+                    // The model's label described the old text; classify the edit on its own.
+                    let analysis = safety::analyze(&edited, config.shell);
+                    output.command = edited;
+                    output.safety = analysis.level.as_str().to_string();
+                    output.safety_reasons = analysis.reasons;
+                    println!();
+                    println!("{}", paint("Edited", Ansi::Cyan));
+                    println!("{}", output.command);
+                    println!();
+                    render_safety(&output.safety, &output.safety_reasons);
+                    println!();
+                    continue;
+                }
+                Key::Char(c) if config.offline && matches!(c.to_ascii_lowercase(), 'f' | 'r') => {
+                    let note = "Offline: follow-up and regenerate need the provider.";
+                    println!("{}", paint(note, Ansi::Yellow));
+                    continue;
+                }
+                Key::Char(c) if c.eq_ignore_ascii_case(&'f') => {
+                    let label = paint("follow-up > ", Ansi::Dim);
+                    let Some(refinement) = follow_ups.edit(&Term::stdout(), &label, "")? else {
+                        continue;
+                    };
+                    if refinement.is_empty() {
+                        continue;
+                    }
+                    if copied {
+                        record_history(config, &session, &output, Outcome::Copied, None);
+                    }
+                    push_follow_up(&mut session.messages, &output, &refinement);
+                    session.refinements.push(refinement);
+                    break;
+                }
                 Key::Char(c) if c.eq_ignore_ascii_case(&'r') => {
+                    if copied {
+                        record_history(config, &session, &output, Outcome::Copied, None);
+                    }
+                    fresh = true;
                     if !config.no_fun {
                         println!("Trying a different phrasing path...");
                     }
@@ -59,16 +161,31 @@ pub fn run_interactive(agent: &ureq::Agent, config: &AppConfig, prompt: &str) ->
                     continue;
                 }
                 Key::Char(c) if c.eq_ignore_ascii_case(&'c') => {
-                    if copy_to_clipboard(output.command.trim()) {
+                    // A pasted command skips the Enter path, so it is gated here too.
+                    let term = Term::stdout();
+                    let cmd = output.command.trim();
+                    let (policy, shell) = (&config.policy, config.shell);
+                    let refusal =
+                        enforce_policy(policy, shell, cmd, &output.safety, &term, "Copy")?;
+                    if let Some(refusal) = refusal {
+                        println!("{}", paint(&refusal, Ansi::Red));
+                        continue;
+                    }
+                    if copy_to_clipboard(cmd) {
+                        copied = true;
                         println!("{}", paint("Copied to clipboard.", Ansi::Green));
                     } else {
                         println!("{}", paint("Copy not supported on this system.", Ansi::Yellow));
                     }
                     continue;
                 }
-                Key::Char(c) if c.eq_ignore_ascii_case(&'q') => return Ok(()),
+                Key::Char(c) if c.eq_ignore_ascii_case(&'q') => {
+                    let outcome = if copied { Outcome::Copied } else { Outcome::Quit };
+                    record_history(config, &session, &output, outcome, None);
+                    return Ok(());
+                }
                 _ => {
-                    println!("{}", paint("Unknown key. Press Enter, r, e, c, or q.", Ansi::Yellow));
+                    println!("{}", paint("Unknown key. Press Enter, i, f, r, e, c, or q.", Ansi::Yellow));
                     continue;
                 }
             }
@@ -76,18 +193,247 @@ pub fn run_interactive(agent: &ureq::Agent, config: &AppConfig, prompt: &str) ->
     }
 }
 
-pub fn emit_non_interactive(config: &AppConfig, output: GenerationOutput) -> Result<()> {
+/// Appends the card's final state to the history file, refinements joined
+/// onto the prompt.
+fn record_history(
+    config: &AppConfig,
+    session: &Session,
+    output: &GenerationOutput,
+    outcome: Outcome,
+    exit_status: Option<i32>,
+) {
+    let mut prompt = session.prompt.trim().to_string();
+    for refinement in &session.refinements {
+        prompt.push_str(&format!(" / {refinement}"));
+    }
+    append_history(config, &prompt, output, outcome, exit_status);
+}
+
+/// Appends a result to the history file. Failures only warn: losing a
+/// history line must never block using the command.
+fn append_history(
+    config: &AppConfig,
+    prompt: &str,
+    output: &GenerationOutput,
+    outcome: Outcome,
+    exit_status: Option<i32>,
+) {
+    let Some(path) = config.history_path.as_deref() else {
+        return;
+    };
+    let (provider, model) = answerer(config, output);
+    let entry = HistoryEntry {
+        timestamp: history::now(),
+        prompt: prompt.trim().to_string(),
+        provider: provider.as_str().to_string(),
+        model,
+        command: output.command.trim().to_string(),
+        shell: config.shell,
+        safety: output.safety.clone(),
+        outcome,
+        exit_status,
+    };
+    if let Err(err) = history::append(path, &entry) {
+        eprintln!("{}", paint(&format!("Could not save history: {err}"), Ansi::Yellow));
+    }
+}
+
+/// `b history [query]`: search past results and run or copy one again.
+pub fn run_history(path: &Path, policy: &Policy, query: Option<&str>) -> Result<()> {
+    let mut entries = history::load(path)?;
+    entries.reverse();
+    let now = history::now();
+
+    if !io::stdout().is_terminal() || !io::stdin().is_terminal() {
+        let needle = query.unwrap_or_default().to_lowercase();
+        for entry in entries.iter().filter(|e| {
+            e.command.to_lowercase().contains(&needle) || e.prompt.to_lowercase().contains(&needle)
+        }) {
+            println!("{}\t{}\t{}", entry.timestamp, entry.outcome.as_str(), entry.command);
+        }
+        return Ok(());
+    }
+    if entries.is_empty() {
+        println!("No history yet. Commands you use, copy or quit on are recorded here.");
+        return Ok(());
+    }
+
+    let items: Vec<String> = entries
+        .iter()
+        .map(|e| {
+            let status = e.exit_status.map(|s| format!(" exit {s},")).unwrap_or_default();
+            format!(
+                "{}    # {} ({},{status} {})",
+                e.command,
+                e.prompt,
+                e.outcome.as_str(),
+                history::age(e.timestamp, now)
+            )
+        })
+        .collect();
+    let theme = ColorfulTheme::default();
+    let Some(idx) = FuzzySelect::with_theme(&theme)
+        .with_prompt("Search history")
+        .with_initial_text(query.unwrap_or_default())
+        .items(&items)
+        .default(0)
+        .max_length(15)
+        .interact_opt()?
+    else {
+        return Ok(());
+    };
+    let entry = &entries[idx];
+
+    let action = Select::with_theme(&theme)
+        .with_prompt(entry.command.as_str())
+        .items(&["Run again", "Copy", "Cancel"])
+        .default(0)
+        .interact_opt()?;
+    if !matches!(action, Some(0) | Some(1)) {
+        return Ok(());
+    }
+    // Policies and rules may have changed since this was recorded. The
+    // command is read and run as the shell it was written for.
+    let shell = entry.shell;
+    let safety = safety::reconcile(&entry.safety, &entry.command, shell).level.as_str();
+    let verb = if action == Some(0) { "Run" } else { "Copy" };
+    let term = Term::stdout();
+    if let Some(refusal) = enforce_policy(policy, shell, &entry.command, safety, &term, verb)? {
+        println!("{}", paint(&refusal, Ansi::Red));
+        return Ok(());
+    }
+    let (outcome, exit_status) = match action {
+        Some(0) => {
+            let status = run_command(&entry.command, shell)?;
+            (Outcome::Executed, status)
+        }
+        Some(1) => {
+            if !copy_to_clipboard(&entry.command) {
+                println!("{}", paint("Copy not supported on this system.", Ansi::Yellow));
+                return Ok(());
+            }
+            println!("{}", paint("Copied to clipboard.", Ansi::Green));
+            (Outcome::Copied, None)
+        }
+        _ => return Ok(()),
+    };
+    let rerun = HistoryEntry {
+        timestamp: history::now(),
+        outcome,
+        exit_status,
+        ..entry.clone()
+    };
+    history::append(path, &rerun)
+}
+
+/// `b usage`: calls, tokens and estimated cost per provider and model per day.
+pub fn run_usage(rows: &[UsageRow], days: u64) {
+    if rows.is_empty() {
+        println!("No provider calls in the last {days} days.");
+        return;
+    }
+    // `≥` marks a lower bound: some calls in it reported no tokens.
+    let money = |cost: Option<f64>, partial: bool| match cost {
+        Some(c) if partial => format!("≥${c:.4}"),
+        Some(c) => format!("${c:.4}"),
+        None => "?".to_string(),
+    };
+    let width = rows
+        .iter()
+        .map(|r| r.provider.len() + r.model.len() + 1)
+        .max()
+        .unwrap_or(0)
+        .max(5);
+    println!(
+        "{:<10}  {:<width$}  {:>5}  {:>6}  {:>10}  {:>10}  {:>8}  {:>9}",
+        "day", "model", "calls", "failed", "input", "output", "avg ms", "cost"
+    );
+    let mut tokens = TokenUsage::default();
+    let mut total = None;
+    let mut unpriced = false;
+    let mut unmetered = false;
+    for row in rows {
+        println!(
+            "{:<10}  {:<width$}  {:>5}  {:>6}  {:>10}  {:>10}  {:>8}  {:>9}",
+            row.day,
+            format!("{}/{}", row.provider, row.model),
+            row.calls,
+            row.failed,
+            row.tokens.input_tokens,
+            row.tokens.output_tokens,
+            row.avg_latency_ms,
+            money(row.cost, row.unmetered > 0)
+        );
+        tokens += row.tokens;
+        match row.cost {
+            Some(cost) => total = Some(total.unwrap_or(0.0) + cost),
+            None => unpriced = true,
+        }
+        unmetered |= row.unmetered > 0 && row.cost.is_some();
+    }
+    println!(
+        "{:<10}  {:<width$}  {:>5}  {:>6}  {:>10}  {:>10}  {:>8}  {:>9}",
+        "total",
+        "",
+        rows.iter().map(|r| r.calls).sum::<u64>(),
+        rows.iter().map(|r| r.failed).sum::<u64>(),
+        tokens.input_tokens,
+        tokens.output_tokens,
+        "",
+        money(total, unpriced || unmetered)
+    );
+    if unpriced {
+        println!("? = no price for that model; add it under [prices] in config.toml.");
+    }
+    if unpriced || unmetered {
+        println!(
+            "≥ = a lower bound: it leaves out unpriced models and calls that reported no \
+             tokens, such as race entrants abandoned mid-answer."
+        );
+    }
+}
+
+/// `b explain <command>`: annotate an existing command part by part.
+pub fn run_explain(agent: &ureq::Agent, config: &AppConfig, command: &str) -> Result<()> {
+    let interactive = io::stdout().is_terminal() && !config.json && !config.plain;
+    let explanation = if interactive {
+        let agent = agent.clone();
+        let cfg = config.clone();
+        let command = command.to_string();
+        run_with_spinner(config.no_fun, move || explain_command(&agent, &cfg, &command))?
+    } else {
+        explain_command(agent, config, command)?
+    };
+
     if config.json {
-        let payload = JsonOutput {
+        let payload = ExplainJsonOutput {
             provider: config.provider.as_str().to_string(),
             model: config.model.clone(),
-            command: output.command,
-            explanation: output.explanation,
-            safety: output.safety,
+            explanation,
         };
         println!("{}", serde_json::to_string(&payload)?);
         return Ok(());
     }
+    render_explanation(&explanation);
+    Ok(())
+}
+
+pub fn emit_non_interactive(
+    config: &AppConfig,
+    prompt: &str,
+    output: GenerationOutput,
+) -> Result<()> {
+    let term = Term::stderr();
+    let (policy, shell) = (&config.policy, config.shell);
+    let refusal = enforce_policy(policy, shell, &output.command, &output.safety, &term, "Run")?;
+    if let Some(refusal) = refusal {
+        return Err(AppError::from(refusal));
+    }
+    append_history(config, prompt, &output, Outcome::Printed, None);
+    if config.json {
+        println!("{}", serde_json::to_string(&json_output(config, output))?);
+        return Ok(());
+    }
 
     if config.explain {
         eprintln!("{}", output.explanation.trim());
@@ -96,43 +442,470 @@ pub fn emit_non_interactive(config: &AppConfig, output: GenerationOutput) -> Res
     Ok(())
 }
 
-fn render_result_card(config: &AppConfig, prompt: &str, output: &GenerationOutput) {
-    println!();
-    println!("{} {}", paint("Prompt:", Ansi::Bold), prompt.trim());
-    println!();
-    println!("{}", paint("Command", Ansi::Cyan));
-    println!("{}", output.command.trim());
+/// `--candidates` outside the TUI: a JSON array, or one command per line.
+/// Candidates the policy refuses are dropped with a note on stderr.
+pub fn emit_candidates(
+    config: &AppConfig,
+    prompt: &str,
+    outputs: Vec<GenerationOutput>,
+) -> Result<()> {
+    let mut allowed = Vec::new();
+    let mut first_refusal = None;
+    for output in outputs {
+        let term = Term::stderr();
+        let (policy, shell) = (&config.policy, config.shell);
+        match enforce_policy(policy, shell, &output.command, &output.safety, &term, "Run")? {
+            Some(refusal) => {
+                eprintln!("skipped `{}`: {refusal}", output.command.trim());
+                first_refusal.get_or_insert(refusal);
+            }
+            None => {
+                append_history(config, prompt, &output, Outcome::Printed, None);
+                allowed.push(output);
+            }
+        }
+    }
+    if let (true, Some(refusal)) = (allowed.is_empty(), first_refusal) {
+        return Err(AppError::from(refusal));
+    }
+
+    if config.json {
+        let payload: Vec<JsonOutput> = allowed.into_iter().map(|o| json_output(config, o)).collect();
+        println!("{}", serde_json::to_string(&payload)?);
+        return Ok(());
+    }
+    for output in allowed {
+        if config.explain {
+            eprintln!("{}", output.explanation.trim());
+        }
+        println!("{}", output.command.trim());
+    }
+    Ok(())
+}
+
+/// The provider and model that produced `output`, which differ from the
+/// configured ones when a fallback answered.
+fn answerer(config: &AppConfig, output: &GenerationOutput) -> (Provider, String) {
+    match &output.answered_by {
+        Some(by) => (by.provider, by.model.clone()),
+        None => (config.provider, config.model.clone()),
+    }
+}
+
+fn json_output(config: &AppConfig, output: GenerationOutput) -> JsonOutput {
+    let (provider, model) = answerer(config, &output);
+    JsonOutput {
+        provider: provider.as_str().to_string(),
+        model,
+        fallback_from: output
+            .answered_by
+            .as_ref()
+            .filter(|by| by.via == Via::Fallback)
+            .map(|_| config.provider.as_str().to_string()),
+        latency_ms: match output.answered_by.as_ref().map(|by| by.via) {
+            Some(Via::Race { latency_ms }) => Some(latency_ms),
+            _ => None,
+        },
+        command: output.command,
+        explanation: output.explanation,
+        safety: output.safety,
+        safety_reasons: output.safety_reasons,
+        original_command: config.fix.as_ref().map(|f| f.command.clone()),
+        usage: output.usage,
+    }
+}
+
+/// Applies the safety policy before a command is used, whether it is run or
+/// copied (`verb`). Returns the reason when it must not be used; prompts go
+/// to `term` when the policy asks for them.
+fn enforce_policy(
+    policy: &Policy,
+    shell: ShellKind,
+    command: &str,
+    safety: &str,
+    term: &Term,
+    verb: &str,
+) -> Result<Option<String>> {
+    let level = SafetyLevel::parse(safety).unwrap_or(SafetyLevel::Caution);
+    let (question, expected, rule) = match policy.check(command.trim(), level, shell) {
+        Verdict::Allow => return Ok(None),
+        Verdict::Block { rule } => return Ok(Some(format!("Blocked by policy: {rule}."))),
+        Verdict::Confirm { rule } => (format!("{verb} this command? [y/N] "), None, rule),
+        Verdict::TypeName { program, rule } => {
+            (format!("Type `{program}` to confirm: "), Some(program), rule)
+        }
+    };
+    if !term.is_term() || !io::stdin().is_terminal() {
+        return Ok(Some(format!(
+            "Policy requires confirmation ({rule}); run `b` from a terminal to confirm."
+        )));
+    }
+
+    term.write_line(&paint(&format!("Policy: {rule}."), Ansi::Yellow))?;
+    term.write_str(&question)?;
+    let confirmed = match expected {
+        Some(program) => term.read_line()?.trim() == program,
+        None => {
+            let key = term.read_key()?;
+            term.write_line("")?;
+            matches!(key, Key::Char('y') | Key::Char('Y'))
+        }
+    };
+    Ok((!confirmed).then(|| "Not confirmed; command not used.".to_string()))
+}
+
+/// Renders the card; when the command was already echoed while streaming
+/// (`streamed`), only the remainder below it is printed. A streamed command
+/// that a repair or fallback replaced stays on screen, so the final one is
+/// labelled as its replacement rather than given a second header.
+fn render_result_card(
+    config: &AppConfig,
+    session: &Session,
+    output: &GenerationOutput,
+    streamed: Option<&str>,
+) {
+    match streamed {
+        Some(shown) if shown.trim() == output.command.trim() => {}
+        Some(_) => {
+            println!("{}", paint("Replaced by:", Ansi::Yellow));
+            println!("{}", output.command.trim());
+        }
+        None => {
+            render_card_header(config, session);
+            println!("{}", output.command.trim());
+        }
+    }
+    if let Some(failed) = &config.fix {
+        println!();
+        println!("{}", paint("Changes", Ansi::Cyan));
+        render_fix_diff(&failed.command, output.command.trim());
+    }
     if config.explain {
         println!();
         println!("{}", paint("Explanation", Ansi::Cyan));
         println!("{}", output.explanation.trim());
     }
+    if output.safety != "safe" {
+        println!();
+        render_safety(&output.safety, &output.safety_reasons);
+    }
+    if let Some(by) = &output.answered_by {
+        println!();
+        let answerer = format!("{} ({})", by.provider.as_str(), by.model);
+        match by.via {
+            Via::Fallback => {
+                let note = format!(
+                    "Answered by {answerer}; {} was unavailable.",
+                    config.provider.as_str()
+                );
+                println!("{}", paint(&note, Ansi::Yellow));
+            }
+            Via::Race { latency_ms } => {
+                let note = format!("Fastest: {answerer} in {latency_ms}ms.");
+                println!("{}", paint(&note, Ansi::Dim));
+            }
+        }
+    }
+    println!();
+}
+
+fn render_card_header(config: &AppConfig, session: &Session) {
     println!();
+    match &config.fix {
+        Some(failed) => {
+            let status = failed
+                .status
+                .map(|s| format!(" (exit {s})"))
+                .unwrap_or_default();
+            println!("{} {}{status}", paint("Fixing:", Ansi::Bold), failed.command);
+        }
+        None => println!("{} {}", paint("Prompt:", Ansi::Bold), session.prompt.trim()),
+    }
+    for refinement in &session.refinements {
+        println!("{} {refinement}", paint("Follow-up:", Ansi::Bold));
+    }
+    println!();
+    println!("{}", paint("Command", Ansi::Cyan));
+}
+
+fn render_explanation(explanation: &CommandExplanation) {
+    const MAX_PART_WIDTH: usize = 28;
+    println!();
+    println!("{}", paint("Command", Ansi::Cyan));
+    println!("{}", explanation.command.trim());
+    println!();
+
+    let width = explanation
+        .parts
+        .iter()
+        .map(|p| p.text.chars().count())
+        .max()
+        .unwrap_or(0)
+        .min(MAX_PART_WIDTH);
+    for part in &explanation.parts {
+        let kind = paint(&format!("{:<12}", part.kind), Ansi::Dim);
+        if part.text.chars().count() > width {
+            // Long parts (quoted scripts, URLs) get their own line.
+            println!("{}", paint(&part.text, Ansi::Bold));
+            println!("{:<width$}  {kind}{}", "", part.explanation.trim());
+        } else {
+            let text = paint(&format!("{:<width$}", part.text), Ansi::Bold);
+            println!("{text}  {kind}{}", part.explanation.trim());
+        }
+    }
+
+    println!();
+    println!("{} {}", paint("Summary:", Ansi::Bold), explanation.summary.trim());
+    render_safety(&explanation.safety, &explanation.safety_reasons);
+    println!();
+}
+
+fn render_safety(safety: &str, reasons: &[String]) {
+    println!("{} {}", paint("Safety:", Ansi::Bold), paint(safety, safety_color(safety)));
+    for reason in reasons {
+        println!("  {} {reason}", paint("-", Ansi::Dim));
+    }
+}
+
+fn safety_color(safety: &str) -> Ansi {
+    match safety {
+        "safe" => Ansi::Green,
+        "caution" => Ansi::Yellow,
+        _ => Ansi::Red,
+    }
+}
+
+/// Prints the original and fixed command with changed words highlighted.
+fn render_fix_diff(original: &str, fixed: &str) {
+    let diff = word_diff(original, fixed);
+    let before: Vec<String> = diff
+        .iter()
+        .filter_map(|part| match part {
+            DiffPart::Same(w) => Some(w.clone()),
+            DiffPart::Removed(w) => Some(paint(w, Ansi::Red)),
+            DiffPart::Added(_) => None,
+        })
+        .collect();
+    let after: Vec<String> = diff
+        .iter()
+        .filter_map(|part| match part {
+            DiffPart::Same(w) => Some(w.clone()),
+            DiffPart::Added(w) => Some(paint(w, Ansi::Green)),
+            DiffPart::Removed(_) => None,
+        })
+        .collect();
+    println!("{} {}", paint("-", Ansi::Red), before.join(" "));
+    println!("{} {}", paint("+", Ansi::Green), after.join(" "));
+}
+
+const LOADER_PHASES: [&str; 4] = ["thinking", "drafting", "refining", "finalizing"];
+const SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+
+enum LoaderEvent {
+    Delta(String),
+    /// A fallback took over mid-stream and starts the command again.
+    Restart,
+    /// The provider asked us to back off; shown instead of the phase text.
+    Retry(RetryNotice),
+    Done(Result<GenerationOutput>),
+}
+
+struct Generated {
+    output: GenerationOutput,
+    /// Command text echoed under the card header as tokens arrived.
+    streamed: Option<String>,
 }
 
 fn generate_with_loader(
     agent: &ureq::Agent,
     config: &AppConfig,
-    prompt: &str,
-) -> Result<GenerationOutput> {
-    let (tx, rx) = mpsc::channel::<Result<GenerationOutput>>();
+    session: &Session,
+) -> Result<Generated> {
+    let (tx, rx) = mpsc::channel::<LoaderEvent>();
     let cfg = config.clone();
-    let prompt_owned = prompt.to_string();
+    let messages = session.messages.clone();
     let agent = agent.clone();
 
     thread::spawn(move || {
-        let result = generate_once(&agent, &cfg, &prompt_owned);
-        let _ = tx.send(result);
+        let delta_tx = tx.clone();
+        let mut on_event = |event: StreamEvent| {
+            let _ = delta_tx.send(match event {
+                StreamEvent::Delta(text) => LoaderEvent::Delta(text.to_string()),
+                StreamEvent::Restart => LoaderEvent::Restart,
+            });
+        };
+        let retry_tx = tx.clone();
+        let result = retry::observe(
+            move |notice| {
+                let _ = retry_tx.send(LoaderEvent::Retry(notice.clone()));
+            },
+            || generate_stream(&agent, &cfg, &messages, &mut on_event),
+        );
+        let _ = tx.send(LoaderEvent::Done(result));
     });
 
-    let phases = ["thinking", "drafting", "refining", "finalizing"];
-    let spinner = ['|', '/', '-', '\\'];
     let mut phase_idx = 0usize;
     let mut spin_idx = 0usize;
     let mut last_phase_tick = Instant::now();
+    let mut streamed: Option<String> = None;
+    let mut waiting: Option<(String, Instant)> = None;
 
     // Immediate feedback in same event-loop tick (<=30ms budget).
-    draw_loader_line(spinner[spin_idx], phases[phase_idx], config.no_fun)?;
+    draw_loader_line(SPINNER[spin_idx], LOADER_PHASES[phase_idx], config.no_fun)?;
+
+    loop {
+        match rx.recv_timeout(Duration::from_millis(90)) {
+            Ok(LoaderEvent::Delta(text)) => {
+                // The spinner only lives until the first token; from then on
+                // the command is echoed in place under the card header.
+                if streamed.is_none() {
+                    clear_line()?;
+                    render_card_header(config, session);
+                }
+                print!("{text}");
+                io::stdout().flush()?;
+                streamed.get_or_insert_with(String::new).push_str(&text);
+            }
+            Ok(LoaderEvent::Restart) => {
+                // The partial command stays on its own line; the fallback's
+                // answer streams in below it.
+                if streamed.replace(String::new()).is_some() {
+                    println!();
+                }
+            }
+            Ok(LoaderEvent::Retry(notice)) => {
+                waiting = Some((notice.reason, Instant::now() + notice.wait));
+            }
+            Ok(LoaderEvent::Done(result)) => {
+                if streamed.is_some() {
+                    println!();
+                } else {
+                    clear_line()?;
+                }
+                return result.map(|output| Generated { output, streamed });
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) if streamed.is_some() => {}
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                spin_idx = (spin_idx + 1) % SPINNER.len();
+                if last_phase_tick.elapsed() >= Duration::from_millis(850) {
+                    phase_idx = (phase_idx + 1) % LOADER_PHASES.len();
+                    last_phase_tick = Instant::now();
+                }
+                let phase = match &waiting {
+                    Some((reason, until)) if *until > Instant::now() => {
+                        let left = until.saturating_duration_since(Instant::now());
+                        format!("{reason}, retrying in {}s", left.as_millis().div_ceil(1000))
+                    }
+                    _ => LOADER_PHASES[phase_idx].to_string(),
+                };
+                draw_loader_line(SPINNER[spin_idx], &phase, config.no_fun)?;
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                return Err("worker disconnected".into());
+            }
+        }
+    }
+}
+
+/// First turn of a session, served from the cache when possible and from a
+/// stale answer when the provider can't be reached.
+fn generate_first_turn(
+    agent: &ureq::Agent,
+    config: &AppConfig,
+    session: &Session,
+    fresh: bool,
+) -> Result<Generated> {
+    if !fresh {
+        if let Some((output, source)) = cache::lookup(config, &session.prompt)? {
+            println!("{}", paint(&source_note(source, false), Ansi::Dim));
+            return Ok(Generated { output, streamed: None });
+        }
+    }
+    match generate_with_loader(agent, config, session) {
+        Ok(generated) => {
+            cache::remember(config, &session.prompt, &generated.output);
+            Ok(generated)
+        }
+        Err(err) => {
+            let (output, source) = cache::fallback(config, &session.prompt, err)?;
+            println!("{}", paint(&source_note(source, true), Ansi::Yellow));
+            Ok(Generated { output, streamed: None })
+        }
+    }
+}
+
+fn source_note(source: Source, unreachable: bool) -> String {
+    let origin = match source {
+        Source::Cache => "a cached answer",
+        Source::History => "a past answer from history",
+        Source::Provider => "the provider",
+    };
+    if unreachable {
+        format!("Provider unreachable; showing {origin}.")
+    } else {
+        format!("Showing {origin} (press r to ask the provider).")
+    }
+}
+
+/// Fetches `config.candidates` alternatives and lets the user pick one.
+/// Returns `None` if the picker is cancelled.
+fn pick_candidate(
+    agent: &ureq::Agent,
+    config: &AppConfig,
+    session: &Session,
+) -> Result<Option<Generated>> {
+    let (agent, cfg, messages) = (agent.clone(), config.clone(), session.messages.clone());
+    let n = config.candidates;
+    let mut candidates = run_with_spinner(config.no_fun, move || {
+        generate_candidates(&agent, &cfg, &messages, n)
+    })?;
+    if candidates.len() == 1 {
+        println!("{}", paint("All candidates were the same command.", Ansi::Dim));
+        return Ok(candidates.pop().map(|output| Generated { output, streamed: None }));
+    }
+
+    println!();
+    println!("{}", paint("Candidates", Ansi::Cyan));
+    for (idx, candidate) in candidates.iter().enumerate() {
+        println!(
+            "{} {}  {}",
+            paint(&format!("{}.", idx + 1), Ansi::Bold),
+            candidate.command.trim(),
+            paint(&candidate.safety, safety_color(&candidate.safety)),
+        );
+        println!("   {}", paint(candidate.explanation.trim(), Ansi::Dim));
+    }
+    println!();
+
+    let items: Vec<String> = candidates
+        .iter()
+        .map(|c| format!("[{}] {}", c.safety, c.command.trim()))
+        .collect();
+    let picked = Select::with_theme(&ColorfulTheme::default())
+        .with_prompt("Pick a command")
+        .items(&items)
+        .default(0)
+        .interact_opt()?;
+    Ok(picked.map(|idx| Generated {
+        output: candidates.swap_remove(idx),
+        streamed: None,
+    }))
+}
+
+/// Runs `job` on a worker thread while drawing the loader line.
+fn run_with_spinner<T: Send + 'static>(
+    no_fun: bool,
+    job: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    let (tx, rx) = mpsc::channel::<Result<T>>();
+    thread::spawn(move || {
+        let _ = tx.send(job());
+    });
+
+    let mut phase_idx = 0usize;
+    let mut spin_idx = 0usize;
+    let mut last_phase_tick = Instant::now();
+    draw_loader_line(SPINNER[spin_idx], LOADER_PHASES[phase_idx], no_fun)?;
 
     loop {
         match rx.recv_timeout(Duration::from_millis(90)) {
@@ -141,12 +914,12 @@ fn generate_with_loader(
                 return result;
             }
             Err(mpsc::RecvTimeoutError::Timeout) => {
-                spin_idx = (spin_idx + 1) % spinner.len();
+                spin_idx = (spin_idx + 1) % SPINNER.len();
                 if last_phase_tick.elapsed() >= Duration::from_millis(850) {
-                    phase_idx = (phase_idx + 1) % phases.len();
+                    phase_idx = (phase_idx + 1) % LOADER_PHASES.len();
                     last_phase_tick = Instant::now();
                 }
-                draw_loader_line(spinner[spin_idx], phases[phase_idx], config.no_fun)?;
+                draw_loader_line(SPINNER[spin_idx], LOADER_PHASES[phase_idx], no_fun)?;
             }
             Err(mpsc::RecvTimeoutError::Disconnected) => {
                 return Err("worker disconnected".into());
@@ -207,22 +980,31 @@ fn copy_to_clipboard(text: &str) -> bool {
     }
 }
 
-fn run_command(command: &str) -> Result<()> {
-    let shell = std::env::var("SHELL").unwrap_or_else(|_| "/bin/bash".to_string());
-    let _status = Command::new(shell)
-        .arg("-lc")
+/// Runs `command` with the shell whose syntax it is written in and returns
+/// its exit code (`None` if it was killed by a signal). Bash-syntax commands
+/// run under bash whatever `$SHELL` is; pwsh doesn't take combined switches.
+fn run_command(command: &str, shell: ShellKind) -> Result<Option<i32>> {
+    let (program, flags): (&str, &[&str]) = match shell {
+        ShellKind::Fish => ("fish", &["-l", "-c"]),
+        ShellKind::Nu => ("nu", &["-l", "-c"]),
+        ShellKind::Pwsh => ("pwsh", &["-Login", "-Command"]),
+        ShellKind::Bash | ShellKind::Zsh => ("bash", &["-lc"]),
+    };
+    let status = Command::new(program)
+        .args(flags)
         .arg(command)
         .stdin(std::process::Stdio::inherit())
         .stdout(std::process::Stdio::inherit())
         .stderr(std::process::Stdio::inherit())
         .status()?;
-    Ok(())
+    Ok(status.code())
 }
 
 #[derive(Clone, Copy)]
 enum Ansi {
     Bold,
     Dim,
+    Red,
     Green,
     Yellow,
     Cyan,
@@ -235,6 +1017,7 @@ fn paint(text: &str, color: Ansi) -> String {
     let code = match color {
         Ansi::Bold => "1",
         Ansi::Dim => "2",
+        Ansi::Red => "31",
         Ansi::Green => "32",
         Ansi::Yellow => "33",
         Ansi::Cyan => "36",
@@ -0,0 +1,311 @@
+use crate::error::Result;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::ops::AddAssign;
+use std::path::{Path, PathBuf};
+
+/// Oldest records are dropped once the file grows past this.
+const MAX_RECORDS: usize = 20_000;
+
+/// Built-in USD prices per million tokens, matched by model-name prefix.
+/// `[prices]` in `config.toml` adds to and overrides these.
+const DEFAULT_PRICES: &[(&str, f64, f64)] = &[
+    ("gpt-5", 1.25, 10.0),
+    ("gpt-5-mini", 0.25, 2.0),
+    ("gpt-5-nano", 0.05, 0.4),
+    ("gpt-4o", 2.5, 10.0),
+    ("gpt-4o-mini", 0.15, 0.6),
+    ("claude-3-7-sonnet", 3.0, 15.0),
+    ("claude-3-5-sonnet", 3.0, 15.0),
+    ("claude-3.5-sonnet", 3.0, 15.0),
+    ("claude-3-5-haiku", 0.8, 4.0),
+    ("gemini-2.5-flash", 0.3, 2.5),
+];
+
+/// Tokens a provider reported for one or more calls.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct TokenUsage {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+}
+
+impl AddAssign for TokenUsage {
+    fn add_assign(&mut self, other: Self) {
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+    }
+}
+
+/// USD per million tokens.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+pub struct Price {
+    pub input: f64,
+    pub output: f64,
+}
+
+/// How a single provider call ended.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CallOutcome {
+    Answered,
+    Failed,
+    /// Lost a race and was still running when the winner arrived; the
+    /// latency is a lower bound and its tokens are never seen.
+    Abandoned,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct CallRecord {
+    /// Unix seconds.
+    pub timestamp: u64,
+    pub provider: String,
+    pub model: String,
+    pub latency_ms: u64,
+    pub outcome: CallOutcome,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub raced: bool,
+    /// `None` when the provider didn't report usage.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokens: Option<TokenUsage>,
+}
+
+/// One line of `b usage`: a provider and model on one (UTC) day.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsageRow {
+    pub day: String,
+    pub provider: String,
+    pub model: String,
+    pub calls: u64,
+    pub failed: u64,
+    pub tokens: TokenUsage,
+    pub avg_latency_ms: u64,
+    /// Calls that may have been billed but reported no tokens, e.g. race
+    /// entrants abandoned mid-answer; `cost` leaves them out.
+    pub unmetered: u64,
+    /// `None` when the model has no price.
+    pub cost: Option<f64>,
+}
+
+thread_local! {
+    static METER: Cell<Option<TokenUsage>> = const { Cell::new(None) };
+}
+
+/// Runs `job` and returns the tokens reported on this thread while it ran,
+/// so provider clients can report usage without threading it through every
+/// return value. Nested meters each see the inner tokens.
+pub fn metered<T>(job: impl FnOnce() -> T) -> (T, Option<TokenUsage>) {
+    let outer = METER.with(|meter| meter.replace(None));
+    let result = job();
+    let inner = METER.with(|meter| meter.replace(outer));
+    if let Some(tokens) = inner {
+        report(tokens);
+    }
+    (result, inner)
+}
+
+/// Adds tokens from a provider response to the current meter.
+pub fn report(tokens: TokenUsage) {
+    METER.with(|meter| {
+        let mut total = meter.get().unwrap_or_default();
+        total += tokens;
+        meter.set(Some(total));
+    });
+}
+
+/// The built-in prices with `overrides` from `config.toml` on top.
+pub fn price_table(overrides: &BTreeMap<String, Price>) -> BTreeMap<String, Price> {
+    let mut table: BTreeMap<String, Price> = DEFAULT_PRICES
+        .iter()
+        .map(|&(model, input, output)| (model.to_string(), Price { input, output }))
+        .collect();
+    table.extend(overrides.iter().map(|(k, v)| (k.clone(), *v)));
+    table
+}
+
+/// An exact `provider/model` or `model` key wins; otherwise the longest key
+/// the model name starts with, after any `vendor/` prefix, so dated
+/// snapshots like `gpt-5-mini-2025-08-07` find their family's price.
+pub fn price_for(table: &BTreeMap<String, Price>, provider: &str, model: &str) -> Option<Price> {
+    if matches!(provider, "ollama" | "llamacpp") {
+        return Some(Price { input: 0.0, output: 0.0 });
+    }
+    if let Some(price) = table
+        .get(&format!("{provider}/{model}"))
+        .or_else(|| table.get(model))
+    {
+        return Some(*price);
+    }
+    let name = model.rsplit('/').next().unwrap_or(model);
+    table
+        .iter()
+        .filter(|(key, _)| name.starts_with(key.as_str()))
+        .max_by_key(|(key, _)| key.len())
+        .map(|(_, price)| *price)
+}
+
+pub fn cost(price: Price, tokens: TokenUsage) -> f64 {
+    (tokens.input_tokens as f64 * price.input + tokens.output_tokens as f64 * price.output)
+        / 1_000_000.0
+}
+
+/// `usage.jsonl` lives next to `config.toml`, one provider call per line.
+pub fn usage_file_path(config_path: &Path) -> PathBuf {
+    config_path.with_file_name("usage.jsonl")
+}
+
+/// Records that fail to parse are skipped, as in `history::load`.
+pub fn load(path: &Path) -> Result<Vec<CallRecord>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let content = fs::read_to_string(path)?;
+    Ok(content
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+pub fn append(path: &Path, records: &[CallRecord]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    for record in records {
+        writeln!(file, "{}", serde_json::to_string(record)?)?;
+    }
+    drop(file);
+
+    let all = load(path)?;
+    if all.len() > MAX_RECORDS {
+        let mut out = String::new();
+        for record in &all[all.len() - MAX_RECORDS..] {
+            out.push_str(&serde_json::to_string(record)?);
+            out.push('\n');
+        }
+        fs::write(path, out)?;
+    }
+    Ok(())
+}
+
+/// `append`, but a failed write only warns; stats are never worth an error.
+pub fn remember(path: Option<&Path>, records: &[CallRecord]) {
+    let Some(path) = path else {
+        return;
+    };
+    if let Err(err) = append(path, records) {
+        eprintln!("warning: could not record provider usage: {err}");
+    }
+}
+
+/// Totals per day, provider and model for records at or after `since`,
+/// newest day first.
+pub fn summarize(
+    records: &[CallRecord],
+    since: u64,
+    prices: &BTreeMap<String, Price>,
+) -> Vec<UsageRow> {
+    let mut groups: BTreeMap<(u64, &str, &str), Vec<&CallRecord>> = BTreeMap::new();
+    for record in records.iter().filter(|r| r.timestamp >= since) {
+        let key = (record.timestamp / 86_400, record.provider.as_str(), record.model.as_str());
+        groups.entry(key).or_default().push(record);
+    }
+    let mut rows: Vec<UsageRow> = groups
+        .into_iter()
+        .map(|((day, provider, model), records)| {
+            let mut tokens = TokenUsage::default();
+            for used in records.iter().filter_map(|r| r.tokens) {
+                tokens += used;
+            }
+            let latency: u64 = records.iter().map(|r| r.latency_ms).sum();
+            UsageRow {
+                day: civil_date(day),
+                provider: provider.to_string(),
+                model: model.to_string(),
+                calls: records.len() as u64,
+                failed: records
+                    .iter()
+                    .filter(|r| r.outcome == CallOutcome::Failed)
+                    .count() as u64,
+                tokens,
+                avg_latency_ms: latency / records.len() as u64,
+                unmetered: records
+                    .iter()
+                    .filter(|r| r.tokens.is_none() && r.outcome != CallOutcome::Failed)
+                    .count() as u64,
+                cost: price_for(prices, provider, model).map(|price| cost(price, tokens)),
+            }
+        })
+        .collect();
+    rows.sort_by(|a, b| b.day.cmp(&a.day));
+    rows
+}
+
+/// `YYYY-MM-DD` for days since the Unix epoch (Howard Hinnant's algorithm).
+fn civil_date(days: u64) -> String {
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarize_groups_by_day_and_prices_tokens() {
+        let record = |timestamp, model: &str, tokens| CallRecord {
+            timestamp,
+            provider: "openai".to_string(),
+            model: model.to_string(),
+            latency_ms: 100,
+            outcome: CallOutcome::Answered,
+            raced: false,
+            tokens,
+        };
+        let used = |input_tokens, output_tokens| {
+            Some(TokenUsage {
+                input_tokens,
+                output_tokens,
+            })
+        };
+        let day = 1_700_000_000;
+        let records = vec![
+            record(day, "gpt-4o-mini", used(1_000_000, 0)),
+            record(day + 60, "gpt-4o-mini", used(0, 500_000)),
+            record(day, "mystery-1", used(10, 10)),
+            record(day - 86_400, "gpt-5-mini-2025-08-07", None),
+        ];
+        let rows = summarize(&records, 0, &price_table(&BTreeMap::new()));
+
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows.iter().map(|r| r.unmetered).collect::<Vec<_>>(), vec![0, 0, 1]);
+        let mini = rows.iter().find(|r| r.model == "gpt-4o-mini").expect("row missing");
+        assert_eq!((mini.day.as_str(), mini.calls), ("2023-11-14", 2));
+        assert_eq!(mini.tokens, TokenUsage { input_tokens: 1_000_000, output_tokens: 500_000 });
+        assert!((mini.cost.expect("priced") - 0.45).abs() < 1e-9);
+        assert_eq!(rows.iter().find(|r| r.model == "mystery-1").map(|r| r.cost), Some(None));
+        assert_eq!(rows.last().map(|r| r.day.as_str()), Some("2023-11-13"));
+        assert_eq!(
+            price_for(&price_table(&BTreeMap::new()), "openrouter", "openai/gpt-5-mini-2025-08-07"),
+            Some(Price { input: 0.25, output: 2.0 })
+        );
+    }
+
+    #[test]
+    fn metered_sees_nested_reports() {
+        let tokens = TokenUsage { input_tokens: 3, output_tokens: 4 };
+        let ((_, inner), outer) = metered(|| metered(|| report(tokens)));
+        assert_eq!((inner, outer), (Some(tokens), Some(tokens)));
+        assert_eq!(metered(|| ()).1, None);
+    }
+}